@@ -0,0 +1,492 @@
+// Wallet backups.
+//
+// A backup is a single JSON document holding everything needed to bring a
+// wallet back on another machine: its descriptors with checksums (private
+// ones plus the words, unless only the public side was exported), network,
+// birthday, labels and the app settings. It can be encrypted under a
+// password of its own, with the same scheme as the keystore. Nothing in a
+// backup is trusted until `Backup::open` has checked it.
+use bdk_wallet::{
+    bitcoin::{bip32::Xpriv, key::Secp256k1, Network},
+    chain::ChainPosition,
+    descriptor::IntoWalletDescriptor,
+    KeychainKind, Wallet,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::crypto::Sealed;
+use crate::descriptors::{self, WatchOnly};
+use crate::error::WalletError;
+use crate::keystore::{self, Keystore, KeystoreFile, ScriptType};
+use crate::labels::Label;
+use crate::registry;
+
+// Tells backups apart from other JSON files
+const BACKUP_FORMAT: &str = "slaps-wallet-backup";
+// Bumped whenever the document layout changes
+const BACKUP_VERSION: u32 = 1;
+// Authenticated along with an encrypted backup
+const ASSOCIATED_DATA: &[u8] = b"slaps-backup";
+
+/// A backup document as written out.
+#[derive(Serialize, Deserialize)]
+struct BackupFile {
+    format: String,
+    version: u32,
+    /// Unix time in seconds
+    created_at: u64,
+    #[serde(flatten)]
+    contents: Contents,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Contents {
+    Encrypted { sealed: Sealed },
+    Plain { backup: Box<Backup> },
+}
+
+/// What a backup holds. Deliberately not `Debug`, it can contain keys.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Backup {
+    pub wallet: WalletBackup,
+    pub settings: BackupSettings,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WalletBackup {
+    pub name: String,
+    pub network: Network,
+    pub script_type: ScriptType,
+    pub fingerprint: String,
+    /// BIP44 account of the seed, for wallets added as further accounts
+    #[serde(default)]
+    pub account: u32,
+    /// Only the public descriptors were exported; restores as a watch-only
+    /// wallet
+    pub watch_only: bool,
+    /// Descriptors with checksums, private unless `watch_only`
+    pub external: String,
+    /// Missing for single descriptor wallets
+    pub internal: Option<String>,
+    pub mnemonic: Option<String>,
+    pub has_passphrase: bool,
+    pub birthday: Birthday,
+    #[serde(default)]
+    pub labels: Vec<Label>,
+}
+
+/// App settings as a backup carries them, without the machine specific
+/// `data_dir`. Kept apart from `Config` so documents still open after the
+/// config changes shape: unknown fields are ignored, missing ones keep the
+/// value of the machine restoring, and nothing is checked until then.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupSettings {
+    pub network: Option<Network>,
+    pub esplora_url: Option<String>,
+    pub stop_gap: Option<usize>,
+    pub recovery_stop_gap: Option<usize>,
+    pub parallel_requests: Option<usize>,
+    pub request_timeout_secs: Option<u64>,
+    pub sync_timeout_secs: Option<u64>,
+    pub sync_retries: Option<u32>,
+    pub retry_delay_ms: Option<u64>,
+    pub auto_lock_secs: Option<u64>,
+}
+
+impl From<&Config> for BackupSettings {
+    fn from(config: &Config) -> Self {
+        Self {
+            network: Some(config.network),
+            esplora_url: Some(config.esplora_url.clone()),
+            stop_gap: Some(config.stop_gap),
+            recovery_stop_gap: Some(config.recovery_stop_gap),
+            parallel_requests: Some(config.parallel_requests),
+            request_timeout_secs: Some(config.request_timeout_secs),
+            sync_timeout_secs: Some(config.sync_timeout_secs),
+            sync_retries: Some(config.sync_retries),
+            retry_delay_ms: Some(config.retry_delay_ms),
+            auto_lock_secs: Some(config.auto_lock_secs),
+        }
+    }
+}
+
+impl BackupSettings {
+    /// `current` with the backed up settings in place of its own, checked
+    /// as a whole.
+    pub fn restore(&self, current: &Config) -> Result<Config, WalletError> {
+        let config = Config {
+            network: self.network.unwrap_or(current.network),
+            esplora_url: self
+                .esplora_url
+                .clone()
+                .unwrap_or_else(|| current.esplora_url.clone()),
+            stop_gap: self.stop_gap.unwrap_or(current.stop_gap),
+            recovery_stop_gap: self.recovery_stop_gap.unwrap_or(current.recovery_stop_gap),
+            parallel_requests: self.parallel_requests.unwrap_or(current.parallel_requests),
+            request_timeout_secs: self
+                .request_timeout_secs
+                .unwrap_or(current.request_timeout_secs),
+            sync_timeout_secs: self.sync_timeout_secs.unwrap_or(current.sync_timeout_secs),
+            sync_retries: self.sync_retries.unwrap_or(current.sync_retries),
+            retry_delay_ms: self.retry_delay_ms.unwrap_or(current.retry_delay_ms),
+            auto_lock_secs: self.auto_lock_secs.unwrap_or(current.auto_lock_secs),
+            // Where the data lives is up to this machine
+            data_dir: current.data_dir.clone(),
+            db_path: current.db_path.clone(),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// When the wallet came into use, so a restore knows how far back to look.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Birthday {
+    /// Unix time in seconds
+    pub time: u64,
+    /// Block of the first confirmed wallet transaction, if there is one
+    pub height: Option<u32>,
+}
+
+impl Birthday {
+    /// The earlier of the wallet's first confirmed transaction and
+    /// `created_at`, the time it was added to this app.
+    pub fn of(wallet: &Wallet, created_at: u64) -> Self {
+        let first = wallet
+            .transactions()
+            .filter_map(|tx| match tx.chain_position {
+                ChainPosition::Confirmed { anchor, .. } => Some(anchor),
+                ChainPosition::Unconfirmed { .. } => None,
+            })
+            .min_by_key(|anchor| anchor.block_id.height);
+        Self {
+            time: first.map_or(created_at, |anchor| {
+                anchor.confirmation_time.min(created_at)
+            }),
+            height: first.map(|anchor| anchor.block_id.height),
+        }
+    }
+}
+
+impl Backup {
+    /// The backup document, encrypted under `password` if one is given.
+    pub fn export(&self, password: Option<&str>) -> Result<String, WalletError> {
+        let contents = match password {
+            Some(password) => {
+                let plaintext = serde_json::to_vec(self).map_err(backup_error)?;
+                Contents::Encrypted {
+                    sealed: Sealed::seal(password, &plaintext, ASSOCIATED_DATA)?,
+                }
+            }
+            None => Contents::Plain {
+                backup: Box::new(self.clone()),
+            },
+        };
+        let file = BackupFile {
+            format: BACKUP_FORMAT.to_string(),
+            version: BACKUP_VERSION,
+            created_at: registry::unix_time(),
+            contents,
+        };
+        serde_json::to_string_pretty(&file).map_err(backup_error)
+    }
+
+    /// Read a backup document, decrypting it with `password` if it is
+    /// encrypted, and check that every descriptor carries a valid checksum
+    /// and matches the network, script type and fingerprint stated with it.
+    /// The settings are only checked by `BackupSettings::restore`.
+    pub fn open(document: &str, password: Option<&str>) -> Result<Self, WalletError> {
+        let file: BackupFile = serde_json::from_str(document.trim())
+            .map_err(|e| invalid("backup", format!("not a wallet backup: {}", e)))?;
+        if file.format != BACKUP_FORMAT {
+            return Err(invalid("backup", "not a wallet backup"));
+        }
+        if file.version > BACKUP_VERSION {
+            return Err(invalid(
+                "backup",
+                format!(
+                    "version {} was written by a newer version of the app",
+                    file.version
+                ),
+            ));
+        }
+
+        let backup = match file.contents {
+            Contents::Plain { backup } => *backup,
+            Contents::Encrypted { sealed } => {
+                let password = password
+                    .filter(|p| !p.is_empty())
+                    .ok_or_else(|| invalid("backup_password", "the backup is encrypted"))?;
+                let plaintext = sealed.open(password, ASSOCIATED_DATA)?;
+                serde_json::from_slice(&plaintext).map_err(|e| invalid("backup", e.to_string()))?
+            }
+        };
+        backup.wallet.verify()?;
+        Ok(backup)
+    }
+}
+
+impl WalletBackup {
+    /// The keystore file to restore the wallet with, its secrets encrypted
+    /// under `password`. Watch-only backups need none.
+    pub fn keystore_file(&self, password: Option<&str>) -> Result<KeystoreFile, WalletError> {
+        if self.watch_only {
+            let imported =
+                WatchOnly::parse(&self.external, self.internal.as_deref(), self.network)?;
+            return Ok(KeystoreFile::watch_only(&imported));
+        }
+        let password =
+            password.ok_or_else(|| invalid("password", "is needed to restore private keys"))?;
+        let keystore = Keystore {
+            network: self.network,
+            script_type: self.script_type,
+            mnemonic: self.mnemonic.clone(),
+            has_passphrase: self.has_passphrase,
+            fingerprint: self.fingerprint.clone(),
+            account: self.account,
+            external: self.external.clone(),
+            internal: self.internal.clone().unwrap_or_default(),
+        };
+        keystore.seal(password)
+    }
+
+    fn verify(&self) -> Result<(), WalletError> {
+        let descriptors = std::iter::once(&self.external).chain(&self.internal);
+        for descriptor in descriptors.clone() {
+            if !descriptor.contains('#') {
+                return Err(invalid("backup", "descriptor is missing its checksum"));
+            }
+        }
+
+        if self.watch_only {
+            if self.mnemonic.is_some() {
+                return Err(invalid("backup", "watch-only wallet with recovery words"));
+            }
+            let imported =
+                WatchOnly::parse(&self.external, self.internal.as_deref(), self.network)?;
+            if imported.script_type != self.script_type
+                || imported.fingerprint != self.fingerprint
+                || imported.account != self.account
+            {
+                return Err(invalid(
+                    "backup",
+                    "descriptors don't match the wallet they are listed for",
+                ));
+            }
+            return Ok(());
+        }
+
+        if self.internal.is_none() {
+            return Err(invalid("backup", "change descriptor is missing"));
+        }
+        let secp = Secp256k1::new();
+        for descriptor in descriptors {
+            let (descriptor, keymap) = descriptor
+                .as_str()
+                .into_wallet_descriptor(&secp, self.network)
+                .map_err(|e| invalid("backup", e.to_string()))?;
+            let ours = keymap
+                .keys()
+                .any(|key| key.master_fingerprint().to_string() == self.fingerprint);
+            let script_type = descriptors::script_type(&descriptor);
+            if keymap.is_empty() || !ours || script_type != Some(self.script_type) {
+                return Err(invalid(
+                    "backup",
+                    "descriptors don't match the wallet they are listed for",
+                ));
+            }
+        }
+
+        // Without a passphrase the words must lead to the same master key
+        if let Some(words) = &self.mnemonic {
+            let mnemonic = keystore::parse_mnemonic(words)?;
+            if !self.has_passphrase {
+                let master = Xpriv::new_master(self.network, &mnemonic.to_seed(""))
+                    .map_err(|e| invalid("backup", e.to_string()))?;
+                if master.fingerprint(&secp).to_string() != self.fingerprint {
+                    return Err(invalid(
+                        "backup",
+                        "recovery words don't match the descriptors",
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The public descriptors of a loaded wallet, external first.
+pub fn public_descriptors(wallet: &Wallet) -> (String, Option<String>) {
+    let internal = wallet
+        .keychains()
+        .find(|(keychain, _)| *keychain == KeychainKind::Internal)
+        .map(|(_, descriptor)| descriptor.to_string());
+    (
+        wallet.public_descriptor(KeychainKind::External).to_string(),
+        internal,
+    )
+}
+
+fn invalid(field: &str, message: impl Into<String>) -> WalletError {
+    WalletError::InvalidArgument {
+        field: field.to_string(),
+        message: message.into(),
+    }
+}
+
+fn backup_error(error: serde_json::Error) -> WalletError {
+    WalletError::Keystore {
+        message: format!("Failed to serialize backup: {}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORDS: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    fn backup() -> Backup {
+        let mnemonic = keystore::parse_mnemonic(WORDS).unwrap();
+        let keystore =
+            Keystore::from_mnemonic(&mnemonic, None, Network::Signet, ScriptType::Bip84, 0)
+                .unwrap();
+        Backup {
+            wallet: WalletBackup {
+                name: "Signet wallet".to_string(),
+                network: keystore.network,
+                script_type: keystore.script_type,
+                fingerprint: keystore.fingerprint,
+                account: keystore.account,
+                watch_only: false,
+                external: keystore.external,
+                internal: Some(keystore.internal),
+                mnemonic: keystore.mnemonic,
+                has_passphrase: false,
+                birthday: Birthday {
+                    time: 1_700_000_000,
+                    height: None,
+                },
+                labels: Vec::new(),
+            },
+            settings: BackupSettings::from(&Config::default()),
+        }
+    }
+
+    // The document with `edit` applied to its JSON
+    fn edited(document: &str, edit: impl FnOnce(&mut serde_json::Value)) -> String {
+        let mut value: serde_json::Value = serde_json::from_str(document).unwrap();
+        edit(&mut value);
+        value.to_string()
+    }
+
+    #[test]
+    fn export_and_open() {
+        let plain = backup().export(None).unwrap();
+        let opened = Backup::open(&plain, None).unwrap();
+        assert_eq!(opened.wallet.fingerprint, "73c5da0a");
+        assert_eq!(opened.wallet.external, backup().wallet.external);
+
+        let encrypted = backup().export(Some("backup password")).unwrap();
+        assert!(!encrypted.contains(WORDS));
+        let opened = Backup::open(&encrypted, Some("backup password")).unwrap();
+        assert_eq!(opened.wallet.mnemonic.as_deref(), Some(WORDS));
+        assert!(matches!(
+            Backup::open(&encrypted, None),
+            Err(WalletError::InvalidArgument { field, .. }) if field == "backup_password"
+        ));
+        assert!(matches!(
+            Backup::open(&encrypted, Some("wrong")),
+            Err(WalletError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn tampered_backups_are_rejected() {
+        let plain = backup().export(None).unwrap();
+        let tampered = [
+            edited(&plain, |v| v["format"] = "something-else".into()),
+            edited(&plain, |v| v["version"] = (BACKUP_VERSION + 1).into()),
+            edited(&plain, |v| {
+                v["backup"]["wallet"]["fingerprint"] = "deadbeef".into()
+            }),
+            edited(&plain, |v| {
+                v["backup"]["wallet"]["script_type"] = "bip86".into()
+            }),
+            edited(&plain, |v| {
+                v["backup"]["wallet"]["internal"] = serde_json::Value::Null
+            }),
+            edited(&plain, |v| {
+                let external = v["backup"]["wallet"]["external"].as_str().unwrap();
+                let (descriptor, _) = external.split_once('#').unwrap();
+                v["backup"]["wallet"]["external"] = descriptor.into();
+            }),
+            edited(&plain, |v| {
+                let external = v["backup"]["wallet"]["external"].as_str().unwrap();
+                let (descriptor, checksum) = external.split_once('#').unwrap();
+                let descriptor = descriptor.replacen("/0/*", "/2/*", 1);
+                v["backup"]["wallet"]["external"] = format!("{}#{}", descriptor, checksum).into();
+            }),
+            // Valid words, but of another seed
+            edited(&plain, |v| {
+                v["backup"]["wallet"]["mnemonic"] =
+                    "legal winner thank year wave sausage worth useful legal winner thank yellow"
+                        .into();
+            }),
+        ];
+        for document in &tampered {
+            assert!(
+                matches!(
+                    Backup::open(document, None),
+                    Err(WalletError::InvalidArgument { .. })
+                ),
+                "accepted {}",
+                document
+            );
+        }
+
+        let encrypted = backup().export(Some("backup password")).unwrap();
+        let tampered = edited(&encrypted, |v| {
+            let ciphertext = v["sealed"]["ciphertext"].as_str().unwrap();
+            let flipped = if ciphertext.ends_with('0') { "1" } else { "0" };
+            let ciphertext = format!("{}{}", &ciphertext[..ciphertext.len() - 1], flipped);
+            v["sealed"]["ciphertext"] = ciphertext.into();
+        });
+        assert!(matches!(
+            Backup::open(&tampered, Some("backup password")),
+            Err(WalletError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn settings_are_only_checked_when_restored() {
+        let plain = backup().export(None).unwrap();
+        // From a build whose config had other fields, and a bad value
+        let document = edited(&plain, |v| {
+            let settings = &mut v["backup"]["settings"];
+            settings.as_object_mut().unwrap().remove("auto_lock_secs");
+            settings["theme"] = "dark".into();
+            settings["stop_gap"] = 0.into();
+        });
+        let opened = Backup::open(&document, None).unwrap();
+        assert!(matches!(
+            opened.settings.restore(&Config::default()),
+            Err(WalletError::InvalidConfig { field, .. }) if field == "stop_gap"
+        ));
+
+        let mut settings = opened.settings;
+        settings.stop_gap = Some(50);
+        let current = Config {
+            auto_lock_secs: 60,
+            data_dir: Some("/elsewhere".into()),
+            ..Config::default()
+        };
+        let restored = settings.restore(&current).unwrap();
+        assert_eq!(restored.stop_gap, 50);
+        assert_eq!(restored.auto_lock_secs, 60);
+        assert_eq!(restored.data_dir, current.data_dir);
+    }
+}
@@ -0,0 +1,601 @@
+// Runtime configuration for the wallet service.
+//
+// Loaded from a TOML file at startup, with `SLAPS_*` environment variables
+// taking precedence over the file. Missing fields fall back to the defaults
+// below, so an empty or absent file is a valid configuration.
+use std::path::{Path, PathBuf};
+
+use bdk_wallet::bitcoin::Network;
+use serde::{Deserialize, Serialize};
+
+use crate::error::WalletError;
+use crate::registry::WalletId;
+
+// File name of the wallet database inside a wallet's directory
+const WALLET_DB_FILE: &str = "wallet.sqlite";
+// File name of the keystore holding the wallet's private keys
+const KEYSTORE_FILE: &str = "keystore.json";
+// Present while a restored wallet still needs its recovery scan, in builds
+// before the registry kept track of it
+const RECOVERING_FILE: &str = "recovering";
+// File name of a wallet's BIP329 labels
+const LABELS_FILE: &str = "labels.jsonl";
+// File name of the list of wallets, in the data directory
+const REGISTRY_FILE: &str = "wallets.json";
+
+// Upper bound for parallel esplora requests, to stay polite to public servers
+const MAX_PARALLEL_REQUESTS: usize = 32;
+// Upper bound for the stop gap, beyond which a scan is practically unbounded
+const MAX_STOP_GAP: usize = 10_000;
+// Upper bound for sync retries, so a dead server is given up on eventually
+const MAX_SYNC_RETRIES: u32 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Network new wallets are created on, and the one `esplora_url` serves
+    pub network: Network,
+    pub esplora_url: String,
+    /// Number of consecutive unused scripts after which a full scan stops
+    pub stop_gap: usize,
+    /// Stop gap used when restoring a wallet, large enough to find funds on
+    /// seeds that other wallets handed out many addresses for
+    pub recovery_stop_gap: usize,
+    /// Number of esplora requests issued concurrently during a scan
+    pub parallel_requests: usize,
+    /// Timeout for a single esplora HTTP request, in seconds
+    pub request_timeout_secs: u64,
+    /// Timeout for a whole sync including retries, in seconds
+    pub sync_timeout_secs: u64,
+    /// How often a sync is retried after a transient esplora error
+    pub sync_retries: u32,
+    /// Delay before the first retry, doubled for every further attempt
+    pub retry_delay_ms: u64,
+    /// Lock wallets after this many seconds without being used (pings,
+    /// settings and the wallet list don't count); 0 keeps them unlocked
+    /// until `Lock` or a restart
+    pub auto_lock_secs: u64,
+    /// Where wallet data is stored; defaults to the platform app-data directory
+    pub data_dir: Option<PathBuf>,
+    /// Old single-file database location, only read to migrate it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub db_path: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            network: Network::Signet,
+            esplora_url: "http://signet.bitcoindevkit.net".to_string(),
+            stop_gap: 5,
+            recovery_stop_gap: 100,
+            parallel_requests: 5,
+            request_timeout_secs: 30,
+            sync_timeout_secs: 600,
+            sync_retries: 3,
+            retry_delay_ms: 1_000,
+            auto_lock_secs: 300,
+            data_dir: None,
+            db_path: None,
+        }
+    }
+}
+
+impl Config {
+    /// Check that the configuration is usable, naming the offending field.
+    pub fn validate(&self) -> Result<(), WalletError> {
+        let url = self.esplora_url.trim();
+        let host = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"));
+        match host {
+            Some(host) if !host.is_empty() && !host.starts_with('/') => {}
+            _ => {
+                return Err(invalid(
+                    "esplora_url",
+                    "must be an http:// or https:// URL with a host",
+                ))
+            }
+        }
+
+        if self.stop_gap == 0 || self.stop_gap > MAX_STOP_GAP {
+            return Err(invalid(
+                "stop_gap",
+                format!("must be between 1 and {}", MAX_STOP_GAP),
+            ));
+        }
+        if self.recovery_stop_gap < self.stop_gap || self.recovery_stop_gap > MAX_STOP_GAP {
+            return Err(invalid(
+                "recovery_stop_gap",
+                format!("must be between stop_gap and {}", MAX_STOP_GAP),
+            ));
+        }
+        if self.parallel_requests == 0 || self.parallel_requests > MAX_PARALLEL_REQUESTS {
+            return Err(invalid(
+                "parallel_requests",
+                format!("must be between 1 and {}", MAX_PARALLEL_REQUESTS),
+            ));
+        }
+        if self.request_timeout_secs == 0 {
+            return Err(invalid("request_timeout_secs", "must be at least 1"));
+        }
+        if self.sync_timeout_secs < self.request_timeout_secs {
+            return Err(invalid(
+                "sync_timeout_secs",
+                "must not be shorter than request_timeout_secs",
+            ));
+        }
+        if self.sync_retries > MAX_SYNC_RETRIES {
+            return Err(invalid(
+                "sync_retries",
+                format!("must be at most {}", MAX_SYNC_RETRIES),
+            ));
+        }
+        if self.retry_delay_ms == 0 {
+            return Err(invalid("retry_delay_ms", "must be at least 1"));
+        }
+        if matches!(&self.data_dir, Some(dir) if dir.as_os_str().is_empty()) {
+            return Err(invalid("data_dir", "must not be empty"));
+        }
+        Ok(())
+    }
+
+    // Override fields from `SLAPS_*` environment variables, returning the
+    // fields that were overridden
+    fn apply_env_overrides(&mut self) -> Result<Vec<Overridable>, WalletError> {
+        let mut overridden = Vec::new();
+        for field in Overridable::ALL {
+            if let Some(value) = env_var(field.env_var()) {
+                field.set(self, &value)?;
+                overridden.push(field);
+            }
+        }
+        Ok(overridden)
+    }
+}
+
+// A field that a `SLAPS_*` environment variable can override
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Overridable {
+    Network,
+    EsploraUrl,
+    StopGap,
+    RecoveryStopGap,
+    ParallelRequests,
+    RequestTimeoutSecs,
+    SyncTimeoutSecs,
+    SyncRetries,
+    RetryDelayMs,
+    AutoLockSecs,
+    DataDir,
+}
+
+impl Overridable {
+    const ALL: [Self; 11] = [
+        Self::Network,
+        Self::EsploraUrl,
+        Self::StopGap,
+        Self::RecoveryStopGap,
+        Self::ParallelRequests,
+        Self::RequestTimeoutSecs,
+        Self::SyncTimeoutSecs,
+        Self::SyncRetries,
+        Self::RetryDelayMs,
+        Self::AutoLockSecs,
+        Self::DataDir,
+    ];
+
+    // The field's name in the config file
+    fn name(self) -> &'static str {
+        match self {
+            Self::Network => "network",
+            Self::EsploraUrl => "esplora_url",
+            Self::StopGap => "stop_gap",
+            Self::RecoveryStopGap => "recovery_stop_gap",
+            Self::ParallelRequests => "parallel_requests",
+            Self::RequestTimeoutSecs => "request_timeout_secs",
+            Self::SyncTimeoutSecs => "sync_timeout_secs",
+            Self::SyncRetries => "sync_retries",
+            Self::RetryDelayMs => "retry_delay_ms",
+            Self::AutoLockSecs => "auto_lock_secs",
+            Self::DataDir => "data_dir",
+        }
+    }
+
+    fn env_var(self) -> &'static str {
+        match self {
+            Self::Network => "SLAPS_NETWORK",
+            Self::EsploraUrl => "SLAPS_ESPLORA_URL",
+            Self::StopGap => "SLAPS_STOP_GAP",
+            Self::RecoveryStopGap => "SLAPS_RECOVERY_STOP_GAP",
+            Self::ParallelRequests => "SLAPS_PARALLEL_REQUESTS",
+            Self::RequestTimeoutSecs => "SLAPS_REQUEST_TIMEOUT_SECS",
+            Self::SyncTimeoutSecs => "SLAPS_SYNC_TIMEOUT_SECS",
+            Self::SyncRetries => "SLAPS_SYNC_RETRIES",
+            Self::RetryDelayMs => "SLAPS_RETRY_DELAY_MS",
+            Self::AutoLockSecs => "SLAPS_AUTO_LOCK_SECS",
+            Self::DataDir => "SLAPS_DATA_DIR",
+        }
+    }
+
+    // Set the field of `config` from the environment variable's `value`
+    fn set(self, config: &mut Config, value: &str) -> Result<(), WalletError> {
+        let name = self.name();
+        match self {
+            Self::Network => {
+                config.network = value
+                    .parse()
+                    .map_err(|_| invalid(name, format!("unknown network '{}'", value)))?
+            }
+            Self::EsploraUrl => config.esplora_url = value.to_string(),
+            Self::StopGap => config.stop_gap = number(name, value)?,
+            Self::RecoveryStopGap => config.recovery_stop_gap = number(name, value)?,
+            Self::ParallelRequests => config.parallel_requests = number(name, value)?,
+            Self::RequestTimeoutSecs => config.request_timeout_secs = number(name, value)?,
+            Self::SyncTimeoutSecs => config.sync_timeout_secs = number(name, value)?,
+            Self::SyncRetries => config.sync_retries = number(name, value)?,
+            Self::RetryDelayMs => config.retry_delay_ms = number(name, value)?,
+            Self::AutoLockSecs => config.auto_lock_secs = number(name, value)?,
+            Self::DataDir => config.data_dir = Some(PathBuf::from(value)),
+        }
+        Ok(())
+    }
+
+    // Give `config` the field's value in `from`
+    fn copy(self, config: &mut Config, from: &Config) {
+        match self {
+            Self::Network => config.network = from.network,
+            Self::EsploraUrl => config.esplora_url = from.esplora_url.clone(),
+            Self::StopGap => config.stop_gap = from.stop_gap,
+            Self::RecoveryStopGap => config.recovery_stop_gap = from.recovery_stop_gap,
+            Self::ParallelRequests => config.parallel_requests = from.parallel_requests,
+            Self::RequestTimeoutSecs => config.request_timeout_secs = from.request_timeout_secs,
+            Self::SyncTimeoutSecs => config.sync_timeout_secs = from.sync_timeout_secs,
+            Self::SyncRetries => config.sync_retries = from.sync_retries,
+            Self::RetryDelayMs => config.retry_delay_ms = from.retry_delay_ms,
+            Self::AutoLockSecs => config.auto_lock_secs = from.auto_lock_secs,
+            Self::DataDir => config.data_dir = from.data_dir.clone(),
+        }
+    }
+
+    // Whether `a` and `b` have the same value for the field
+    fn same(self, a: &Config, b: &Config) -> bool {
+        let mut copy = a.clone();
+        self.copy(&mut copy, b);
+        copy == *a
+    }
+}
+
+/// Effective configuration after environment overrides, plus where to save
+/// it. Fields set by the environment keep their file value when `update`
+/// saves, so an override never leaks into the saved file even when the
+/// effective configuration is sent back unchanged.
+#[derive(Debug, Clone)]
+pub struct ConfigStore {
+    path: Option<PathBuf>,
+    default_data_dir: PathBuf,
+    // What the file says, before overrides
+    file: Config,
+    effective: Config,
+    overridden: Vec<Overridable>,
+}
+
+impl ConfigStore {
+    /// Load the config file at `path`, using defaults if it doesn't exist yet.
+    /// Wallet data goes to `default_data_dir` unless the config says otherwise.
+    pub fn load(
+        path: impl Into<PathBuf>,
+        default_data_dir: impl Into<PathBuf>,
+    ) -> Result<Self, WalletError> {
+        let path = path.into();
+        let file = match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents).map_err(|e| WalletError::Config {
+                message: format!("Failed to parse {}: {}", path.display(), e),
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(config_io(&path, e)),
+        };
+        Self::with_path(Some(path), default_data_dir.into(), file)
+    }
+
+    /// A store that is never written to disk, e.g. for tests or a CLI.
+    pub fn in_memory(
+        config: Config,
+        default_data_dir: impl Into<PathBuf>,
+    ) -> Result<Self, WalletError> {
+        Self::with_path(None, default_data_dir.into(), config)
+    }
+
+    fn with_path(
+        path: Option<PathBuf>,
+        default_data_dir: PathBuf,
+        file: Config,
+    ) -> Result<Self, WalletError> {
+        let mut effective = file.clone();
+        let overridden = effective.apply_env_overrides()?;
+        effective.validate()?;
+        Ok(Self {
+            path,
+            default_data_dir,
+            file,
+            effective,
+            overridden,
+        })
+    }
+
+    /// Read the config file again, e.g. when the service is restarted. An
+    /// in-memory store has nothing to reload and is returned unchanged.
+    pub fn reload(&self) -> Result<Self, WalletError> {
+        match &self.path {
+            Some(path) => Self::load(path, &self.default_data_dir),
+            None => Ok(self.clone()),
+        }
+    }
+
+    /// The configuration the service should use.
+    pub fn config(&self) -> &Config {
+        &self.effective
+    }
+
+    /// Root directory for wallet data.
+    pub fn data_dir(&self) -> &Path {
+        self.effective
+            .data_dir
+            .as_deref()
+            .unwrap_or(&self.default_data_dir)
+    }
+
+    /// The list of wallets and which one is active.
+    pub fn registry_path(&self) -> PathBuf {
+        self.data_dir().join(REGISTRY_FILE)
+    }
+
+    /// Directory holding one wallet's database and keystore.
+    pub fn wallet_dir(&self, id: WalletId) -> PathBuf {
+        self.data_dir().join("wallets").join(id.to_string())
+    }
+
+    pub fn db_path_for(&self, id: WalletId) -> PathBuf {
+        self.wallet_dir(id).join(WALLET_DB_FILE)
+    }
+
+    /// Keystore for a wallet, kept next to its database.
+    pub fn keystore_path_for(&self, id: WalletId) -> PathBuf {
+        self.wallet_dir(id).join(KEYSTORE_FILE)
+    }
+
+    /// Labels for a wallet's transactions and addresses, kept next to its
+    /// database.
+    pub fn labels_path_for(&self, id: WalletId) -> PathBuf {
+        self.wallet_dir(id).join(LABELS_FILE)
+    }
+
+    /// Where builds before the wallet registry kept the one wallet per
+    /// network, only read to migrate it.
+    pub fn legacy_network_dir(&self, network: Network) -> PathBuf {
+        self.data_dir().join(network.to_string())
+    }
+
+    pub fn legacy_db_path(&self, network: Network) -> PathBuf {
+        self.legacy_network_dir(network).join(WALLET_DB_FILE)
+    }
+
+    pub fn legacy_keystore_path(&self, network: Network) -> PathBuf {
+        self.legacy_network_dir(network).join(KEYSTORE_FILE)
+    }
+
+    pub fn legacy_recovering_path(&self, network: Network) -> PathBuf {
+        self.legacy_network_dir(network).join(RECOVERING_FILE)
+    }
+
+    /// Validate `config`, write it to the config file and make it current.
+    /// Fields the environment overrides must keep their effective value, and
+    /// are saved as the file had them.
+    pub fn update(&mut self, config: Config) -> Result<&Config, WalletError> {
+        let mut effective = config.clone();
+        let overridden = effective.apply_env_overrides()?;
+        if let Some(field) = overridden
+            .iter()
+            .find(|field| !field.same(&config, &effective))
+        {
+            return Err(invalid(
+                field.name(),
+                format!("is set by {} and can't be changed here", field.env_var()),
+            ));
+        }
+        effective.validate()?;
+
+        let mut file = config;
+        for field in &overridden {
+            field.copy(&mut file, &self.file);
+        }
+        if let Some(path) = &self.path {
+            save(path, &file)?;
+        }
+        self.file = file;
+        self.effective = effective;
+        self.overridden = overridden;
+        Ok(&self.effective)
+    }
+
+    /// `config` with the fields the environment overrides set back to their
+    /// effective values, checked as a whole. For settings from elsewhere,
+    /// e.g. a backup, that `update` would otherwise refuse.
+    pub fn keep_overrides(&self, mut config: Config) -> Result<Config, WalletError> {
+        for field in &self.overridden {
+            field.copy(&mut config, &self.effective);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn save(path: &Path, config: &Config) -> Result<(), WalletError> {
+    let contents = toml::to_string_pretty(config).map_err(|e| WalletError::Config {
+        message: format!("Failed to serialize config: {}", e),
+    })?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| config_io(parent, e))?;
+    }
+    std::fs::write(path, contents).map_err(|e| config_io(path, e))
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+fn number<T: std::str::FromStr>(field: &str, value: &str) -> Result<T, WalletError> {
+    value
+        .parse()
+        .map_err(|_| invalid(field, format!("'{}' is not a number", value)))
+}
+
+fn invalid(field: &str, message: impl Into<String>) -> WalletError {
+    WalletError::InvalidConfig {
+        field: field.to_string(),
+        message: message.into(),
+    }
+}
+
+fn config_io(path: &Path, error: std::io::Error) -> WalletError {
+    WalletError::Config {
+        message: format!("{}: {}", path.display(), error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid_field(config: Config) -> String {
+        match config.validate() {
+            Err(WalletError::InvalidConfig { field, .. }) => field,
+            other => panic!("expected InvalidConfig, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn validate() {
+        assert!(Config::default().validate().is_ok());
+        let invalid = [
+            (
+                Config {
+                    esplora_url: "signet.bitcoindevkit.net".to_string(),
+                    ..Config::default()
+                },
+                "esplora_url",
+            ),
+            (
+                Config {
+                    esplora_url: "https:///api".to_string(),
+                    ..Config::default()
+                },
+                "esplora_url",
+            ),
+            (
+                Config {
+                    stop_gap: 0,
+                    ..Config::default()
+                },
+                "stop_gap",
+            ),
+            (
+                Config {
+                    parallel_requests: MAX_PARALLEL_REQUESTS + 1,
+                    ..Config::default()
+                },
+                "parallel_requests",
+            ),
+            (
+                Config {
+                    data_dir: Some(PathBuf::new()),
+                    ..Config::default()
+                },
+                "data_dir",
+            ),
+        ];
+        for (config, field) in invalid {
+            assert_eq!(invalid_field(config), field);
+        }
+    }
+
+    #[test]
+    fn parse_file() {
+        let config: Config = toml::from_str("stop_gap = 20\nnetwork = \"testnet\"").unwrap();
+        assert_eq!(config.stop_gap, 20);
+        assert_eq!(config.network, Network::Testnet);
+        assert_eq!(
+            config.parallel_requests,
+            Config::default().parallel_requests
+        );
+        assert!(toml::from_str::<Config>("stop_gaps = 20").is_err());
+    }
+
+    // The only test touching `SLAPS_*`: the environment is shared between
+    // test threads, so it only sets values other tests can live with
+    #[test]
+    fn env_overrides() {
+        let dir = std::env::temp_dir().join(format!("slaps-config-{}", std::process::id()));
+        let path = dir.join("config.toml");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, "esplora_url = \"https://file.example\"\n").unwrap();
+
+        std::env::set_var("SLAPS_ESPLORA_URL", "https://env.example");
+        std::env::set_var("SLAPS_STOP_GAP", "20");
+        let mut store = ConfigStore::load(&path, &dir).unwrap();
+        assert_eq!(store.config().esplora_url, "https://env.example");
+        assert_eq!(store.config().stop_gap, 20);
+        assert_eq!(store.data_dir(), dir);
+        assert_eq!(
+            store.db_path_for(3),
+            dir.join("wallets").join("3").join(WALLET_DB_FILE)
+        );
+        assert_eq!(
+            store.legacy_db_path(Network::Testnet),
+            dir.join("testnet").join(WALLET_DB_FILE)
+        );
+
+        // Only what the environment doesn't set can be changed
+        let error = store
+            .update(Config {
+                esplora_url: "https://file.example".to_string(),
+                ..store.config().clone()
+            })
+            .unwrap_err();
+        assert_eq!(
+            error,
+            invalid(
+                "esplora_url",
+                "is set by SLAPS_ESPLORA_URL and can't be changed here"
+            )
+        );
+        let restored = store
+            .keep_overrides(Config {
+                esplora_url: "https://backup.example".to_string(),
+                stop_gap: 30,
+                parallel_requests: 8,
+                ..Config::default()
+            })
+            .unwrap();
+        assert_eq!(restored.esplora_url, "https://env.example");
+        assert_eq!(restored.stop_gap, 20);
+        assert_eq!(restored.parallel_requests, 8);
+
+        let current = store.update(restored).unwrap();
+        assert_eq!(current.esplora_url, "https://env.example");
+        assert_eq!(current.parallel_requests, 8);
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(saved.contains("https://file.example"), "{}", saved);
+        assert!(saved.contains("parallel_requests = 8"), "{}", saved);
+        assert!(!saved.contains("env.example"), "{}", saved);
+
+        std::env::remove_var("SLAPS_ESPLORA_URL");
+        std::env::remove_var("SLAPS_STOP_GAP");
+        let reloaded = ConfigStore::load(&path, &dir).unwrap();
+        assert_eq!(reloaded.config().esplora_url, "https://file.example");
+        assert_eq!(reloaded.config().stop_gap, Config::default().stop_gap);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+// Password-based encryption for secrets written to disk.
+//
+// A key is derived from the password with Argon2id, whose cost parameters
+// are stored next to the ciphertext so they can be raised later without
+// breaking existing files. The data is sealed with ChaCha20-Poly1305, which
+// also authenticates `aad`: public data stored in the clear that must not be
+// swapped out, such as the network a keystore belongs to.
+use argon2::{Algorithm, Argon2, Params, Version};
+use bdk_wallet::bitcoin::hex::{DisplayHex, FromHex};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+
+use crate::error::WalletError;
+
+// Argon2id cost for new files: 64 MiB of memory and three passes, a bit
+// under a second on a laptop
+const MEMORY_KIB: u32 = 64 * 1024;
+const ITERATIONS: u32 = 3;
+const PARALLELISM: u32 = 1;
+// Files come from disk or from a backup someone hands us, so their cost
+// parameters are capped before deriving: a few times the defaults, never
+// enough to exhaust memory
+const MAX_MEMORY_KIB: u32 = 4 * MEMORY_KIB;
+const MAX_ITERATIONS: u32 = 4 * ITERATIONS;
+const MAX_PARALLELISM: u32 = 4;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// How the encryption key was derived from the password.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum Kdf {
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+        /// Hex encoded
+        salt: String,
+    },
+}
+
+/// Ciphertext plus everything except the password needed to decrypt it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sealed {
+    pub kdf: Kdf,
+    /// Always `chacha20poly1305` for now
+    pub cipher: String,
+    /// Hex encoded
+    pub nonce: String,
+    /// Hex encoded, including the authentication tag
+    pub ciphertext: String,
+}
+
+impl Sealed {
+    /// Encrypt `plaintext` under `password`, binding it to `aad`.
+    pub fn seal(password: &str, plaintext: &[u8], aad: &[u8]) -> Result<Self, WalletError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let kdf = Kdf::Argon2id {
+            memory_kib: MEMORY_KIB,
+            iterations: ITERATIONS,
+            parallelism: PARALLELISM,
+            salt: salt.to_lower_hex_string(),
+        };
+
+        let cipher = ChaCha20Poly1305::new(&derive_key(password, &kdf)?);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| crypto_error("encryption failed"))?;
+
+        Ok(Self {
+            kdf,
+            cipher: "chacha20poly1305".to_string(),
+            nonce: nonce.to_lower_hex_string(),
+            ciphertext: ciphertext.to_lower_hex_string(),
+        })
+    }
+
+    /// Decrypt with `password`. A wrong password and tampered data look the
+    /// same and both fail with `WrongPassword`.
+    pub fn open(&self, password: &str, aad: &[u8]) -> Result<Vec<u8>, WalletError> {
+        if self.cipher != "chacha20poly1305" {
+            return Err(crypto_error(format!("unsupported cipher {}", self.cipher)));
+        }
+        let nonce = <[u8; 12]>::from_hex(&self.nonce).map_err(|e| crypto_error(e.to_string()))?;
+        let ciphertext =
+            Vec::<u8>::from_hex(&self.ciphertext).map_err(|e| crypto_error(e.to_string()))?;
+
+        let cipher = ChaCha20Poly1305::new(&derive_key(password, &self.kdf)?);
+        cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| WalletError::WrongPassword)
+    }
+}
+
+fn derive_key(password: &str, kdf: &Kdf) -> Result<Key, WalletError> {
+    let Kdf::Argon2id {
+        memory_kib,
+        iterations,
+        parallelism,
+        salt,
+    } = kdf;
+    for (parameter, value, max) in [
+        ("memory_kib", *memory_kib, MAX_MEMORY_KIB),
+        ("iterations", *iterations, MAX_ITERATIONS),
+        ("parallelism", *parallelism, MAX_PARALLELISM),
+    ] {
+        if value > max {
+            return Err(WalletError::KdfLimitExceeded {
+                parameter: parameter.to_string(),
+                value,
+                max,
+            });
+        }
+    }
+    let salt = Vec::<u8>::from_hex(salt).map_err(|e| crypto_error(e.to_string()))?;
+    let params = Params::new(*memory_kib, *iterations, *parallelism, Some(KEY_LEN))
+        .map_err(|e| crypto_error(e.to_string()))?;
+
+    let mut key = Key::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), &salt, &mut key)
+        .map_err(|e| crypto_error(e.to_string()))?;
+    Ok(key)
+}
+
+fn crypto_error(message: impl Into<String>) -> WalletError {
+    WalletError::Keystore {
+        message: message.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_and_open() {
+        let sealed = Sealed::seal("correct horse", b"secret", b"signet").unwrap();
+        assert_eq!(sealed.cipher, "chacha20poly1305");
+        assert_eq!(sealed.open("correct horse", b"signet").unwrap(), b"secret");
+    }
+
+    #[test]
+    fn wrong_password_or_data() {
+        let sealed = Sealed::seal("correct horse", b"secret", b"signet").unwrap();
+        assert!(matches!(
+            sealed.open("battery staple", b"signet"),
+            Err(WalletError::WrongPassword)
+        ));
+        assert!(matches!(
+            sealed.open("correct horse", b"bitcoin"),
+            Err(WalletError::WrongPassword)
+        ));
+
+        let mut tampered = sealed.clone();
+        let flipped = if tampered.ciphertext.starts_with('0') {
+            "1"
+        } else {
+            "0"
+        };
+        tampered.ciphertext.replace_range(..1, flipped);
+        assert!(matches!(
+            tampered.open("correct horse", b"signet"),
+            Err(WalletError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn kdf_limits() {
+        let mut sealed = Sealed::seal("correct horse", b"secret", b"signet").unwrap();
+        let Kdf::Argon2id { memory_kib, .. } = &mut sealed.kdf;
+        *memory_kib = MAX_MEMORY_KIB + 1;
+        match sealed.open("correct horse", b"signet") {
+            Err(WalletError::KdfLimitExceeded {
+                parameter, value, ..
+            }) => {
+                assert_eq!(parameter, "memory_kib");
+                assert_eq!(value, MAX_MEMORY_KIB + 1);
+            }
+            other => panic!("expected KdfLimitExceeded, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,497 @@
+// Public keys and descriptors imported for watch-only wallets.
+//
+// Accepts either a bare extended public key (xpub/tpub, or the ypub/upub and
+// zpub/vpub spellings other wallets export for nested and native segwit),
+// optionally prefixed with its key origin, or public output descriptors.
+// Anything holding a private key is refused: watch-only wallets never see one.
+// Multisig cosigner keys go through the same checks.
+use std::str::FromStr;
+
+use bdk_wallet::{
+    bitcoin::{
+        base58,
+        bip32::{ChildNumber, Fingerprint, Xpub},
+        key::Secp256k1,
+        Network, NetworkKind,
+    },
+    descriptor::{ExtendedDescriptor, IntoWalletDescriptor},
+    miniscript::{descriptor::WshInner, Descriptor, DescriptorPublicKey, ForEachKey, Terminal},
+};
+
+use crate::error::WalletError;
+use crate::keystore::ScriptType;
+
+// SLIP-132 version bytes, and the standard ones they stand in for
+const YPUB: [u8; 4] = [0x04, 0x9d, 0x7c, 0xb2];
+const UPUB: [u8; 4] = [0x04, 0x4a, 0x52, 0x62];
+const ZPUB: [u8; 4] = [0x04, 0xb2, 0x47, 0x46];
+const VPUB: [u8; 4] = [0x04, 0x5f, 0x1c, 0xf6];
+// Capitalized ones are for multisig
+const ZPUB_MULTISIG: [u8; 4] = [0x02, 0xaa, 0x7e, 0xd3];
+const VPUB_MULTISIG: [u8; 4] = [0x02, 0x57, 0x54, 0x83];
+const XPUB: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const TPUB: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+
+/// Validated public descriptors for a watch-only wallet.
+#[derive(Debug, Clone)]
+pub struct WatchOnly {
+    pub network: Network,
+    pub script_type: ScriptType,
+    /// Master fingerprint from the key origin, or of the key itself when
+    /// the origin wasn't given
+    pub fingerprint: String,
+    /// Account from the key origin (`m/purpose'/coin'/account'`), 0 when
+    /// the origin doesn't say
+    pub account: u32,
+    /// Public descriptors with checksums
+    pub external: String,
+    /// Missing for single descriptor wallets, which take change on the
+    /// external keychain
+    pub internal: Option<String>,
+}
+
+impl WatchOnly {
+    /// Parse what the user pasted: an extended public key, a descriptor
+    /// (multipath `<0;1>` descriptors cover both keychains), or a pair of
+    /// descriptors when `change` is given.
+    pub fn parse(input: &str, change: Option<&str>, network: Network) -> Result<Self, WalletError> {
+        let input = input.trim();
+        let change = change.map(str::trim).filter(|c| !c.is_empty());
+
+        let (external, internal) = if input.contains('(') {
+            let mut descriptors = parse_descriptor(input, "descriptor", network)?;
+            match (descriptors.len(), change) {
+                (1, Some(change)) => {
+                    let mut change = parse_descriptor(change, "change_descriptor", network)?;
+                    if change.len() != 1 {
+                        return Err(invalid("change_descriptor", "must be a single descriptor"));
+                    }
+                    (descriptors.remove(0), change.pop())
+                }
+                (1, None) => (descriptors.remove(0), None),
+                (2, None) => {
+                    let internal = descriptors.pop();
+                    (descriptors.remove(0), internal)
+                }
+                (2, Some(_)) => {
+                    return Err(invalid(
+                        "change_descriptor",
+                        "not needed, the descriptor already has a change path",
+                    ))
+                }
+                _ => return Err(invalid("descriptor", "expected at most two paths")),
+            }
+        } else {
+            if change.is_some() {
+                return Err(invalid(
+                    "change_descriptor",
+                    "only used together with a descriptor",
+                ));
+            }
+            let (key, script_type) = parse_extended_key(input, "key", network)?;
+            let external = parse_descriptor(&single_key(script_type, &key, 0)?, "key", network)?;
+            let internal = parse_descriptor(&single_key(script_type, &key, 1)?, "key", network)?;
+            (
+                external.into_iter().next().expect("single path"),
+                internal.into_iter().next(),
+            )
+        };
+
+        let script_type = script_type(&external).ok_or_else(|| {
+            invalid(
+                "descriptor",
+                format!(
+                    "{:?} descriptors are not supported yet",
+                    external.desc_type()
+                ),
+            )
+        })?;
+        // Signatures are counted against the external descriptor, so change
+        // must be spent the same way
+        if let Some(internal) = &internal {
+            if internal.desc_type() != external.desc_type() {
+                return Err(invalid(
+                    "change_descriptor",
+                    "must use the same script type as the descriptor",
+                ));
+            }
+            if fingerprints(internal) != fingerprints(&external) {
+                return Err(invalid(
+                    "change_descriptor",
+                    "must use the same keys as the descriptor",
+                ));
+            }
+            if required_signatures(internal) != required_signatures(&external) {
+                return Err(invalid(
+                    "change_descriptor",
+                    "must need as many signatures as the descriptor",
+                ));
+            }
+        }
+
+        let mut fingerprint = None;
+        let mut account = None;
+        external.for_each_key(|key| {
+            fingerprint.get_or_insert(key.master_fingerprint());
+            account.get_or_insert_with(|| match key {
+                DescriptorPublicKey::XPub(xpub) => xpub
+                    .origin
+                    .as_ref()
+                    .and_then(|(_, path)| path.into_iter().nth(2).copied()),
+                _ => None,
+            });
+            true
+        });
+        let account = match account.flatten() {
+            Some(ChildNumber::Hardened { index }) => index,
+            _ => 0,
+        };
+
+        Ok(Self {
+            network,
+            script_type,
+            fingerprint: fingerprint.map(|f| f.to_string()).unwrap_or_default(),
+            account,
+            external: external.to_string(),
+            internal: internal.map(|d| d.to_string()),
+        })
+    }
+}
+
+// Parse a public descriptor, checking its checksum when it has one and that
+// its keys are for `network`. Multipath descriptors come back split.
+fn parse_descriptor(
+    descriptor: &str,
+    field: &str,
+    network: Network,
+) -> Result<Vec<ExtendedDescriptor>, WalletError> {
+    let secp = Secp256k1::new();
+    let (descriptor, keymap) = Descriptor::parse_descriptor(&secp, descriptor)
+        .map_err(|e| invalid(field, e.to_string()))?;
+    if !keymap.is_empty() {
+        return Err(invalid(
+            field,
+            "contains a private key; import the public descriptor instead",
+        ));
+    }
+
+    let descriptors = if descriptor.is_multipath() {
+        descriptor
+            .into_single_descriptors()
+            .map_err(|e| invalid(field, e.to_string()))?
+    } else {
+        vec![descriptor]
+    };
+    descriptors
+        .into_iter()
+        .map(|descriptor| {
+            descriptor
+                .into_wallet_descriptor(&secp, network)
+                .map(|(descriptor, _)| descriptor)
+                .map_err(|e| invalid(field, e.to_string()))
+        })
+        .collect()
+}
+
+/// A multisig cosigner's account key, optionally with its
+/// `[fingerprint/path]` origin, in the form descriptors expect.
+pub fn parse_cosigner(input: &str, network: Network) -> Result<String, WalletError> {
+    parse_extended_key(input.trim(), "cosigners", network).map(|(key, _)| key)
+}
+
+/// Signatures a transaction from this descriptor needs: the threshold of a
+/// `multi` or `sortedmulti` multisig, one otherwise.
+pub fn required_signatures(descriptor: &ExtendedDescriptor) -> usize {
+    match descriptor {
+        Descriptor::Wsh(wsh) => match wsh.as_inner() {
+            WshInner::SortedMulti(multi) => multi.k(),
+            WshInner::Ms(ms) => match ms.as_inner() {
+                Terminal::Multi(thresh) => thresh.k(),
+                _ => 1,
+            },
+        },
+        _ => 1,
+    }
+}
+
+/// The script type of a descriptor, if it is one we support: a single key
+/// descriptor, or a `multi` or `sortedmulti` multisig.
+pub fn script_type(descriptor: &ExtendedDescriptor) -> Option<ScriptType> {
+    match descriptor {
+        // Script paths are more than a single key can watch
+        Descriptor::Tr(tr) if tr.tap_tree().is_some() => None,
+        Descriptor::Wsh(wsh) => match wsh.as_inner() {
+            WshInner::SortedMulti(_) => Some(ScriptType::Bip48),
+            WshInner::Ms(ms) => match ms.as_inner() {
+                Terminal::Multi(_) => Some(ScriptType::Bip48),
+                _ => None,
+            },
+        },
+        descriptor => ScriptType::for_descriptor(descriptor.desc_type()),
+    }
+}
+
+// Master fingerprints of the descriptor's keys, in order
+fn fingerprints(descriptor: &ExtendedDescriptor) -> Vec<Fingerprint> {
+    let mut fingerprints = Vec::new();
+    descriptor.for_each_key(|key| {
+        fingerprints.push(key.master_fingerprint());
+        true
+    });
+    fingerprints.sort();
+    fingerprints
+}
+
+// An extended public key, optionally with its `[fingerprint/path]` origin,
+// rewritten to the standard xpub/tpub encoding descriptors expect, and the
+// script type its prefix stands for. Plain xpubs/tpubs don't say, so they
+// get native segwit like zpubs/vpubs.
+fn parse_extended_key(
+    input: &str,
+    field: &str,
+    network: Network,
+) -> Result<(String, ScriptType), WalletError> {
+    let (origin, key) = match input.strip_prefix('[') {
+        Some(rest) => {
+            let (origin, key) = rest
+                .split_once(']')
+                .ok_or_else(|| invalid(field, "key origin is missing its closing `]`"))?;
+            (format!("[{}]", origin), key)
+        }
+        None => (String::new(), input),
+    };
+
+    let mut data = base58::decode_check(key)
+        .ok()
+        .filter(|data| data.len() >= 4)
+        .ok_or_else(|| invalid(field, "not an extended public key or descriptor"))?;
+    let version: [u8; 4] = data[..4].try_into().expect("four bytes");
+    let (standard, script_type) = match version {
+        YPUB => (XPUB, ScriptType::Bip49),
+        UPUB => (TPUB, ScriptType::Bip49),
+        ZPUB => (XPUB, ScriptType::Bip84),
+        VPUB => (TPUB, ScriptType::Bip84),
+        ZPUB_MULTISIG => (XPUB, ScriptType::Bip48),
+        VPUB_MULTISIG => (TPUB, ScriptType::Bip48),
+        XPUB | TPUB => (version, ScriptType::Bip84),
+        _ => {
+            return Err(invalid(
+                field,
+                "expected an extended public key such as an xpub, tpub, zpub or vpub; \
+                 private keys are not accepted",
+            ))
+        }
+    };
+    data[..4].copy_from_slice(&standard);
+
+    let xpub = Xpub::decode(&data).map_err(|e| invalid(field, e.to_string()))?;
+    if xpub.network != NetworkKind::from(network) {
+        return Err(WalletError::NetworkMismatch {
+            message: format!("key is not for {}", network),
+        });
+    }
+    // Round-trip through FromStr so a bad origin is reported here
+    let key = format!("{}{}", origin, xpub);
+    DescriptorPublicKey::from_str(&key).map_err(|e| invalid(field, e.to_string()))?;
+    Ok((key, script_type))
+}
+
+// Descriptor for one keychain of an account-level key
+fn single_key(script_type: ScriptType, key: &str, keychain: u32) -> Result<String, WalletError> {
+    Ok(match script_type {
+        ScriptType::Bip44 => format!("pkh({}/{}/*)", key, keychain),
+        ScriptType::Bip49 => format!("sh(wpkh({}/{}/*))", key, keychain),
+        ScriptType::Bip84 => format!("wpkh({}/{}/*)", key, keychain),
+        ScriptType::Bip86 => format!("tr({}/{}/*)", key, keychain),
+        ScriptType::Bip48 => {
+            return Err(invalid(
+                "key",
+                "a multisig key alone doesn't describe the wallet; import its descriptor instead",
+            ))
+        }
+    })
+}
+
+fn invalid(field: &str, message: impl Into<String>) -> WalletError {
+    WalletError::InvalidArgument {
+        field: field.to_string(),
+        message: message.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bdk_wallet::bitcoin::bip32::{DerivationPath, Xpriv};
+    use bdk_wallet::keys::bip39::Mnemonic;
+    use bdk_wallet::{KeychainKind, Wallet};
+
+    // BIP84 test vector
+    const WORDS: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    // Account key of the test vector at m/84'/coin'/0'
+    fn account_xpub(network: Network) -> Xpub {
+        let seed = Mnemonic::parse(WORDS).unwrap().to_seed("");
+        let secp = Secp256k1::new();
+        let coin = if network == Network::Bitcoin { 0 } else { 1 };
+        let path = DerivationPath::from_str(&format!("m/84'/{}'/0'", coin)).unwrap();
+        let xprv = Xpriv::new_master(network, &seed)
+            .unwrap()
+            .derive_priv(&secp, &path)
+            .unwrap();
+        Xpub::from_priv(&secp, &xprv)
+    }
+
+    // The same key under other version bytes
+    fn with_version(xpub: &Xpub, version: [u8; 4]) -> String {
+        let mut data = xpub.encode();
+        data[..4].copy_from_slice(&version);
+        base58::encode_check(&data)
+    }
+
+    fn first_address(imported: &WatchOnly) -> String {
+        let wallet = Wallet::create(
+            imported.external.clone(),
+            imported.internal.clone().unwrap(),
+        )
+        .network(imported.network)
+        .create_wallet_no_persist()
+        .unwrap();
+        wallet
+            .peek_address(KeychainKind::External, 0)
+            .address
+            .to_string()
+    }
+
+    #[test]
+    fn slip132_keys() {
+        let xpub = account_xpub(Network::Bitcoin);
+        let zpub = with_version(&xpub, ZPUB);
+        assert!(zpub.starts_with("zpub"));
+        let imported = WatchOnly::parse(&zpub, None, Network::Bitcoin).unwrap();
+        assert_eq!(imported.script_type, ScriptType::Bip84);
+        assert!(imported
+            .external
+            .starts_with(&format!("wpkh({}/0/*)", xpub)));
+        assert_eq!(
+            first_address(&imported),
+            "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"
+        );
+        let plain = WatchOnly::parse(&xpub.to_string(), None, Network::Bitcoin).unwrap();
+        assert_eq!(plain.external, imported.external);
+
+        let tpub = account_xpub(Network::Testnet);
+        let vpub = with_version(&tpub, VPUB);
+        assert!(vpub.starts_with("vpub"));
+        let imported = WatchOnly::parse(&vpub, None, Network::Signet).unwrap();
+        assert!(imported
+            .external
+            .starts_with(&format!("wpkh({}/0/*)", tpub)));
+        assert!(imported
+            .internal
+            .unwrap()
+            .starts_with(&format!("wpkh({}/1/*)", tpub)));
+    }
+
+    #[test]
+    fn key_origin() {
+        let xpub = account_xpub(Network::Bitcoin);
+        let key = format!("[73c5da0a/84'/0'/0']{}", with_version(&xpub, ZPUB));
+        let imported = WatchOnly::parse(&key, None, Network::Bitcoin).unwrap();
+        assert_eq!(imported.fingerprint, "73c5da0a");
+        assert!(imported
+            .external
+            .starts_with("wpkh([73c5da0a/84'/0'/0']xpub"));
+
+        assert!(matches!(
+            WatchOnly::parse(&key[1..], None, Network::Bitcoin),
+            Err(WalletError::InvalidArgument { field, .. }) if field == "key"
+        ));
+    }
+
+    #[test]
+    fn descriptors() {
+        let xpub = account_xpub(Network::Bitcoin);
+        let multipath = format!("wpkh({}/<0;1>/*)", xpub);
+        let imported = WatchOnly::parse(&multipath, None, Network::Bitcoin).unwrap();
+        assert!(imported.internal.unwrap().contains("/1/*)"));
+
+        let external = format!("wpkh({}/0/*)", xpub);
+        let single = WatchOnly::parse(&external, None, Network::Bitcoin).unwrap();
+        assert!(single.internal.is_none());
+        assert!(matches!(
+            WatchOnly::parse(&multipath, Some(&external), Network::Bitcoin),
+            Err(WalletError::InvalidArgument { field, .. }) if field == "change_descriptor"
+        ));
+    }
+
+    #[test]
+    fn multisig_thresholds() {
+        let xpubs: Vec<_> = [Network::Bitcoin, Network::Testnet]
+            .into_iter()
+            .map(|network| {
+                // Another seed's key, but still a mainnet xpub
+                let xpub = account_xpub(network);
+                Xpub {
+                    network: NetworkKind::Main,
+                    ..xpub
+                }
+            })
+            .collect();
+        let multi = |function: &str, k: usize, branch: u32| {
+            format!(
+                "wsh({}({},{}/{}/*,{}/{}/*))",
+                function, k, xpubs[0], branch, xpubs[1], branch
+            )
+        };
+        for function in ["multi", "sortedmulti"] {
+            let imported = WatchOnly::parse(
+                &multi(function, 2, 0),
+                Some(&multi(function, 2, 1)),
+                Network::Bitcoin,
+            )
+            .unwrap();
+            let (external, _) =
+                Descriptor::parse_descriptor(&Secp256k1::new(), &imported.external).unwrap();
+            assert_eq!(required_signatures(&external), 2, "{}", function);
+
+            assert!(matches!(
+                WatchOnly::parse(
+                    &multi(function, 2, 0),
+                    Some(&multi(function, 1, 1)),
+                    Network::Bitcoin,
+                ),
+                Err(WalletError::InvalidArgument { field, message })
+                    if field == "change_descriptor" && message.contains("signatures")
+            ));
+        }
+
+        let other_keys = format!("wsh(multi(2,{}/1/*,{}/2/*))", xpubs[0], xpubs[0]);
+        assert!(matches!(
+            WatchOnly::parse(&multi("multi", 2, 0), Some(&other_keys), Network::Bitcoin),
+            Err(WalletError::InvalidArgument { field, message })
+                if field == "change_descriptor" && message.contains("keys")
+        ));
+    }
+
+    #[test]
+    fn refuses_private_and_foreign_keys() {
+        let xpub = account_xpub(Network::Bitcoin);
+        assert!(matches!(
+            WatchOnly::parse(&xpub.to_string(), None, Network::Signet),
+            Err(WalletError::NetworkMismatch { .. })
+        ));
+
+        let seed = Mnemonic::parse(WORDS).unwrap().to_seed("");
+        let xprv = Xpriv::new_master(Network::Bitcoin, &seed).unwrap();
+        assert!(matches!(
+            WatchOnly::parse(&xprv.to_string(), None, Network::Bitcoin),
+            Err(WalletError::InvalidArgument { field, .. }) if field == "key"
+        ));
+        assert!(matches!(
+            WatchOnly::parse(&format!("wpkh({}/0/*)", xprv), None, Network::Bitcoin),
+            Err(WalletError::InvalidArgument { field, .. }) if field == "descriptor"
+        ));
+    }
+}
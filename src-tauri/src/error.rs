@@ -0,0 +1,366 @@
+// Errors reported by the wallet service.
+//
+// Serialized with a stable snake_case `code` plus structured fields so the
+// frontend can react to and localize errors without parsing the message.
+use bdk_esplora::esplora_client;
+use bdk_wallet::{
+    bitcoin::Network, chain::rusqlite, error::CreateTxError, signer::SignerError,
+    CreateWithPersistError, LoadError, LoadMismatch, LoadWithPersistError,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, Serialize, Deserialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum WalletError {
+    #[error("Wallet database error: {message}")]
+    Database { message: String },
+    #[error("Wallet data does not match the configured descriptors: {message}")]
+    DescriptorMismatch { message: String },
+    #[error("Wallet data belongs to a different network: {message}")]
+    NetworkMismatch { message: String },
+    #[error("Wallet not found. Create a wallet first.")]
+    WalletNotFound,
+    #[error("This wallet has already been added")]
+    WalletAlreadyExists,
+    #[error("Wallet is busy sending a transaction, try again when it is done")]
+    WalletBusy,
+    #[error("A wallet is already being restored")]
+    RestoreInProgress,
+    #[error("Invalid recovery words: {message}")]
+    InvalidMnemonic { message: String },
+    #[error("Wrong password")]
+    WrongPassword,
+    #[error("Wallet is locked. Unlock it with your password first.")]
+    WalletLocked,
+    #[error("This is a watch-only wallet without private keys")]
+    WatchOnly,
+    #[error("Keystore error: {message}")]
+    Keystore { message: String },
+    #[error("Key derivation asks for {parameter} = {value}, more than the {max} allowed")]
+    KdfLimitExceeded {
+        parameter: String,
+        value: u32,
+        max: u32,
+    },
+    #[error("Invalid {field}: {message}")]
+    InvalidArgument { field: String, message: String },
+    #[error("Invalid address: {message}")]
+    InvalidAddress { message: String },
+    #[error("Address is not a {network} address")]
+    AddressNetworkMismatch { network: Network },
+    #[error("Not enough funds. Required: {required} sats, Available: {available} sats")]
+    InsufficientFunds { required: u64, available: u64 },
+    #[error("Failed to build transaction: {message}")]
+    TransactionBuild { message: String },
+    #[error("Failed to sign transaction: {message}")]
+    Signing { message: String },
+    #[error("Esplora request failed: {message}")]
+    Esplora { message: String },
+    #[error("No fee estimates ({message}), pick a custom fee rate instead")]
+    FeeEstimatesUnavailable { message: String },
+    #[error("Sync did not finish within {seconds} seconds")]
+    SyncTimeout { seconds: u64 },
+    #[error("Sync was cancelled")]
+    SyncCancelled,
+    #[error("Transaction rejected by the network: {message}")]
+    BroadcastRejected { status: u16, message: String },
+    #[error("Background task unavailable: {message}")]
+    ServiceUnavailable { message: String },
+    #[error("Background task is stopped. Start it again first.")]
+    ServiceStopped,
+    #[error("Background task is restarting, try again shortly")]
+    ServiceRestarting,
+    #[error("Background job failed: {message}")]
+    JobFailed { message: String },
+    #[error("Configuration error: {message}")]
+    Config { message: String },
+    #[error("Invalid configuration for {field}: {message}")]
+    InvalidConfig { field: String, message: String },
+}
+
+impl WalletError {
+    /// Map an esplora error from a broadcast, where an HTTP error response
+    /// means the node refused the transaction rather than a transport problem.
+    pub fn broadcast(error: esplora_client::Error) -> Self {
+        match error {
+            esplora_client::Error::HttpResponse { status, message } => {
+                WalletError::BroadcastRejected { status, message }
+            }
+            other => other.into(),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for WalletError {
+    fn from(error: rusqlite::Error) -> Self {
+        WalletError::Database {
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<LoadWithPersistError<rusqlite::Error>> for WalletError {
+    fn from(error: LoadWithPersistError<rusqlite::Error>) -> Self {
+        match error {
+            LoadWithPersistError::Persist(e) => e.into(),
+            LoadWithPersistError::InvalidChangeSet(e) => e.into(),
+        }
+    }
+}
+
+impl From<LoadError> for WalletError {
+    fn from(error: LoadError) -> Self {
+        let message = error.to_string();
+        match error {
+            LoadError::Mismatch(LoadMismatch::Network { .. })
+            | LoadError::Mismatch(LoadMismatch::Genesis { .. }) => {
+                WalletError::NetworkMismatch { message }
+            }
+            LoadError::Descriptor(_) | LoadError::Mismatch(_) => {
+                WalletError::DescriptorMismatch { message }
+            }
+            // Anything else means the stored changeset is incomplete
+            _ => WalletError::Database { message },
+        }
+    }
+}
+
+impl From<CreateWithPersistError<rusqlite::Error>> for WalletError {
+    fn from(error: CreateWithPersistError<rusqlite::Error>) -> Self {
+        let message = error.to_string();
+        match error {
+            CreateWithPersistError::Persist(e) => e.into(),
+            CreateWithPersistError::Descriptor(_) => WalletError::DescriptorMismatch { message },
+            CreateWithPersistError::DataAlreadyExists(_) => WalletError::Database { message },
+        }
+    }
+}
+
+impl From<CreateTxError> for WalletError {
+    fn from(error: CreateTxError) -> Self {
+        match error {
+            CreateTxError::CoinSelection(e) => WalletError::InsufficientFunds {
+                required: e.needed.to_sat(),
+                available: e.available.to_sat(),
+            },
+            other => WalletError::TransactionBuild {
+                message: other.to_string(),
+            },
+        }
+    }
+}
+
+impl From<SignerError> for WalletError {
+    fn from(error: SignerError) -> Self {
+        WalletError::Signing {
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<esplora_client::Error> for WalletError {
+    fn from(error: esplora_client::Error) -> Self {
+        use esplora_client::Error;
+
+        // The client's own Display is its Debug output, which buries the
+        // cause in a dump of the whole request
+        let message = match error {
+            Error::Reqwest(e) => {
+                let host = e.url().and_then(|url| url.host_str()).unwrap_or("server");
+                let mut cause: &dyn std::error::Error = &e;
+                while let Some(source) = cause.source() {
+                    cause = source;
+                }
+                format!("{}: {}", host, cause)
+            }
+            Error::HttpResponse { status, .. } => format!("server answered with HTTP {}", status),
+            Error::TransactionNotFound(txid) => format!("transaction {} not found", txid),
+            Error::HeaderHeightNotFound(height) => format!("no block at height {}", height),
+            Error::HeaderHashNotFound(hash) => format!("block {} not found", hash),
+            Error::Parsing(_)
+            | Error::StatusCode(_)
+            | Error::BitcoinEncoding(_)
+            | Error::HexToArray(_)
+            | Error::HexToBytes(_)
+            | Error::InvalidResponse => "server sent an invalid response".to_string(),
+            Error::InvalidHttpHeaderName(header) | Error::InvalidHttpHeaderValue(header) => {
+                format!("invalid HTTP header {}", header)
+            }
+            // Only the blocking client's errors are left
+            other => other.to_string(),
+        };
+        WalletError::Esplora { message }
+    }
+}
+
+impl From<Box<esplora_client::Error>> for WalletError {
+    fn from(error: Box<esplora_client::Error>) -> Self {
+        (*error).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> String {
+        "details".to_string()
+    }
+
+    // The codes are what the frontend matches on, so they must not change
+    #[test]
+    fn codes() {
+        let errors = [
+            (WalletError::Database { message: message() }, "database"),
+            (
+                WalletError::DescriptorMismatch { message: message() },
+                "descriptor_mismatch",
+            ),
+            (
+                WalletError::NetworkMismatch { message: message() },
+                "network_mismatch",
+            ),
+            (WalletError::WalletNotFound, "wallet_not_found"),
+            (
+                WalletError::InsufficientFunds {
+                    required: 2,
+                    available: 1,
+                },
+                "insufficient_funds",
+            ),
+            (
+                WalletError::TransactionBuild { message: message() },
+                "transaction_build",
+            ),
+            (WalletError::Signing { message: message() }, "signing"),
+            (WalletError::Esplora { message: message() }, "esplora"),
+            (
+                WalletError::BroadcastRejected {
+                    status: 400,
+                    message: message(),
+                },
+                "broadcast_rejected",
+            ),
+            (
+                WalletError::ServiceUnavailable { message: message() },
+                "service_unavailable",
+            ),
+            (WalletError::Config { message: message() }, "config"),
+            (
+                WalletError::InvalidConfig {
+                    field: "stop_gap".to_string(),
+                    message: message(),
+                },
+                "invalid_config",
+            ),
+            (WalletError::JobFailed { message: message() }, "job_failed"),
+            (WalletError::SyncTimeout { seconds: 600 }, "sync_timeout"),
+            (WalletError::SyncCancelled, "sync_cancelled"),
+            (WalletError::ServiceStopped, "service_stopped"),
+            (WalletError::ServiceRestarting, "service_restarting"),
+            (WalletError::WalletAlreadyExists, "wallet_already_exists"),
+            (WalletError::Keystore { message: message() }, "keystore"),
+            (
+                WalletError::InvalidArgument {
+                    field: "word_count".to_string(),
+                    message: message(),
+                },
+                "invalid_argument",
+            ),
+            (WalletError::RestoreInProgress, "restore_in_progress"),
+            (
+                WalletError::InvalidMnemonic { message: message() },
+                "invalid_mnemonic",
+            ),
+            (WalletError::WrongPassword, "wrong_password"),
+            (WalletError::WalletLocked, "wallet_locked"),
+            (WalletError::WatchOnly, "watch_only"),
+            (WalletError::WalletBusy, "wallet_busy"),
+            (
+                WalletError::InvalidAddress {
+                    message: "checksum".to_string(),
+                },
+                "invalid_address",
+            ),
+            (
+                WalletError::AddressNetworkMismatch {
+                    network: Network::Signet,
+                },
+                "address_network_mismatch",
+            ),
+            (
+                WalletError::KdfLimitExceeded {
+                    parameter: "memory_kib".to_string(),
+                    value: 1 << 30,
+                    max: 1 << 20,
+                },
+                "kdf_limit_exceeded",
+            ),
+            (
+                WalletError::FeeEstimatesUnavailable { message: message() },
+                "fee_estimates_unavailable",
+            ),
+        ];
+        for (error, code) in errors {
+            let value = serde_json::to_value(&error).unwrap();
+            assert_eq!(value["code"], code);
+            assert_eq!(serde_json::from_value::<WalletError>(value).unwrap(), error);
+        }
+    }
+
+    #[test]
+    fn fields_sit_next_to_the_code() {
+        let error = WalletError::InsufficientFunds {
+            required: 2,
+            available: 1,
+        };
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({"code": "insufficient_funds", "required": 2, "available": 1})
+        );
+    }
+
+    #[test]
+    fn broadcast_errors() {
+        let rejected = WalletError::broadcast(esplora_client::Error::HttpResponse {
+            status: 400,
+            message: "bad-txns-inputs-missingorspent".to_string(),
+        });
+        assert_eq!(
+            rejected,
+            WalletError::BroadcastRejected {
+                status: 400,
+                message: "bad-txns-inputs-missingorspent".to_string(),
+            }
+        );
+        let other = WalletError::broadcast(esplora_client::Error::HeaderHeightNotFound(7));
+        assert!(matches!(other, WalletError::Esplora { .. }));
+    }
+
+    #[tokio::test]
+    async fn esplora_messages() {
+        // Nothing listens on the discard port, so the connection fails
+        let unreachable: WalletError = esplora_client::Builder::new("http://127.0.0.1:9")
+            .build_async()
+            .unwrap()
+            .get_height()
+            .await
+            .unwrap_err()
+            .into();
+        let WalletError::Esplora { message } = unreachable else {
+            panic!("expected Esplora, got {:?}", unreachable);
+        };
+        assert!(message.starts_with("127.0.0.1: "), "{}", message);
+        assert!(!message.contains("Reqwest"), "{}", message);
+
+        let status: WalletError = esplora_client::Error::HttpResponse {
+            status: 503,
+            message: "<html>...</html>".to_string(),
+        }
+        .into();
+        assert_eq!(
+            status.to_string(),
+            "Esplora request failed: server answered with HTTP 503"
+        );
+    }
+}
@@ -0,0 +1,722 @@
+// Typed payloads for every event the wallet service emits.
+//
+// Each payload is wrapped in an `Event` envelope carrying the schema version,
+// the id of the request that caused the event, if any, and the id of the
+// wallet it is about, if any.
+use std::sync::Arc;
+
+use bdk_wallet::bitcoin::{Address, Network};
+use bdk_wallet::miniscript::ForEachKey;
+use bdk_wallet::{KeychainKind, LocalOutput, Wallet};
+use serde::{Deserialize, Serialize};
+
+use crate::backup::Birthday;
+use crate::config::Config;
+use crate::error::WalletError;
+use crate::fees::FeeEstimates;
+use crate::keystore::ScriptType;
+use crate::labels::Label;
+use crate::message::MessageFormat;
+use crate::registry::{WalletEntry, WalletId};
+
+/// Bumped whenever an event payload changes in a way the frontend must know about.
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+/// A payload that can be emitted as a named event.
+pub trait WalletEvent: Serialize {
+    const NAME: &'static str;
+}
+
+/// Destination for events emitted by the wallet service.
+///
+/// The Tauri layer forwards these to the main webview; other frontends can
+/// log them, print them or collect them for assertions.
+pub trait EventSink: Send + Sync + 'static {
+    fn emit(&self, event: &str, payload: serde_json::Value);
+}
+
+impl<F> EventSink for F
+where
+    F: Fn(&str, serde_json::Value) + Send + Sync + 'static,
+{
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        self(event, payload)
+    }
+}
+
+// Wraps payloads in a versioned envelope and hands them to the sink. Kept
+// separate from the wallet state so events can be emitted while the wallet
+// is borrowed, and cheap to clone into spawned jobs.
+#[derive(Clone)]
+pub(crate) struct EventEmitter {
+    sink: Arc<dyn EventSink>,
+    request_id: Option<u64>,
+    wallet_id: Option<WalletId>,
+}
+
+impl EventEmitter {
+    pub(crate) fn new(sink: Arc<dyn EventSink>) -> Self {
+        Self {
+            sink,
+            request_id: None,
+            wallet_id: None,
+        }
+    }
+
+    // Emitter tagging every event with the request that caused it
+    pub(crate) fn for_request(&self, request_id: u64) -> Self {
+        Self {
+            sink: self.sink.clone(),
+            request_id: Some(request_id),
+            wallet_id: self.wallet_id,
+        }
+    }
+
+    // Emitter tagging every event with the wallet it is about
+    pub(crate) fn for_wallet(&self, wallet_id: WalletId) -> Self {
+        Self {
+            sink: self.sink.clone(),
+            request_id: self.request_id,
+            wallet_id: Some(wallet_id),
+        }
+    }
+
+    pub(crate) fn emit<E: WalletEvent>(&self, data: E) {
+        match serde_json::to_value(Event::new(self.request_id, self.wallet_id, data)) {
+            Ok(value) => self.sink.emit(E::NAME, value),
+            Err(e) => println!("Failed to serialize {} event: {}", E::NAME, e),
+        }
+    }
+}
+
+/// Envelope sent to the frontend for every event.
+///
+/// Payloads are flattened into it, so none of them may have a field of its
+/// own called `version`, `request_id` or `wallet_id`; the ids of the wallets
+/// they add are `new_wallet_id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event<T> {
+    pub version: u32,
+    pub request_id: Option<u64>,
+    /// Wallet the event is about; missing for app-wide events
+    #[serde(default)]
+    pub wallet_id: Option<WalletId>,
+    #[serde(flatten)]
+    pub data: T,
+}
+
+impl<T> Event<T> {
+    pub fn new(request_id: Option<u64>, wallet_id: Option<WalletId>, data: T) -> Self {
+        Self {
+            version: EVENT_SCHEMA_VERSION,
+            request_id,
+            wallet_id,
+            data,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pong {
+    pub message: String,
+}
+
+impl WalletEvent for Pong {
+    const NAME: &'static str = "background-event";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataUpdated {
+    pub data: String,
+}
+
+impl WalletEvent for DataUpdated {
+    const NAME: &'static str = "data-updated";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub count: u64,
+}
+
+impl WalletEvent for Heartbeat {
+    const NAME: &'static str = "heartbeat";
+}
+
+/// Lifecycle state of the supervised background task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerState {
+    Running,
+    /// Crashed and waiting to be started again
+    Restarting,
+    /// Shut down on request; needs `AppMessage::Start`
+    Stopped,
+}
+
+/// Health of the background task, emitted whenever its state changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceStatus {
+    pub state: WorkerState,
+    /// Times the task was started again since the app launched
+    pub restarts: u32,
+    /// Why the task last crashed, if it ever did
+    pub last_error: Option<String>,
+}
+
+impl ServiceStatus {
+    pub fn running() -> Self {
+        Self {
+            state: WorkerState::Running,
+            restarts: 0,
+            last_error: None,
+        }
+    }
+}
+
+impl WalletEvent for ServiceStatus {
+    const NAME: &'static str = "service-status";
+}
+
+/// A new wallet was generated. The recovery words are only ever in the
+/// reply to `CreateWallet`, never in an event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletCreated {
+    pub new_wallet_id: WalletId,
+    pub network: Network,
+    pub fingerprint: String,
+    pub script_type: ScriptType,
+}
+
+impl WalletEvent for WalletCreated {
+    const NAME: &'static str = "wallet-created";
+}
+
+/// Another account of an existing wallet's seed was added as a wallet of
+/// its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountAdded {
+    pub new_wallet_id: WalletId,
+    /// The wallet whose words the account was derived from
+    pub parent_id: WalletId,
+    pub network: Network,
+    pub fingerprint: String,
+    pub script_type: ScriptType,
+    pub account: u32,
+}
+
+impl WalletEvent for AccountAdded {
+    const NAME: &'static str = "account-added";
+}
+
+/// A wallet was restored from its recovery words and scanned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletRestored {
+    pub new_wallet_id: WalletId,
+    pub network: Network,
+    pub fingerprint: String,
+    /// The candidate that showed on-chain activity, or the first one given
+    pub script_type: ScriptType,
+    pub balance: BalanceInfo,
+}
+
+impl WalletEvent for WalletRestored {
+    const NAME: &'static str = "wallet-restored";
+}
+
+/// A multisig wallet was created between our key and the cosigners'.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultisigCreated {
+    pub new_wallet_id: WalletId,
+    pub network: Network,
+    pub fingerprint: String,
+    /// Signatures a transaction needs
+    pub threshold: u32,
+    /// Keys in the wallet, ours included
+    pub signers: u32,
+    /// Our account key with its origin, for the cosigners to add on their side
+    pub key: String,
+}
+
+impl WalletEvent for MultisigCreated {
+    const NAME: &'static str = "multisig-created";
+}
+
+/// A watch-only wallet was imported from public keys or descriptors.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletImported {
+    pub new_wallet_id: WalletId,
+    pub network: Network,
+    pub fingerprint: String,
+    pub script_type: ScriptType,
+}
+
+impl WalletEvent for WalletImported {
+    const NAME: &'static str = "wallet-imported";
+}
+
+/// A backup of a wallet was written. The document itself only goes to the
+/// requester.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupExported {
+    /// Holds only public descriptors
+    pub watch_only: bool,
+    pub encrypted: bool,
+}
+
+impl WalletEvent for BackupExported {
+    const NAME: &'static str = "backup-exported";
+}
+
+/// A wallet was added from a backup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupImported {
+    pub new_wallet_id: WalletId,
+    pub network: Network,
+    pub fingerprint: String,
+    pub script_type: ScriptType,
+    pub watch_only: bool,
+    pub birthday: Birthday,
+    pub labels: usize,
+    pub settings_restored: bool,
+}
+
+impl WalletEvent for BackupImported {
+    const NAME: &'static str = "backup-imported";
+}
+
+/// Every wallet in the registry, and which one requests go to by default.
+/// Emitted whenever a wallet is added, renamed, deleted or selected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletList {
+    pub active: Option<WalletId>,
+    pub wallets: Vec<WalletEntry>,
+}
+
+impl WalletEvent for WalletList {
+    const NAME: &'static str = "wallets-updated";
+}
+
+/// The signing keys were decrypted; the wallet can send.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletUnlocked {
+    /// Seconds without a request after which it locks itself again, if it
+    /// does
+    pub auto_lock_secs: Option<u64>,
+}
+
+impl WalletEvent for WalletUnlocked {
+    const NAME: &'static str = "wallet-unlocked";
+}
+
+/// The signing keys were dropped from memory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletLocked {
+    /// Locked by the idle timer rather than by a `Lock` request
+    pub idle: bool,
+}
+
+impl WalletEvent for WalletLocked {
+    const NAME: &'static str = "wallet-locked";
+}
+
+/// A message signed with the key of one of the wallet's addresses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedMessage {
+    pub address: String,
+    pub message: String,
+    /// Base64 encoded
+    pub signature: String,
+    pub format: MessageFormat,
+}
+
+impl WalletEvent for SignedMessage {
+    const NAME: &'static str = "message-signed";
+}
+
+/// Outcome of checking a message signature.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageVerification {
+    pub address: String,
+    pub valid: bool,
+    pub format: MessageFormat,
+}
+
+impl WalletEvent for MessageVerification {
+    const NAME: &'static str = "message-verified";
+}
+
+/// A revealed wallet address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressInfo {
+    pub address: String,
+    pub index: u32,
+    pub keychain: KeychainKind,
+    /// Which kind of address this is, e.g. `bip86` for Taproot
+    pub script_type: ScriptType,
+    /// Full BIP32 path of the key behind the address, e.g. `m/84'/1'/0'/0/3`
+    pub derivation_path: Option<String>,
+}
+
+impl AddressInfo {
+    pub fn new(
+        wallet: &Wallet,
+        script_type: ScriptType,
+        address: &bdk_wallet::AddressInfo,
+    ) -> Self {
+        Self {
+            address: address.address.to_string(),
+            index: address.index,
+            keychain: address.keychain,
+            script_type,
+            derivation_path: derivation_path(wallet, address.keychain, address.index),
+        }
+    }
+}
+
+// Derive the concrete descriptor at `index` and read the first key's origin
+fn derivation_path(wallet: &Wallet, keychain: KeychainKind, index: u32) -> Option<String> {
+    let descriptor = wallet
+        .public_descriptor(keychain)
+        .at_derivation_index(index)
+        .ok()?;
+
+    let mut path = None;
+    descriptor.for_any_key(|key| {
+        path = key.full_derivation_path();
+        path.is_some()
+    });
+    path.map(|path| format!("m/{}", path))
+}
+
+impl WalletEvent for AddressInfo {
+    const NAME: &'static str = "wallet-address";
+}
+
+/// Wallet balance broken down by confirmation state, all amounts in sats.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BalanceInfo {
+    pub confirmed: u64,
+    pub trusted_pending: u64,
+    pub untrusted_pending: u64,
+    pub immature: u64,
+    /// Confirmed plus trusted pending
+    pub spendable: u64,
+    pub total: u64,
+}
+
+impl From<bdk_wallet::Balance> for BalanceInfo {
+    fn from(balance: bdk_wallet::Balance) -> Self {
+        Self {
+            confirmed: balance.confirmed.to_sat(),
+            trusted_pending: balance.trusted_pending.to_sat(),
+            untrusted_pending: balance.untrusted_pending.to_sat(),
+            immature: balance.immature.to_sat(),
+            spendable: balance.trusted_spendable().to_sat(),
+            total: balance.total().to_sat(),
+        }
+    }
+}
+
+impl WalletEvent for BalanceInfo {
+    const NAME: &'static str = "wallet-balance";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncStarted {}
+
+impl WalletEvent for SyncStarted {
+    const NAME: &'static str = "sync-started";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncProgress {
+    pub keychain: KeychainKind,
+    pub index: u32,
+}
+
+impl WalletEvent for SyncProgress {
+    const NAME: &'static str = "sync-progress";
+}
+
+/// A full scan attempt is starting; `attempt` counts from 1.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncAttempt {
+    pub attempt: u32,
+    pub max_attempts: u32,
+}
+
+impl WalletEvent for SyncAttempt {
+    const NAME: &'static str = "sync-attempt";
+}
+
+/// A scan attempt hit a transient error and will be retried after `delay_ms`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncRetrying {
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub delay_ms: u64,
+    pub message: String,
+}
+
+impl WalletEvent for SyncRetrying {
+    const NAME: &'static str = "sync-retry";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncCancelled {}
+
+impl WalletEvent for SyncCancelled {
+    const NAME: &'static str = "sync-cancelled";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncCompleted {
+    pub balance: BalanceInfo,
+}
+
+impl WalletEvent for SyncCompleted {
+    const NAME: &'static str = "sync-completed";
+}
+
+/// An unspent output of the wallet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UtxoInfo {
+    /// `txid:vout`, as `SendAll` takes it
+    pub outpoint: String,
+    /// Value in sats
+    pub value: u64,
+    pub address: Option<String>,
+    pub keychain: KeychainKind,
+    /// Height of the block it confirmed in, if it has
+    pub confirmation_height: Option<u32>,
+}
+
+impl UtxoInfo {
+    pub fn new(utxo: &LocalOutput, network: Network) -> Self {
+        Self {
+            outpoint: utxo.outpoint.to_string(),
+            value: utxo.txout.value.to_sat(),
+            address: Address::from_script(&utxo.txout.script_pubkey, network)
+                .ok()
+                .map(|address| address.to_string()),
+            keychain: utxo.keychain,
+            confirmation_height: utxo.chain_position.confirmation_height_upper_bound(),
+        }
+    }
+}
+
+/// The wallet's unspent outputs, largest first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UtxoList {
+    pub utxos: Vec<UtxoInfo>,
+}
+
+impl WalletEvent for UtxoList {
+    const NAME: &'static str = "wallet-utxos";
+}
+
+/// The wallet's labels, after `GetLabels` or a change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LabelList {
+    pub labels: Vec<Label>,
+}
+
+impl WalletEvent for LabelList {
+    const NAME: &'static str = "wallet-labels";
+}
+
+/// An output of a sent transaction that pays someone, i.e. isn't change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputInfo {
+    pub vout: u32,
+    pub address: Option<String>,
+    /// Value in sats
+    pub amount: u64,
+}
+
+/// A broadcast transaction.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionInfo {
+    pub txid: String,
+    /// Sats paid out, without change
+    pub amount: u64,
+    pub outputs: Vec<OutputInfo>,
+    /// Absolute fee in sats
+    pub fee: u64,
+    /// Virtual size in vbytes
+    pub vsize: u64,
+    /// For batches, an estimate of the fees a transaction per recipient
+    /// would have cost on top, in sats
+    pub fee_saved: Option<u64>,
+}
+
+impl WalletEvent for TransactionInfo {
+    const NAME: &'static str = "transaction-sent";
+}
+
+impl WalletEvent for FeeEstimates {
+    const NAME: &'static str = "fee-estimates";
+}
+
+/// A transaction that still needs signatures from elsewhere: built by a
+/// watch-only wallet, or signed with our share of a multisig. Pass it back
+/// with `CombinePsbt` once the others have signed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnsignedPsbt {
+    /// Base64 encoded
+    pub psbt: String,
+    pub txid: String,
+    pub amount: u64,
+    pub outputs: Vec<OutputInfo>,
+    /// Absolute fee in sats
+    pub fee: u64,
+    /// Virtual size in vbytes once fully signed, assuming the largest
+    /// signatures for inputs that are still missing some
+    pub vsize: u64,
+    /// Like `TransactionInfo::fee_saved`
+    pub fee_saved: Option<u64>,
+    /// Signatures present on every input
+    pub signatures: u32,
+    /// Signatures needed to broadcast
+    pub required: u32,
+}
+
+impl WalletEvent for UnsignedPsbt {
+    const NAME: &'static str = "psbt-created";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigUpdated {
+    pub config: Config,
+}
+
+impl WalletEvent for ConfigUpdated {
+    const NAME: &'static str = "config-updated";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletErrorEvent {
+    /// Human readable fallback for `error`, next to its own fields
+    pub display: String,
+    #[serde(flatten)]
+    pub error: WalletError,
+}
+
+impl From<WalletError> for WalletErrorEvent {
+    fn from(error: WalletError) -> Self {
+        Self {
+            display: error.to_string(),
+            error,
+        }
+    }
+}
+
+impl WalletEvent for WalletErrorEvent {
+    const NAME: &'static str = "wallet-error";
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fmt;
+
+    use serde::de::{Deserializer, IgnoredAny, MapAccess, Visitor};
+
+    use super::*;
+
+    // Keys of a JSON object as they were written, duplicates included,
+    // which a serde_json::Value would silently merge
+    struct Keys(Vec<String>);
+
+    impl<'de> Deserialize<'de> for Keys {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct KeysVisitor;
+
+            impl<'de> Visitor<'de> for KeysVisitor {
+                type Value = Keys;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("an object")
+                }
+
+                fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Keys, A::Error> {
+                    let mut keys = Vec::new();
+                    while let Some(key) = map.next_key::<String>()? {
+                        map.next_value::<IgnoredAny>()?;
+                        keys.push(key);
+                    }
+                    Ok(Keys(keys))
+                }
+            }
+
+            deserializer.deserialize_map(KeysVisitor)
+        }
+    }
+
+    fn assert_unique_keys<T: Serialize>(data: T) {
+        let json = serde_json::to_string(&Event::new(Some(7), Some(1), data)).unwrap();
+        let Keys(keys) = serde_json::from_str(&json).unwrap();
+        let mut seen = HashSet::new();
+        for key in &keys {
+            assert!(seen.insert(key), "{} appears twice in {}", key, json);
+        }
+    }
+
+    #[test]
+    fn payloads_keep_clear_of_the_envelope() {
+        let fingerprint = "73c5da0a".to_string();
+        assert_unique_keys(WalletCreated {
+            new_wallet_id: 2,
+            network: Network::Signet,
+            fingerprint: fingerprint.clone(),
+            script_type: ScriptType::Bip84,
+        });
+        assert_unique_keys(AccountAdded {
+            new_wallet_id: 2,
+            parent_id: 1,
+            network: Network::Signet,
+            fingerprint: fingerprint.clone(),
+            script_type: ScriptType::Bip84,
+            account: 1,
+        });
+        assert_unique_keys(WalletRestored {
+            new_wallet_id: 2,
+            network: Network::Signet,
+            fingerprint: fingerprint.clone(),
+            script_type: ScriptType::Bip84,
+            balance: bdk_wallet::Balance::default().into(),
+        });
+        assert_unique_keys(MultisigCreated {
+            new_wallet_id: 2,
+            network: Network::Signet,
+            fingerprint: fingerprint.clone(),
+            threshold: 2,
+            signers: 3,
+            key: "[73c5da0a/48'/1'/0'/2']tpub".to_string(),
+        });
+        assert_unique_keys(WalletImported {
+            new_wallet_id: 2,
+            network: Network::Signet,
+            fingerprint: fingerprint.clone(),
+            script_type: ScriptType::Bip84,
+        });
+        assert_unique_keys(BackupExported {
+            watch_only: false,
+            encrypted: true,
+        });
+        assert_unique_keys(BackupImported {
+            new_wallet_id: 2,
+            network: Network::Signet,
+            fingerprint,
+            script_type: ScriptType::Bip84,
+            watch_only: false,
+            birthday: Birthday {
+                time: 1_700_000_000,
+                height: None,
+            },
+            labels: 0,
+            settings_restored: false,
+        });
+    }
+}
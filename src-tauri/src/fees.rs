@@ -0,0 +1,169 @@
+// Fee rates for sends.
+//
+// The presets follow esplora's fee estimates, which map a confirmation
+// target in blocks to a rate in sat/vB. The service caches them along with
+// the time they were fetched and asks again once they are older than
+// `MAX_AGE_SECS`; a custom rate needs no estimates at all.
+use std::collections::HashMap;
+
+use bdk_wallet::bitcoin::FeeRate;
+use serde::{Deserialize, Serialize};
+
+use crate::error::WalletError;
+
+/// Estimates older than this are fetched again before they are used.
+pub const MAX_AGE_SECS: u64 = 120;
+
+// Confirmation targets of the presets, in blocks
+const FAST_TARGET: u16 = 1;
+const NORMAL_TARGET: u16 = 6;
+const SLOW_TARGET: u16 = 144;
+// Nodes don't relay transactions paying less
+const MIN_RATE: f64 = 1.0;
+// A custom rate above this is taken for a typo
+const MAX_RATE: f64 = 10_000.0;
+
+/// Fee rate picked for a send.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeChoice {
+    /// Next block
+    Fast,
+    /// Within about an hour
+    #[default]
+    Normal,
+    /// Within about a day
+    Slow,
+    /// This many sat/vB
+    Custom(f64),
+}
+
+impl FeeChoice {
+    /// Whether the rate is read from fee estimates.
+    pub fn is_preset(&self) -> bool {
+        !matches!(self, Self::Custom(_))
+    }
+
+    /// The rate to build the transaction with. Presets need `estimates`.
+    pub fn rate(&self, estimates: Option<&FeeEstimates>) -> Result<FeeRate, WalletError> {
+        let sat_per_vb = match (self, estimates) {
+            (Self::Custom(rate), _) => {
+                if !(MIN_RATE..=MAX_RATE).contains(rate) {
+                    return Err(WalletError::InvalidArgument {
+                        field: "fee".to_string(),
+                        message: format!(
+                            "custom rate must be between {} and {} sat/vB",
+                            MIN_RATE, MAX_RATE
+                        ),
+                    });
+                }
+                *rate
+            }
+            (Self::Fast, Some(estimates)) => estimates.fast,
+            (Self::Normal, Some(estimates)) => estimates.normal,
+            (Self::Slow, Some(estimates)) => estimates.slow,
+            (_, None) => {
+                return Err(WalletError::FeeEstimatesUnavailable {
+                    message: "not fetched yet".to_string(),
+                })
+            }
+        };
+        // Per 1000 weight units for BDK, rounded up so the transaction never
+        // pays less than asked
+        Ok(FeeRate::from_sat_per_kwu((sat_per_vb * 250.0).ceil() as u64))
+    }
+}
+
+/// Preset rates in sat/vB, as fetched from esplora.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeeEstimates {
+    pub fast: f64,
+    pub normal: f64,
+    pub slow: f64,
+    /// Unix time in seconds
+    pub fetched_at: u64,
+}
+
+impl FeeEstimates {
+    /// Presets from esplora's estimates by confirmation target.
+    pub fn new(estimates: &HashMap<u16, f64>, fetched_at: u64) -> Self {
+        Self {
+            fast: rate_for(estimates, FAST_TARGET),
+            normal: rate_for(estimates, NORMAL_TARGET),
+            slow: rate_for(estimates, SLOW_TARGET),
+            fetched_at,
+        }
+    }
+
+    /// Whether the estimates can still be used at `now`.
+    pub fn is_fresh(&self, now: u64) -> bool {
+        now.saturating_sub(self.fetched_at) < MAX_AGE_SECS
+    }
+}
+
+// The estimate for the longest target within `target` blocks, or the
+// quickest one if there is none that short. A server without estimates has
+// an empty mempool, so the minimum is enough.
+fn rate_for(estimates: &HashMap<u16, f64>, target: u16) -> f64 {
+    let within = estimates
+        .iter()
+        .filter(|(blocks, _)| **blocks <= target)
+        .max_by_key(|(blocks, _)| **blocks);
+    let quickest = || estimates.iter().min_by_key(|(blocks, _)| **blocks);
+    within
+        .or_else(quickest)
+        .map_or(MIN_RATE, |(_, rate)| rate.max(MIN_RATE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_rates() {
+        let rate = |sat_per_vb| FeeChoice::Custom(sat_per_vb).rate(None);
+        assert_eq!(rate(1.0).unwrap(), FeeRate::from_sat_per_vb_unchecked(1));
+        assert_eq!(rate(2.5).unwrap(), FeeRate::from_sat_per_kwu(625));
+        // Rounded up, never below what was asked
+        assert_eq!(rate(1.001).unwrap(), FeeRate::from_sat_per_kwu(251));
+        assert!(rate(0.5).is_err());
+        assert!(rate(MAX_RATE + 1.0).is_err());
+        assert!(rate(f64::NAN).is_err());
+    }
+
+    #[test]
+    fn preset_rates() {
+        let estimates = FeeEstimates::new(&HashMap::from([(1, 20.0), (3, 10.0), (144, 1.5)]), 0);
+        assert_eq!(
+            (estimates.fast, estimates.normal, estimates.slow),
+            (20.0, 10.0, 1.5)
+        );
+        assert_eq!(
+            FeeChoice::Normal.rate(Some(&estimates)).unwrap(),
+            FeeRate::from_sat_per_vb_unchecked(10)
+        );
+        assert!(matches!(
+            FeeChoice::Fast.rate(None),
+            Err(WalletError::FeeEstimatesUnavailable { .. })
+        ));
+
+        // Nothing short enough falls back to the quickest, nothing at all
+        // to the minimum
+        let estimates = FeeEstimates::new(&HashMap::from([(25, 3.0), (1008, 0.5)]), 0);
+        assert_eq!((estimates.fast, estimates.slow), (3.0, 3.0));
+        let estimates = FeeEstimates::new(&HashMap::new(), 0);
+        assert_eq!(estimates.fast, MIN_RATE);
+        let estimates = FeeEstimates::new(&HashMap::from([(1, 0.2)]), 0);
+        assert_eq!(estimates.fast, MIN_RATE);
+    }
+
+    #[test]
+    fn freshness() {
+        let estimates = FeeEstimates::new(&HashMap::new(), 1_000);
+        assert!(estimates.is_fresh(1_000));
+        assert!(estimates.is_fresh(1_000 + MAX_AGE_SECS - 1));
+        assert!(!estimates.is_fresh(1_000 + MAX_AGE_SECS));
+        // A clock that went back doesn't underflow
+        assert!(estimates.is_fresh(0));
+    }
+}
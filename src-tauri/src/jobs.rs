@@ -0,0 +1,330 @@
+// Long-running wallet operations that run outside the service loop.
+//
+// Jobs only ever receive owned data (a scan request, a signed transaction)
+// and hand their result back as a `JobOutcome`. The service applies the
+// outcome to the wallet itself, so every wallet mutation still happens on
+// the service task, one at a time.
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use bdk_esplora::esplora_client::{self, AsyncClient};
+use bdk_esplora::EsploraAsyncExt;
+use bdk_wallet::{
+    bitcoin::{constants::genesis_block, key::Secp256k1, Amount, Network, Transaction},
+    chain::{
+        spk_client::{FullScanRequest, FullScanResponse},
+        BlockId, CheckPoint, SpkIterator,
+    },
+    descriptor::{ExtendedDescriptor, IntoWalletDescriptor},
+    KeychainKind, Wallet,
+};
+use tokio::time::{sleep, timeout, Duration};
+
+use crate::config::Config;
+use crate::error::WalletError;
+use crate::events::{EventEmitter, SyncAttempt, SyncProgress, SyncRetrying};
+use crate::keystore::Keystore;
+
+// Longest wait between two sync attempts, however many have failed
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Result of a finished job, applied to the wallet by the service.
+pub enum JobOutcome {
+    Synced {
+        // Wallet the scan was started for, so a stale result is never
+        // applied to a different wallet
+        db_path: PathBuf,
+        result: Result<FullScanResponse<KeychainKind>, WalletError>,
+    },
+    // One scan per restore candidate, in the order they were given
+    Recovered {
+        result: Result<Vec<FullScanResponse<KeychainKind>>, WalletError>,
+    },
+    Broadcast {
+        db_path: PathBuf,
+        tx: Transaction,
+        fee: Amount,
+        fee_saved: Option<Amount>,
+        result: Result<(), WalletError>,
+    },
+    FeeEstimates {
+        // Server they were asked from; they are only used while it is the
+        // configured one
+        esplora_url: String,
+        result: Result<HashMap<u16, f64>, WalletError>,
+    },
+}
+
+/// What a full scan covers. A `FullScanRequest` is consumed by the scan, so
+/// this keeps what is needed to build a fresh one for every attempt.
+pub struct ScanPlan {
+    chain_tip: CheckPoint,
+    keychains: Vec<(KeychainKind, ExtendedDescriptor)>,
+}
+
+impl ScanPlan {
+    pub fn new(wallet: &Wallet) -> Self {
+        Self {
+            chain_tip: wallet.latest_checkpoint(),
+            keychains: wallet
+                .keychains()
+                .map(|(keychain, descriptor)| (keychain, descriptor.clone()))
+                .collect(),
+        }
+    }
+
+    /// Plan for a wallet that doesn't exist yet, scanning from genesis.
+    pub fn for_keystore(keystore: &Keystore) -> Result<Self, WalletError> {
+        let secp = Secp256k1::new();
+        let network = keystore.network;
+        let public = |descriptor: &str| {
+            descriptor
+                .into_wallet_descriptor(&secp, network)
+                .map(|(descriptor, _)| descriptor)
+                .map_err(|e| WalletError::Keystore {
+                    message: e.to_string(),
+                })
+        };
+        Ok(Self {
+            chain_tip: genesis_checkpoint(network),
+            keychains: vec![
+                (KeychainKind::External, public(&keystore.external)?),
+                (KeychainKind::Internal, public(&keystore.internal)?),
+            ],
+        })
+    }
+
+    fn request(&self, events: &EventEmitter) -> FullScanRequest<KeychainKind> {
+        let events = events.clone();
+        let mut builder = FullScanRequest::builder()
+            .chain_tip(self.chain_tip.clone())
+            .inspect(move |keychain, index, _| {
+                events.emit(SyncProgress { keychain, index });
+            });
+        for (keychain, descriptor) in &self.keychains {
+            builder = builder.spks_for_keychain(*keychain, SpkIterator::new(descriptor.clone()));
+        }
+        builder.build()
+    }
+}
+
+// Tip of a freshly created wallet's chain
+fn genesis_checkpoint(network: Network) -> CheckPoint {
+    CheckPoint::new(BlockId {
+        height: 0,
+        hash: genesis_block(network).block_hash(),
+    })
+}
+
+/// Limits for a full scan, taken from the config when the sync starts.
+#[derive(Debug, Clone)]
+pub struct SyncSettings {
+    pub stop_gap: usize,
+    pub parallel_requests: usize,
+    pub retries: u32,
+    pub retry_delay: Duration,
+    pub timeout: Duration,
+}
+
+impl From<&Config> for SyncSettings {
+    fn from(config: &Config) -> Self {
+        Self {
+            stop_gap: config.stop_gap,
+            parallel_requests: config.parallel_requests,
+            retries: config.sync_retries,
+            retry_delay: Duration::from_millis(config.retry_delay_ms),
+            timeout: Duration::from_secs(config.sync_timeout_secs),
+        }
+    }
+}
+
+impl SyncSettings {
+    fn delay_after(&self, attempt: u32) -> Duration {
+        backoff(self.retry_delay, attempt, MAX_RETRY_DELAY)
+    }
+}
+
+/// Exponential backoff: `base` after the first failure, doubled after every
+/// further one, and never more than `max`.
+pub fn backoff(base: Duration, failures: u32, max: Duration) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    base.checked_mul(factor).map_or(max, |delay| delay.min(max))
+}
+
+/// Scan every keychain, retrying transient esplora errors with backoff until
+/// the retries or the overall timeout run out. Each attempt is reported
+/// through `events`.
+pub async fn full_scan(
+    client: AsyncClient,
+    db_path: PathBuf,
+    plan: ScanPlan,
+    settings: SyncSettings,
+    events: EventEmitter,
+) -> JobOutcome {
+    let result = match timeout(
+        settings.timeout,
+        scan_with_retries(&client, &plan, &settings, &events),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => Err(WalletError::SyncTimeout {
+            seconds: settings.timeout.as_secs(),
+        }),
+    };
+    JobOutcome::Synced { db_path, result }
+}
+
+/// Scan each restore candidate in turn, so the service can pick the one
+/// that has been used. Any failed scan fails the whole recovery.
+pub async fn recover(
+    client: AsyncClient,
+    plans: Vec<ScanPlan>,
+    settings: SyncSettings,
+    events: EventEmitter,
+) -> JobOutcome {
+    let scans = async {
+        let mut updates = Vec::with_capacity(plans.len());
+        for plan in &plans {
+            updates.push(scan_with_retries(&client, plan, &settings, &events).await?);
+        }
+        Ok(updates)
+    };
+    let result = match timeout(settings.timeout, scans).await {
+        Ok(result) => result,
+        Err(_) => Err(WalletError::SyncTimeout {
+            seconds: settings.timeout.as_secs(),
+        }),
+    };
+    JobOutcome::Recovered { result }
+}
+
+async fn scan_with_retries(
+    client: &AsyncClient,
+    plan: &ScanPlan,
+    settings: &SyncSettings,
+    events: &EventEmitter,
+) -> Result<FullScanResponse<KeychainKind>, WalletError> {
+    let max_attempts = settings.retries + 1;
+    let mut attempt = 1;
+    loop {
+        events.emit(SyncAttempt {
+            attempt,
+            max_attempts,
+        });
+        let error = match client
+            .full_scan(
+                plan.request(events),
+                settings.stop_gap,
+                settings.parallel_requests,
+            )
+            .await
+        {
+            Ok(update) => return Ok(update),
+            Err(e) => *e,
+        };
+
+        if attempt >= max_attempts || !is_transient(&error) {
+            return Err(error.into());
+        }
+
+        let delay = settings.delay_after(attempt);
+        println!(
+            "Sync attempt {} of {} failed, retrying in {:?}: {}",
+            attempt, max_attempts, delay, error
+        );
+        events.emit(SyncRetrying {
+            attempt,
+            max_attempts,
+            delay_ms: delay.as_millis() as u64,
+            message: error.to_string(),
+        });
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+// Errors worth another try: the connection failed or timed out, the server
+// is rate limiting us or is temporarily broken
+fn is_transient(error: &esplora_client::Error) -> bool {
+    match error {
+        esplora_client::Error::Reqwest(_) => true,
+        esplora_client::Error::HttpResponse { status, .. } => *status == 429 || *status >= 500,
+        _ => false,
+    }
+}
+
+pub async fn broadcast(
+    client: AsyncClient,
+    db_path: PathBuf,
+    tx: Transaction,
+    fee: Amount,
+    fee_saved: Option<Amount>,
+) -> JobOutcome {
+    let result = client.broadcast(&tx).await.map_err(WalletError::broadcast);
+    JobOutcome::Broadcast {
+        db_path,
+        tx,
+        fee,
+        fee_saved,
+        result,
+    }
+}
+
+/// Esplora's fee estimates, in sat/vB by confirmation target.
+pub async fn fee_estimates(client: AsyncClient, esplora_url: String) -> JobOutcome {
+    let result = client.get_fee_estimates().await.map_err(WalletError::from);
+    JobOutcome::FeeEstimates {
+        esplora_url,
+        result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn http(status: u16) -> esplora_client::Error {
+        esplora_client::Error::HttpResponse {
+            status,
+            message: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_errors() {
+        // Nothing listens on the discard port, so the connection fails
+        let unreachable = esplora_client::Builder::new("http://127.0.0.1:9")
+            .build_async()
+            .unwrap()
+            .get_height()
+            .await
+            .unwrap_err();
+        assert!(is_transient(&unreachable));
+
+        assert!(is_transient(&http(429)));
+        assert!(is_transient(&http(500)));
+        assert!(is_transient(&http(503)));
+        assert!(!is_transient(&http(400)));
+        assert!(!is_transient(&http(404)));
+        assert!(!is_transient(&esplora_client::Error::HeaderHeightNotFound(
+            7
+        )));
+    }
+
+    #[test]
+    fn retry_delays() {
+        let settings = SyncSettings {
+            stop_gap: 5,
+            parallel_requests: 5,
+            retries: 3,
+            retry_delay: Duration::from_millis(500),
+            timeout: Duration::from_secs(600),
+        };
+        assert_eq!(settings.delay_after(1), Duration::from_millis(500));
+        assert_eq!(settings.delay_after(2), Duration::from_secs(1));
+        assert_eq!(settings.delay_after(3), Duration::from_secs(2));
+        assert_eq!(settings.delay_after(10), MAX_RETRY_DELAY);
+        assert_eq!(settings.delay_after(u32::MAX), MAX_RETRY_DELAY);
+    }
+}
@@ -0,0 +1,828 @@
+// Secret key material for a wallet.
+//
+// The wallet database only ever holds public descriptors. The private
+// descriptors needed to sign, and the mnemonic they were derived from, live
+// in a keystore file next to the database (see `ConfigStore::keystore_path_for`),
+// encrypted under the user's password. Only the network, script type and
+// fingerprint are readable without it. Watch-only wallets get a keystore
+// with just that header, so they are found and loaded like any other.
+use std::path::Path;
+
+use bdk_wallet::{
+    bitcoin::{
+        bip32::{ChildNumber, DerivationPath, Fingerprint, Xpriv, Xpub},
+        key::Secp256k1,
+        Network,
+    },
+    descriptor::IntoWalletDescriptor,
+    keys::{
+        bip39::{self, Language, Mnemonic, WordCount},
+        GeneratableKey, GeneratedKey,
+    },
+    miniscript::{descriptor::DescriptorType, Segwitv0},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::Sealed;
+use crate::descriptors::WatchOnly;
+use crate::error::WalletError;
+use crate::storage;
+
+// Bumped whenever the file layout changes. Version 1 kept the secrets in
+// the clear.
+const KEYSTORE_VERSION: u32 = 2;
+
+/// Address type a wallet's descriptors are derived for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptType {
+    /// Legacy, `pkh` at m/44'
+    Bip44,
+    /// Nested segwit, `sh(wpkh)` at m/49'
+    Bip49,
+    /// Native segwit, `wpkh` at m/84'
+    #[default]
+    Bip84,
+    /// Taproot key path, `tr` at m/86'
+    Bip86,
+    /// Multisig, `wsh(sortedmulti)` with our key at m/48'/coin'/0'/2'
+    Bip48,
+}
+
+impl ScriptType {
+    /// The script type behind a single key descriptor, if it is one we
+    /// support.
+    pub fn for_descriptor(desc_type: DescriptorType) -> Option<Self> {
+        match desc_type {
+            DescriptorType::Pkh => Some(Self::Bip44),
+            DescriptorType::ShWpkh => Some(Self::Bip49),
+            DescriptorType::Wpkh => Some(Self::Bip84),
+            DescriptorType::Tr => Some(Self::Bip86),
+            DescriptorType::WshSortedMulti => Some(Self::Bip48),
+            _ => None,
+        }
+    }
+}
+
+/// Decrypted keystore, only held in memory while it is needed. Deliberately
+/// not `Debug` so the keys can't end up in a log line.
+#[derive(Clone)]
+pub struct Keystore {
+    pub network: Network,
+    pub script_type: ScriptType,
+    /// BIP39 words the descriptors were derived from. Missing for wallets
+    /// that predate mnemonics.
+    pub mnemonic: Option<String>,
+    /// Whether a BIP39 passphrase was used. The passphrase itself is never
+    /// stored; the user has to remember it to restore from the words.
+    pub has_passphrase: bool,
+    /// Fingerprint of the master key, e.g. `d34db33f`
+    pub fingerprint: String,
+    /// BIP44 account the descriptors are derived at, `0'` unless the wallet
+    /// was added as a further account of its seed
+    pub account: u32,
+    /// Private descriptors with checksums
+    pub external: String,
+    pub internal: String,
+}
+
+/// A keystore file as stored on disk.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeystoreFile {
+    pub version: u32,
+    pub network: Network,
+    #[serde(default)]
+    pub script_type: ScriptType,
+    pub has_passphrase: bool,
+    pub fingerprint: String,
+    #[serde(default)]
+    pub account: u32,
+    /// No private keys at all; sends produce unsigned PSBTs
+    #[serde(default)]
+    pub watch_only: bool,
+    /// Checksum of the public receive descriptor of a multisig wallet,
+    /// which tells apart multisigs of one seed with other cosigners
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    #[serde(flatten)]
+    secrets: Option<StoredSecrets>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredSecrets {
+    Encrypted { sealed: Sealed },
+    // Version 1 files, and wallets migrated from the shared test key.
+    // Encrypted the first time they are unlocked.
+    Plain(Secrets),
+}
+
+// The part of a keystore that is encrypted
+#[derive(Clone, Serialize, Deserialize)]
+struct Secrets {
+    mnemonic: Option<String>,
+    external: String,
+    internal: String,
+}
+
+/// Words handed to the frontend once, right after the wallet is created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveryPhrase {
+    pub words: Vec<String>,
+    pub fingerprint: String,
+}
+
+impl Keystore {
+    /// Generate a fresh mnemonic and derive `script_type` descriptors for
+    /// `network` at account `0'`.
+    pub fn generate(
+        word_count: usize,
+        passphrase: Option<&str>,
+        network: Network,
+        script_type: ScriptType,
+    ) -> Result<Self, WalletError> {
+        let mnemonic = generate_mnemonic(word_count)?;
+        Self::from_mnemonic(&mnemonic, passphrase, network, script_type, 0)
+    }
+
+    /// Derive descriptors of `script_type` for `network` from an existing
+    /// mnemonic, at `m/purpose'/coin'/account'`.
+    pub fn from_mnemonic(
+        mnemonic: &Mnemonic,
+        passphrase: Option<&str>,
+        network: Network,
+        script_type: ScriptType,
+        account: u32,
+    ) -> Result<Self, WalletError> {
+        // The descriptor the account key goes into
+        let template = match script_type {
+            ScriptType::Bip44 => "pkh(KEY)",
+            ScriptType::Bip49 => "sh(wpkh(KEY))",
+            ScriptType::Bip84 => "wpkh(KEY)",
+            ScriptType::Bip86 => "tr(KEY)",
+            ScriptType::Bip48 => {
+                return Err(WalletError::InvalidArgument {
+                    field: "script_type".to_string(),
+                    message: "multisig wallets are created with CreateMultisig".to_string(),
+                })
+            }
+        };
+        let secp = Secp256k1::new();
+        let passphrase = passphrase.filter(|p| !p.is_empty());
+        let seed = mnemonic.to_seed(passphrase.unwrap_or_default());
+        let key = derive_account(&seed, script_type, network, account)?;
+
+        // Same descriptors the BIP44/49/84/86 templates build for account 0'
+        let descriptor = |keychain: u32| {
+            let key = format!("{}{}/{}/*", key.origin(), key.xprv, keychain);
+            let (descriptor, keymap) = template
+                .replace("KEY", &key)
+                .as_str()
+                .into_wallet_descriptor(&secp, network)
+                .map_err(key_error)?;
+            Ok::<_, WalletError>(descriptor.to_string_with_secret(&keymap))
+        };
+
+        Ok(Self {
+            network,
+            script_type,
+            mnemonic: Some(mnemonic.to_string()),
+            has_passphrase: passphrase.is_some(),
+            fingerprint: key.fingerprint.to_string(),
+            account,
+            external: descriptor(0)?,
+            internal: descriptor(1)?,
+        })
+    }
+
+    /// A `threshold`-of-n multisig between our key, derived from `mnemonic`
+    /// at the BIP48 path, and the cosigners' account keys (see
+    /// `descriptors::parse_cosigner`). Also returns our account key with its
+    /// origin, for the cosigners to add on their side.
+    pub fn multisig(
+        mnemonic: &Mnemonic,
+        passphrase: Option<&str>,
+        network: Network,
+        threshold: usize,
+        cosigners: &[String],
+    ) -> Result<(Self, String), WalletError> {
+        let invalid = |field: &str, message: String| WalletError::InvalidArgument {
+            field: field.to_string(),
+            message,
+        };
+        let signers = cosigners.len() + 1;
+        if threshold == 0 || threshold > signers {
+            return Err(invalid(
+                "threshold",
+                format!("must be between 1 and {}, the number of signers", signers),
+            ));
+        }
+
+        let secp = Secp256k1::new();
+        let passphrase = passphrase.filter(|p| !p.is_empty());
+        let seed = mnemonic.to_seed(passphrase.unwrap_or_default());
+        let key = derive_account(&seed, ScriptType::Bip48, network, 0)?;
+        let origin = key.origin();
+        let shared = format!("{}{}", origin, Xpub::from_priv(&secp, &key.xprv));
+
+        // The same key twice would let one signer count for two
+        let mut keys = vec![shared.as_str()];
+        for cosigner in cosigners {
+            let xpub = |key: &str| key.rsplit(']').next().unwrap_or_default().to_string();
+            if keys.iter().any(|key| xpub(key) == xpub(cosigner)) {
+                return Err(invalid(
+                    "cosigners",
+                    format!("{} is listed more than once", cosigner),
+                ));
+            }
+            keys.push(cosigner);
+        }
+
+        // The same cosigners in any order make the same wallet, and should
+        // make the same descriptor
+        let mut cosigners = cosigners.to_vec();
+        cosigners.sort();
+        let descriptor = |keychain: u32| {
+            let keys = std::iter::once(format!("{}{}/{}/*", origin, key.xprv, keychain))
+                .chain(
+                    cosigners
+                        .iter()
+                        .map(|cosigner| format!("{}/{}/*", cosigner, keychain)),
+                )
+                .collect::<Vec<_>>();
+            let descriptor = format!("wsh(sortedmulti({},{}))", threshold, keys.join(","));
+            let (descriptor, keymap) = descriptor
+                .into_wallet_descriptor(&secp, network)
+                .map_err(|e| invalid("cosigners", e.to_string()))?;
+            Ok::<_, WalletError>(descriptor.to_string_with_secret(&keymap))
+        };
+
+        let keystore = Self {
+            network,
+            script_type: ScriptType::Bip48,
+            mnemonic: Some(mnemonic.to_string()),
+            has_passphrase: passphrase.is_some(),
+            fingerprint: key.fingerprint.to_string(),
+            account: 0,
+            external: descriptor(0)?,
+            internal: descriptor(1)?,
+        };
+        Ok((keystore, shared))
+    }
+
+    /// Keystore for wallets created before mnemonics, from their private
+    /// descriptors alone.
+    pub fn from_descriptors(
+        external: &str,
+        internal: &str,
+        network: Network,
+    ) -> Result<Self, WalletError> {
+        let secp = Secp256k1::new();
+        let (descriptor, keymap) = external
+            .into_wallet_descriptor(&secp, network)
+            .map_err(key_error)?;
+        let fingerprint = keymap
+            .keys()
+            .next()
+            .map(|key| key.master_fingerprint().to_string())
+            .unwrap_or_default();
+        // Round-trip both so they are stored with checksums
+        let external = descriptor.to_string_with_secret(&keymap);
+        let (descriptor, keymap) = internal
+            .into_wallet_descriptor(&secp, network)
+            .map_err(key_error)?;
+        let internal = descriptor.to_string_with_secret(&keymap);
+
+        Ok(Self {
+            network,
+            script_type: ScriptType::Bip84,
+            mnemonic: None,
+            has_passphrase: false,
+            fingerprint,
+            account: 0,
+            external,
+            internal,
+        })
+    }
+
+    /// The words to show the user for backup.
+    pub fn recovery_phrase(&self) -> Option<RecoveryPhrase> {
+        self.mnemonic.as_ref().map(|mnemonic| RecoveryPhrase {
+            words: mnemonic.split_whitespace().map(str::to_string).collect(),
+            fingerprint: self.fingerprint.clone(),
+        })
+    }
+
+    /// Encrypt the keystore under `password`, ready to be saved.
+    pub fn seal(&self, password: &str) -> Result<KeystoreFile, WalletError> {
+        let mut file = KeystoreFile::unencrypted(self);
+        let secrets = serde_json::to_vec(&self.secrets()).map_err(key_error)?;
+        file.secrets = Some(StoredSecrets::Encrypted {
+            sealed: Sealed::seal(password, &secrets, &file.associated_data())?,
+        });
+        Ok(file)
+    }
+
+    fn secrets(&self) -> Secrets {
+        Secrets {
+            mnemonic: self.mnemonic.clone(),
+            external: self.external.clone(),
+            internal: self.internal.clone(),
+        }
+    }
+}
+
+impl KeystoreFile {
+    /// A file holding the secrets in the clear. Only used for the shared
+    /// test key, which is public anyway; it is encrypted on first unlock.
+    pub fn unencrypted(keystore: &Keystore) -> Self {
+        Self {
+            version: KEYSTORE_VERSION,
+            network: keystore.network,
+            script_type: keystore.script_type,
+            has_passphrase: keystore.has_passphrase,
+            fingerprint: keystore.fingerprint.clone(),
+            account: keystore.account,
+            watch_only: false,
+            checksum: multisig_checksum(keystore),
+            secrets: Some(StoredSecrets::Plain(keystore.secrets())),
+        }
+    }
+
+    /// Header for a watch-only wallet, whose descriptors live in its
+    /// database like everyone else's.
+    pub fn watch_only(imported: &WatchOnly) -> Self {
+        Self {
+            version: KEYSTORE_VERSION,
+            network: imported.network,
+            script_type: imported.script_type,
+            has_passphrase: false,
+            fingerprint: imported.fingerprint.clone(),
+            account: imported.account,
+            watch_only: true,
+            checksum: (imported.script_type == ScriptType::Bip48)
+                .then(|| imported.external.rsplit_once('#'))
+                .flatten()
+                .map(|(_, checksum)| checksum.to_string()),
+            secrets: None,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self.secrets, Some(StoredSecrets::Encrypted { .. }))
+    }
+
+    /// Decrypt the keystore. An unencrypted file opens with any password.
+    pub fn open(&self, password: &str) -> Result<Keystore, WalletError> {
+        let secrets = match &self.secrets {
+            Some(StoredSecrets::Encrypted { sealed }) => {
+                let plaintext = sealed.open(password, &self.associated_data())?;
+                serde_json::from_slice(&plaintext).map_err(key_error)?
+            }
+            Some(StoredSecrets::Plain(secrets)) => secrets.clone(),
+            None => return Err(WalletError::WatchOnly),
+        };
+        Ok(Keystore {
+            network: self.network,
+            script_type: self.script_type,
+            mnemonic: secrets.mnemonic,
+            has_passphrase: self.has_passphrase,
+            fingerprint: self.fingerprint.clone(),
+            account: self.account,
+            external: secrets.external,
+            internal: secrets.internal,
+        })
+    }
+
+    // Ties the ciphertext to the header, so a keystore's secrets can't be
+    // passed off under another network, fingerprint or account. Account 0'
+    // is left out, as it was before there were further accounts.
+    fn associated_data(&self) -> Vec<u8> {
+        let mut data = format!("slaps-keystore:{}:{}", self.network, self.fingerprint);
+        if self.account != 0 {
+            data.push_str(&format!(":{}", self.account));
+        }
+        data.into_bytes()
+    }
+
+    /// Read the keystore at `path`, or `None` if there isn't one.
+    pub fn load(path: &Path) -> Result<Option<Self>, WalletError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_error(path, e)),
+        };
+        let keystore: Self =
+            serde_json::from_str(&contents).map_err(|e| WalletError::Keystore {
+                message: format!("Failed to parse {}: {}", path.display(), e),
+            })?;
+        // A damaged key section must not turn a wallet into a watch-only one
+        if keystore.secrets.is_none() && !keystore.watch_only {
+            return Err(WalletError::Keystore {
+                message: format!("{} does not contain any keys", path.display()),
+            });
+        }
+        Ok(Some(keystore))
+    }
+
+    /// Write the keystore to `path`, readable by the current user only.
+    pub fn save(&self, path: &Path) -> Result<(), WalletError> {
+        let contents = serde_json::to_string_pretty(self).map_err(|e| WalletError::Keystore {
+            message: format!("Failed to serialize keystore: {}", e),
+        })?;
+        storage::create_parent_dir(path)?;
+
+        // Write to a temporary file first so a crash never leaves a
+        // half-written keystore behind
+        let tmp = path.with_extension("tmp");
+        write_private(&tmp, contents.as_bytes()).map_err(|e| io_error(&tmp, e))?;
+        std::fs::rename(&tmp, path).map_err(|e| io_error(path, e))
+    }
+}
+
+/// A fresh English mnemonic of `word_count` words.
+pub fn generate_mnemonic(word_count: usize) -> Result<Mnemonic, WalletError> {
+    let word_count = match word_count {
+        12 => WordCount::Words12,
+        24 => WordCount::Words24,
+        _ => {
+            return Err(WalletError::InvalidArgument {
+                field: "word_count".to_string(),
+                message: "must be 12 or 24".to_string(),
+            })
+        }
+    };
+    let generated: GeneratedKey<Mnemonic, Segwitv0> =
+        Mnemonic::generate((word_count, Language::English)).map_err(|e| WalletError::Keystore {
+            message: format!("Failed to generate mnemonic: {:?}", e),
+        })?;
+    Ok(generated.into_key())
+}
+
+/// Parse words typed in by the user, checking them against the English
+/// wordlist and the BIP39 checksum.
+pub fn parse_mnemonic(words: &str) -> Result<Mnemonic, WalletError> {
+    let normalized = words
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ");
+    Mnemonic::parse_in(Language::English, &normalized).map_err(|e| {
+        let message = match e {
+            bip39::Error::BadWordCount(count) => {
+                format!("expected 12, 15, 18, 21 or 24 words, got {}", count)
+            }
+            bip39::Error::UnknownWord(index) => format!(
+                "word {} ({:?}) is not in the BIP39 wordlist",
+                index + 1,
+                normalized.split(' ').nth(index).unwrap_or_default()
+            ),
+            bip39::Error::InvalidChecksum => {
+                "checksum does not match, check the words and their order".to_string()
+            }
+            other => other.to_string(),
+        };
+        WalletError::InvalidMnemonic { message }
+    })
+}
+
+// An account's private key, with the master fingerprint and path that make
+// up its origin
+struct AccountKey {
+    fingerprint: Fingerprint,
+    path: DerivationPath,
+    xprv: Xpriv,
+}
+
+impl AccountKey {
+    // `[fingerprint/path]`, as descriptors write it
+    fn origin(&self) -> String {
+        format!("[{}/{}]", self.fingerprint, self.path)
+    }
+}
+
+// Derive the key of `account` from `seed`: at `m/purpose'/coin'/account'`,
+// or `m/48'/coin'/account'/2'` for native segwit multisig
+fn derive_account(
+    seed: &[u8],
+    script_type: ScriptType,
+    network: Network,
+    account: u32,
+) -> Result<AccountKey, WalletError> {
+    let purpose = match script_type {
+        ScriptType::Bip44 => 44,
+        ScriptType::Bip49 => 49,
+        ScriptType::Bip84 => 84,
+        ScriptType::Bip86 => 86,
+        ScriptType::Bip48 => 48,
+    };
+    let coin = if network == Network::Bitcoin { 0 } else { 1 };
+    let account =
+        ChildNumber::from_hardened_idx(account).map_err(|_| WalletError::InvalidArgument {
+            field: "account".to_string(),
+            message: "must be below 2^31".to_string(),
+        })?;
+    let mut path = vec![
+        ChildNumber::from_hardened_idx(purpose).map_err(key_error)?,
+        ChildNumber::from_hardened_idx(coin).map_err(key_error)?,
+        account,
+    ];
+    if script_type == ScriptType::Bip48 {
+        path.push(ChildNumber::from_hardened_idx(2).map_err(key_error)?);
+    }
+    let path = DerivationPath::from(path);
+
+    let secp = Secp256k1::new();
+    let master = Xpriv::new_master(network, seed).map_err(key_error)?;
+    let xprv = master.derive_priv(&secp, &path).map_err(key_error)?;
+    Ok(AccountKey {
+        fingerprint: master.fingerprint(&secp),
+        path,
+        xprv,
+    })
+}
+
+// Checksum of the public receive descriptor, for multisig wallets only. The
+// private one's would say something about the key.
+fn multisig_checksum(keystore: &Keystore) -> Option<String> {
+    if keystore.script_type != ScriptType::Bip48 {
+        return None;
+    }
+    let (descriptor, _) = keystore
+        .external
+        .as_str()
+        .into_wallet_descriptor(&Secp256k1::new(), keystore.network)
+        .ok()?;
+    let public = descriptor.to_string();
+    public
+        .rsplit_once('#')
+        .map(|(_, checksum)| checksum.to_string())
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, contents)
+}
+
+fn key_error(error: impl std::fmt::Display) -> WalletError {
+    WalletError::Keystore {
+        message: error.to_string(),
+    }
+}
+
+fn io_error(path: &Path, error: std::io::Error) -> WalletError {
+    WalletError::Keystore {
+        message: format!("{}: {}", path.display(), error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bdk_wallet::{KeychainKind, Wallet};
+
+    // BIP84 test vector
+    const WORDS: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    fn mnemonic() -> Mnemonic {
+        Mnemonic::parse(WORDS).unwrap()
+    }
+
+    fn first_address(keystore: &Keystore) -> String {
+        let wallet = Wallet::create(keystore.external.clone(), keystore.internal.clone())
+            .network(keystore.network)
+            .create_wallet_no_persist()
+            .unwrap();
+        wallet
+            .peek_address(KeychainKind::External, 0)
+            .address
+            .to_string()
+    }
+
+    #[test]
+    fn derives_bip84() {
+        let keystore =
+            Keystore::from_mnemonic(&mnemonic(), None, Network::Bitcoin, ScriptType::Bip84, 0)
+                .unwrap();
+        assert_eq!(keystore.fingerprint, "73c5da0a");
+        assert!(keystore
+            .external
+            .starts_with("wpkh([73c5da0a/84'/0'/0']xprv"));
+        assert!(keystore.external.contains("/0/*)#"));
+        assert!(keystore.internal.contains("/1/*)#"));
+        assert_eq!(
+            first_address(&keystore),
+            "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"
+        );
+
+        // Test networks use coin type 1
+        let keystore =
+            Keystore::from_mnemonic(&mnemonic(), None, Network::Signet, ScriptType::Bip84, 0)
+                .unwrap();
+        assert!(keystore
+            .external
+            .starts_with("wpkh([73c5da0a/84'/1'/0']tprv"));
+    }
+
+    // First receive address of each BIP's test vector
+    #[test]
+    fn derives_every_script_type() {
+        let expected = [
+            (
+                ScriptType::Bip44,
+                "pkh([73c5da0a/44'/0'/0']xprv",
+                "1LqBGSKuX5yYUonjxT5qGfpUsXKYYWeabA",
+            ),
+            (
+                ScriptType::Bip49,
+                "sh(wpkh([73c5da0a/49'/0'/0']xprv",
+                "37VucYSaXLCAsxYyAPfbSi9eh4iEcbShgf",
+            ),
+            (
+                ScriptType::Bip84,
+                "wpkh([73c5da0a/84'/0'/0']xprv",
+                "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu",
+            ),
+            (
+                ScriptType::Bip86,
+                "tr([73c5da0a/86'/0'/0']xprv",
+                "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr",
+            ),
+        ];
+        for (script_type, prefix, address) in expected {
+            let keystore =
+                Keystore::from_mnemonic(&mnemonic(), None, Network::Bitcoin, script_type, 0)
+                    .unwrap();
+            assert_eq!(keystore.script_type, script_type);
+            assert!(
+                keystore.external.starts_with(prefix),
+                "{}",
+                keystore.external
+            );
+            assert_eq!(first_address(&keystore), address);
+        }
+    }
+
+    #[test]
+    fn derives_further_accounts() {
+        let keystore =
+            Keystore::from_mnemonic(&mnemonic(), None, Network::Bitcoin, ScriptType::Bip84, 1)
+                .unwrap();
+        assert_eq!(keystore.account, 1);
+        assert_eq!(keystore.fingerprint, "73c5da0a");
+        assert!(keystore
+            .external
+            .starts_with("wpkh([73c5da0a/84'/0'/1']xprv"));
+        assert_ne!(
+            first_address(&keystore),
+            "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"
+        );
+        assert!(matches!(
+            Keystore::from_mnemonic(&mnemonic(), None, Network::Bitcoin, ScriptType::Bip84, 1 << 31),
+            Err(WalletError::InvalidArgument { field, .. }) if field == "account"
+        ));
+        assert!(matches!(
+            Keystore::from_mnemonic(&mnemonic(), None, Network::Bitcoin, ScriptType::Bip48, 0),
+            Err(WalletError::InvalidArgument { field, .. }) if field == "script_type"
+        ));
+    }
+
+    #[test]
+    fn multisig_key_at_bip48_path() {
+        let (keystore, shared) =
+            Keystore::multisig(&mnemonic(), None, Network::Testnet, 1, &[]).unwrap();
+        assert_eq!(keystore.script_type, ScriptType::Bip48);
+        assert!(
+            shared.starts_with("[73c5da0a/48'/1'/0'/2']tpub"),
+            "{}",
+            shared
+        );
+        assert!(
+            keystore
+                .external
+                .starts_with("wsh(sortedmulti(1,[73c5da0a/48'/1'/0'/2']tprv"),
+            "{}",
+            keystore.external
+        );
+    }
+
+    #[test]
+    fn passphrase() {
+        let keystore = Keystore::from_mnemonic(
+            &mnemonic(),
+            Some("TREZOR"),
+            Network::Bitcoin,
+            ScriptType::Bip84,
+            0,
+        )
+        .unwrap();
+        assert!(keystore.has_passphrase);
+        assert_ne!(keystore.fingerprint, "73c5da0a");
+        // An empty passphrase is no passphrase
+        let keystore = Keystore::from_mnemonic(
+            &mnemonic(),
+            Some(""),
+            Network::Bitcoin,
+            ScriptType::Bip84,
+            0,
+        )
+        .unwrap();
+        assert!(!keystore.has_passphrase);
+        assert_eq!(keystore.fingerprint, "73c5da0a");
+    }
+
+    #[test]
+    fn generate() {
+        let keystore = Keystore::generate(24, None, Network::Signet, ScriptType::Bip86).unwrap();
+        assert_eq!(keystore.script_type, ScriptType::Bip86);
+        let phrase = keystore.recovery_phrase().unwrap();
+        assert_eq!(phrase.words.len(), 24);
+        assert_eq!(phrase.fingerprint, keystore.fingerprint);
+        assert_eq!(
+            Keystore::generate(12, None, Network::Signet, ScriptType::Bip84)
+                .unwrap()
+                .recovery_phrase()
+                .unwrap()
+                .words
+                .len(),
+            12
+        );
+        assert!(matches!(
+            Keystore::generate(13, None, Network::Signet, ScriptType::Bip84),
+            Err(WalletError::InvalidArgument { field, .. }) if field == "word_count"
+        ));
+    }
+
+    #[test]
+    fn parses_typed_words() {
+        let typed = format!("  {}\n", WORDS.to_uppercase());
+        assert_eq!(parse_mnemonic(&typed).unwrap(), mnemonic());
+        assert!(matches!(
+            parse_mnemonic(&WORDS.replace("about", "abandon")),
+            Err(WalletError::InvalidMnemonic { .. })
+        ));
+        assert!(matches!(
+            parse_mnemonic("abandon abandon abandon"),
+            Err(WalletError::InvalidMnemonic { .. })
+        ));
+    }
+
+    #[test]
+    fn seal_save_and_open() {
+        let dir = std::env::temp_dir().join(format!("slaps-keystore-{}", std::process::id()));
+        let path = dir.join("keystore.json");
+        assert!(KeystoreFile::load(&path).unwrap().is_none());
+
+        let keystore =
+            Keystore::from_mnemonic(&mnemonic(), None, Network::Signet, ScriptType::Bip84, 0)
+                .unwrap();
+        keystore.seal("correct horse").unwrap().save(&path).unwrap();
+        let file = KeystoreFile::load(&path).unwrap().unwrap();
+        assert!(file.is_encrypted());
+        assert_eq!(file.fingerprint, "73c5da0a");
+        // Nothing secret is readable without the password
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("abandon") && !contents.contains("tprv"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let opened = file.open("correct horse").unwrap();
+        assert_eq!(opened.external, keystore.external);
+        assert_eq!(opened.mnemonic.as_deref(), Some(WORDS));
+        assert!(matches!(
+            file.open("battery staple"),
+            Err(WalletError::WrongPassword)
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unencrypted_files_open_with_any_password() {
+        let keystore =
+            Keystore::from_mnemonic(&mnemonic(), None, Network::Signet, ScriptType::Bip84, 0)
+                .unwrap();
+        let file = KeystoreFile::unencrypted(&keystore);
+        assert!(!file.is_encrypted());
+        assert_eq!(file.open("anything").unwrap().internal, keystore.internal);
+    }
+}
@@ -0,0 +1,144 @@
+// Labels the user attached to a wallet's transactions, addresses and
+// outputs, in the BIP329 format so they can be moved to and from other
+// wallets.
+//
+// They are kept as JSON Lines next to the wallet database (see
+// `ConfigStore::labels_path_for`) and travel with the wallet in backups.
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::WalletError;
+use crate::storage;
+
+/// What a label is attached to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelType {
+    Tx,
+    Addr,
+    Pubkey,
+    Input,
+    Output,
+    Xpub,
+}
+
+/// A single BIP329 record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Label {
+    #[serde(rename = "type")]
+    pub label_type: LabelType,
+    /// Txid, address, outpoint or key the label is for
+    #[serde(rename = "ref")]
+    pub reference: String,
+    pub label: String,
+    /// Only used for outputs: whether they may be spent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spendable: Option<bool>,
+}
+
+/// Read the labels at `path`; a wallet that has none has no file.
+pub fn load(path: &Path) -> Result<Vec<Label>, WalletError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(WalletError::Database {
+                message: format!("{}: {}", path.display(), e),
+            })
+        }
+    };
+    parse(&contents).map_err(|message| WalletError::Database {
+        message: format!("Failed to parse {}: {}", path.display(), message),
+    })
+}
+
+/// Put `label` in place of the one for the same record, or add it. One
+/// without text removes the record's label instead.
+pub fn set(labels: &mut Vec<Label>, label: Label) {
+    labels.retain(|existing| {
+        existing.label_type != label.label_type || existing.reference != label.reference
+    });
+    if !label.label.trim().is_empty() {
+        labels.push(label);
+    }
+}
+
+/// Replace the labels at `path`.
+pub fn save(path: &Path, labels: &[Label]) -> Result<(), WalletError> {
+    let mut contents = String::new();
+    for label in labels {
+        let line = serde_json::to_string(label).map_err(|e| WalletError::Database {
+            message: format!("Failed to serialize label: {}", e),
+        })?;
+        contents.push_str(&line);
+        contents.push('\n');
+    }
+    storage::replace_file(path, contents.as_bytes())
+}
+
+// One label per non-empty line
+fn parse(contents: &str) -> Result<Vec<Label>, String> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|e| format!("line {}: {}", index + 1, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(label_type: LabelType, reference: &str, label: &str) -> Label {
+        Label {
+            label_type,
+            reference: reference.to_string(),
+            label: label.to_string(),
+            spendable: None,
+        }
+    }
+
+    #[test]
+    fn set_save_and_load() {
+        let txid = "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd";
+        let mut labels = Vec::new();
+        set(&mut labels, label(LabelType::Tx, txid, "Rent"));
+        set(
+            &mut labels,
+            label(LabelType::Addr, txid, "Not the same record"),
+        );
+        set(&mut labels, label(LabelType::Tx, txid, "Rent for May"));
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels[1], label(LabelType::Tx, txid, "Rent for May"));
+
+        let dir = std::env::temp_dir().join(format!("slaps-labels-{}", std::process::id()));
+        let path = dir.join("labels.jsonl");
+        assert!(load(&path).unwrap().is_empty());
+        save(&path, &labels).unwrap();
+        assert_eq!(load(&path).unwrap(), labels);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            contents.starts_with(r#"{"type":"addr","ref":"#),
+            "{}",
+            contents
+        );
+
+        set(&mut labels, label(LabelType::Tx, txid, " "));
+        assert_eq!(
+            labels,
+            [label(LabelType::Addr, txid, "Not the same record")]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_errors_name_the_line() {
+        let contents = "{\"type\":\"tx\",\"ref\":\"ab\",\"label\":\"ok\"}\n\nnot json\n";
+        let message = parse(contents).unwrap_err();
+        assert!(message.starts_with("line 3:"), "{}", message);
+    }
+}
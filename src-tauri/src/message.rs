@@ -0,0 +1,380 @@
+// Signed messages that prove control of an address.
+//
+// Segwit v0 (`wpkh`) and Taproot (`tr`) addresses use BIP322 "simple"
+// signatures: the witness of a virtual transaction spending a virtual output
+// locked to the address, committing to the message. Legacy `pkh` addresses
+// use the older signmessage format every wallet understands. The signing
+// side goes through the wallet's own signers, so it only works while the
+// wallet is unlocked.
+use bdk_wallet::{
+    bitcoin::{
+        absolute::LockTime,
+        base64::{engine::general_purpose::STANDARD as BASE64, Engine},
+        bip32::ChildNumber,
+        blockdata::opcodes::all::{OP_PUSHBYTES_0, OP_RETURN},
+        consensus, ecdsa,
+        hashes::{sha256, Hash, HashEngine},
+        key::Secp256k1,
+        secp256k1::{Message, XOnlyPublicKey},
+        sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType},
+        sign_message::{signed_msg_hash, MessageSignature},
+        taproot,
+        transaction::Version,
+        Address, AddressType, Amount, OutPoint, PrivateKey, Psbt, PublicKey, Script, ScriptBuf,
+        Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    },
+    chain::ChainPosition,
+    miniscript::descriptor::{DescriptorSecretKey, Wildcard},
+    KeychainKind, LocalOutput, SignOptions, Wallet,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::error::WalletError;
+
+// Tag of the BIP322 message hash
+const MESSAGE_TAG: &[u8] = b"BIP0322-signed-message";
+
+/// How a message signature is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageFormat {
+    /// BIP322 simple: the base64 encoded witness stack
+    Bip322Simple,
+    /// Base64 encoded recoverable signature, as Bitcoin Core's signmessage
+    Legacy,
+}
+
+impl MessageFormat {
+    /// The format used for `address`, if it is one we can sign for.
+    pub fn for_address(address: &Address) -> Result<Self, WalletError> {
+        match address.address_type() {
+            Some(AddressType::P2wpkh) | Some(AddressType::P2tr) => Ok(Self::Bip322Simple),
+            Some(AddressType::P2pkh) => Ok(Self::Legacy),
+            _ => Err(invalid(
+                "address",
+                "signing messages works for native segwit, taproot and legacy addresses",
+            )),
+        }
+    }
+}
+
+/// Sign `message` with the key behind `address`, which must belong to
+/// `wallet`.
+pub fn sign(
+    wallet: &Wallet,
+    address: &Address,
+    message: &str,
+) -> Result<(String, MessageFormat), WalletError> {
+    let format = MessageFormat::for_address(address)?;
+    let script_pubkey = address.script_pubkey();
+    let (keychain, index) = wallet
+        .derivation_of_spk(script_pubkey.clone())
+        .ok_or_else(|| invalid("address", "is not an address of this wallet"))?;
+
+    let signature = match format {
+        MessageFormat::Bip322Simple => {
+            let to_spend = to_spend(&script_pubkey, message);
+            let mut psbt = Psbt::from_unsigned_tx(to_sign(&to_spend, Witness::new()))
+                .map_err(|e| signing_error(e.to_string()))?;
+            let utxo = LocalOutput {
+                outpoint: OutPoint::new(to_spend.compute_txid(), 0),
+                txout: to_spend.output[0].clone(),
+                keychain,
+                is_spent: false,
+                derivation_index: index,
+                chain_position: ChainPosition::Unconfirmed { last_seen: None },
+            };
+            psbt.inputs[0] = wallet.get_psbt_input(utxo, None, true)?;
+            psbt.inputs[0].witness_utxo = Some(to_spend.output[0].clone());
+
+            // The virtual output has no transaction to check it against
+            let options = SignOptions {
+                trust_witness_utxo: true,
+                ..Default::default()
+            };
+            if !wallet.sign(&mut psbt, options)? {
+                return Err(signing_error("no key for this address"));
+            }
+            let witness = psbt.inputs[0]
+                .final_script_witness
+                .take()
+                .ok_or_else(|| signing_error("no witness was produced"))?;
+            BASE64.encode(consensus::serialize(&witness))
+        }
+        MessageFormat::Legacy => {
+            let secp = Secp256k1::new();
+            let key = private_key(wallet, keychain, index, address)?;
+            let digest = Message::from_digest(signed_msg_hash(message).to_byte_array());
+            let signature = secp.sign_ecdsa_recoverable(&digest, &key.inner);
+            MessageSignature::new(signature, key.compressed).to_base64()
+        }
+    };
+    Ok((signature, format))
+}
+
+/// Check a signature over `message` made with the key behind `address`.
+/// Malformed signatures are an error, wrong ones are just not valid.
+pub fn verify(
+    address: &Address,
+    message: &str,
+    signature: &str,
+) -> Result<(bool, MessageFormat), WalletError> {
+    let format = MessageFormat::for_address(address)?;
+    let valid = match format {
+        MessageFormat::Legacy => {
+            let signature = MessageSignature::from_base64(signature.trim())
+                .map_err(|e| invalid("signature", e.to_string()))?;
+            signature
+                .is_signed_by_address(&Secp256k1::new(), address, signed_msg_hash(message))
+                .unwrap_or(false)
+        }
+        MessageFormat::Bip322Simple => {
+            let bytes = BASE64
+                .decode(signature.trim())
+                .map_err(|e| invalid("signature", e.to_string()))?;
+            let witness: Witness =
+                consensus::deserialize(&bytes).map_err(|e| invalid("signature", e.to_string()))?;
+            verify_simple(&address.script_pubkey(), message, witness)
+        }
+    };
+    Ok((valid, format))
+}
+
+// Check a BIP322 simple signature for a `wpkh` or key path `tr` output
+fn verify_simple(script_pubkey: &Script, message: &str, witness: Witness) -> bool {
+    let secp = Secp256k1::verification_only();
+    let to_spend = to_spend(script_pubkey, message);
+    let to_sign = to_sign(&to_spend, witness.clone());
+    let mut cache = SighashCache::new(&to_sign);
+
+    if script_pubkey.is_p2wpkh() {
+        let (Some(signature), Some(key), 2) = (witness.nth(0), witness.nth(1), witness.len())
+        else {
+            return false;
+        };
+        let (Ok(signature), Ok(key)) = (
+            ecdsa::Signature::from_slice(signature),
+            PublicKey::from_slice(key),
+        ) else {
+            return false;
+        };
+        let matches = key
+            .wpubkey_hash()
+            .is_ok_and(|hash| ScriptBuf::new_p2wpkh(&hash) == *script_pubkey);
+        if !matches || signature.sighash_type != EcdsaSighashType::All {
+            return false;
+        }
+        cache
+            .p2wpkh_signature_hash(0, script_pubkey, Amount::ZERO, EcdsaSighashType::All)
+            .is_ok_and(|sighash| {
+                secp.verify_ecdsa(&Message::from(sighash), &signature.signature, &key.inner)
+                    .is_ok()
+            })
+    } else if script_pubkey.is_p2tr() {
+        let (Some(signature), 1) = (witness.nth(0), witness.len()) else {
+            return false;
+        };
+        let (Ok(signature), Ok(key)) = (
+            taproot::Signature::from_slice(signature),
+            XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..]),
+        ) else {
+            return false;
+        };
+        if !matches!(
+            signature.sighash_type,
+            TapSighashType::Default | TapSighashType::All
+        ) {
+            return false;
+        }
+        let prevouts = [&to_spend.output[0]];
+        cache
+            .taproot_key_spend_signature_hash(0, &Prevouts::All(&prevouts), signature.sighash_type)
+            .is_ok_and(|sighash| {
+                secp.verify_schnorr(&signature.signature, &Message::from(sighash), &key)
+                    .is_ok()
+            })
+    } else {
+        false
+    }
+}
+
+// Tagged hash of the message, committed to by the virtual output
+fn message_hash(message: &str) -> [u8; 32] {
+    let tag = sha256::Hash::hash(MESSAGE_TAG);
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    engine.input(message.as_bytes());
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+// The virtual transaction creating an output locked to `script_pubkey`
+fn to_spend(script_pubkey: &Script, message: &str) -> Transaction {
+    let script_sig = ScriptBuf::builder()
+        .push_opcode(OP_PUSHBYTES_0)
+        .push_slice(message_hash(message))
+        .into_script();
+    Transaction {
+        version: Version(0),
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(Txid::all_zeros(), 0xFFFF_FFFF),
+            script_sig,
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: script_pubkey.to_owned(),
+        }],
+    }
+}
+
+// The virtual transaction spending it, whose witness is the signature
+fn to_sign(to_spend: &Transaction, witness: Witness) -> Transaction {
+    Transaction {
+        version: Version(0),
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(to_spend.compute_txid(), 0),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ZERO,
+            witness,
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::builder().push_opcode(OP_RETURN).into_script(),
+        }],
+    }
+}
+
+// The private key for `address` at `index` on `keychain`, from the signers
+// of an unlocked wallet
+fn private_key(
+    wallet: &Wallet,
+    keychain: KeychainKind,
+    index: u32,
+    address: &Address,
+) -> Result<PrivateKey, WalletError> {
+    let secp = Secp256k1::new();
+    let child = ChildNumber::from_normal_idx(index).map_err(|e| signing_error(e.to_string()))?;
+    wallet
+        .get_signers(keychain)
+        .as_key_map(&secp)
+        .into_values()
+        .filter_map(|secret| match secret {
+            DescriptorSecretKey::Single(single) => Some(single.key),
+            DescriptorSecretKey::XPrv(xprv) => {
+                let path = match xprv.wildcard {
+                    Wildcard::None => xprv.derivation_path.clone(),
+                    Wildcard::Unhardened => xprv.derivation_path.child(child),
+                    Wildcard::Hardened => return None,
+                };
+                let derived = xprv.xkey.derive_priv(&secp, &path).ok()?;
+                Some(derived.to_priv())
+            }
+            DescriptorSecretKey::MultiXPrv(_) => None,
+        })
+        .find(|key| {
+            ScriptBuf::new_p2pkh(&key.public_key(&secp).pubkey_hash()) == address.script_pubkey()
+        })
+        .ok_or_else(|| signing_error("no key for this address"))
+}
+
+fn invalid(field: &str, message: impl Into<String>) -> WalletError {
+    WalletError::InvalidArgument {
+        field: field.to_string(),
+        message: message.into(),
+    }
+}
+
+fn signing_error(message: impl Into<String>) -> WalletError {
+    WalletError::Signing {
+        message: message.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bdk_wallet::bitcoin::{hex::DisplayHex, Network};
+
+    // Test vectors from BIP322, for the key of the `abandon ... about` seed
+    // at m/84'/0'/0'/0/0 and its Taproot counterpart
+    const SEGWIT: &str = "bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l";
+    const TAPROOT: &str = "bc1ppv609nr0vr25u07u95waq5lucwfm6tde4nydujnu8npg4q75mr5sxq8lt3";
+
+    fn address(address: &str) -> Address {
+        address
+            .parse::<Address<_>>()
+            .unwrap()
+            .require_network(Network::Bitcoin)
+            .unwrap()
+    }
+
+    #[test]
+    fn message_hashes() {
+        assert_eq!(
+            message_hash("").to_lower_hex_string(),
+            "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"
+        );
+        assert_eq!(
+            message_hash("Hello World").to_lower_hex_string(),
+            "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"
+        );
+    }
+
+    #[test]
+    fn virtual_transactions() {
+        let script_pubkey = address(SEGWIT).script_pubkey();
+        let empty = to_spend(&script_pubkey, "");
+        assert_eq!(
+            empty.compute_txid().to_string(),
+            "c5680aa69bb8d860bf82d4e9cd3504b55dde018de765a91bb566283c545a99a7"
+        );
+        assert_eq!(
+            to_sign(&empty, Witness::new()).compute_txid().to_string(),
+            "1e9654e951a5ba44c8604c4de6c67fd78a27e81dcadcfe1edf638ba3aaebaed6"
+        );
+        let hello = to_spend(&script_pubkey, "Hello World");
+        assert_eq!(
+            hello.compute_txid().to_string(),
+            "b79d196740ad5217771c1098fc4a4b51e0535c32236c71f1ea4d61a2d603352b"
+        );
+        assert_eq!(
+            to_sign(&hello, Witness::new()).compute_txid().to_string(),
+            "88737ae86f2077145f93cc4b153ae9a1cb8d56afa511988c149c5c8c9d93bddf"
+        );
+    }
+
+    #[test]
+    fn verify_vectors() {
+        let segwit = address(SEGWIT);
+        let empty = "AkcwRAIgM2gBAQqvZX15ZiysmKmQpDrG83avLIT492QBzLnQIxYCIBaTpOaD20qRlEylyxFSeEA2ba9YOixpX8z46TSDtS40ASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+        let hello = "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+        assert_eq!(
+            verify(&segwit, "", empty).unwrap(),
+            (true, MessageFormat::Bip322Simple)
+        );
+        assert_eq!(
+            verify(&segwit, "Hello World", hello).unwrap(),
+            (true, MessageFormat::Bip322Simple)
+        );
+        // Swapped around, each signature is for the other message
+        assert!(!verify(&segwit, "Hello World", empty).unwrap().0);
+        assert!(!verify(&segwit, "", hello).unwrap().0);
+
+        let taproot = address(TAPROOT);
+        let hello = "AUHd69PrJQEv+oKTfZ8l+WROBHuy9HKrbFCJu7U1iK2iiEy1vMU5EfMtjc+VSHM7aU0SDbak5IUZRVno2P5mjSafAQ==";
+        assert!(verify(&taproot, "Hello World", hello).unwrap().0);
+        assert!(!verify(&taproot, "Hello World!", hello).unwrap().0);
+        // A segwit signature doesn't pass for the Taproot address
+        assert!(!verify(&taproot, "", empty).unwrap().0);
+
+        assert!(matches!(
+            verify(&segwit, "Hello World", "not base64!"),
+            Err(WalletError::InvalidArgument { field, .. }) if field == "signature"
+        ));
+    }
+}
@@ -0,0 +1,476 @@
+// The wallets this app instance knows about.
+//
+// Every wallet has its own directory under the data dir holding its
+// database and keystore (see `ConfigStore::wallet_dir`), and its own
+// network. The registry file lists them by id along with their display name,
+// and remembers which one requests go to when they don't name a wallet.
+// Further accounts of a seed are wallets of their own; they share the
+// fingerprint of the wallet they were added to.
+use std::path::{Path, PathBuf};
+
+use bdk_wallet::bitcoin::Network;
+use serde::{Deserialize, Serialize};
+
+use crate::error::WalletError;
+use crate::keystore::{KeystoreFile, ScriptType};
+use crate::storage;
+
+/// Stable identifier of a wallet, never reused after it is deleted.
+pub type WalletId = u32;
+
+// Bumped whenever the file layout changes
+const REGISTRY_VERSION: u32 = 1;
+// Longest wallet name, in characters
+const MAX_NAME_LEN: usize = 64;
+
+/// What the frontend needs to list a wallet; no keys.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalletEntry {
+    pub id: WalletId,
+    pub name: String,
+    pub network: Network,
+    pub script_type: ScriptType,
+    pub fingerprint: String,
+    /// BIP44 account of the seed the wallet is derived at
+    #[serde(default)]
+    pub account: u32,
+    pub watch_only: bool,
+    /// Unix time in seconds
+    pub created_at: u64,
+    /// See `KeystoreFile::checksum`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// Restored, but no recovery scan has gone through yet. Syncs look as
+    /// far ahead as a restore until one does.
+    #[serde(default)]
+    pub recovering: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RegistryFile {
+    version: u32,
+    next_id: WalletId,
+    active: Option<WalletId>,
+    wallets: Vec<WalletEntry>,
+}
+
+impl Default for RegistryFile {
+    fn default() -> Self {
+        Self {
+            version: REGISTRY_VERSION,
+            next_id: 1,
+            active: None,
+            wallets: Vec::new(),
+        }
+    }
+}
+
+/// The registry file, loaded once and written back on every change.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    path: PathBuf,
+    file: RegistryFile,
+}
+
+impl Registry {
+    /// Read the registry at `path`, starting empty if there isn't one yet.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, WalletError> {
+        let path = path.into();
+        let file = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|e| WalletError::Database {
+                message: format!("Failed to parse {}: {}", path.display(), e),
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => RegistryFile::default(),
+            Err(e) => {
+                return Err(WalletError::Database {
+                    message: format!("{}: {}", path.display(), e),
+                })
+            }
+        };
+        Ok(Self { path, file })
+    }
+
+    pub fn wallets(&self) -> &[WalletEntry] {
+        &self.file.wallets
+    }
+
+    pub fn active(&self) -> Option<WalletId> {
+        self.file.active
+    }
+
+    pub fn get(&self, id: WalletId) -> Result<&WalletEntry, WalletError> {
+        self.file
+            .wallets
+            .iter()
+            .find(|entry| entry.id == id)
+            .ok_or(WalletError::WalletNotFound)
+    }
+
+    /// Id the next wallet will get. Nothing is reserved until `add`.
+    pub fn next_id(&self) -> WalletId {
+        self.file.next_id
+    }
+
+    /// Check that a wallet for `keystore` could be added under `name`, so
+    /// nothing is written to disk for a request that is going to fail.
+    pub fn check_new(&self, name: &str, keystore: &KeystoreFile) -> Result<(), WalletError> {
+        self.check_name(name, None)?;
+        self.check_keys(keystore, None)
+    }
+
+    /// Check that wallet `id` could be swapped for one with `keystore`'s keys.
+    pub fn check_replacement(
+        &self,
+        id: WalletId,
+        keystore: &KeystoreFile,
+    ) -> Result<(), WalletError> {
+        self.get(id)?;
+        self.check_keys(keystore, Some(id))
+    }
+
+    /// Register a wallet whose files were written to `next_id`'s directory
+    /// and make it the active one.
+    pub fn add(&mut self, name: &str, keystore: &KeystoreFile) -> Result<WalletEntry, WalletError> {
+        self.check_new(name, keystore)?;
+        let entry = WalletEntry {
+            id: self.file.next_id,
+            name: name.trim().to_string(),
+            network: keystore.network,
+            script_type: keystore.script_type,
+            fingerprint: keystore.fingerprint.clone(),
+            account: keystore.account,
+            watch_only: keystore.watch_only,
+            created_at: unix_time(),
+            checksum: keystore.checksum.clone(),
+            recovering: false,
+        };
+        self.update(|file| {
+            file.next_id += 1;
+            file.active = Some(entry.id);
+            file.wallets.push(entry.clone());
+        })?;
+        Ok(entry)
+    }
+
+    /// Register a wallet whose files were written to `next_id`'s directory
+    /// in place of wallet `id`, under its name and in its spot in the list.
+    /// Removing the old wallet's files is up to the caller.
+    pub fn replace(
+        &mut self,
+        id: WalletId,
+        keystore: &KeystoreFile,
+    ) -> Result<WalletEntry, WalletError> {
+        self.check_replacement(id, keystore)?;
+        let entry = WalletEntry {
+            id: self.file.next_id,
+            name: self.get(id)?.name.clone(),
+            network: keystore.network,
+            script_type: keystore.script_type,
+            fingerprint: keystore.fingerprint.clone(),
+            account: keystore.account,
+            watch_only: keystore.watch_only,
+            created_at: unix_time(),
+            checksum: keystore.checksum.clone(),
+            recovering: false,
+        };
+        self.update(|file| {
+            file.next_id += 1;
+            if file.active == Some(id) {
+                file.active = Some(entry.id);
+            }
+            if let Some(old) = file.wallets.iter_mut().find(|old| old.id == id) {
+                *old = entry.clone();
+            }
+        })?;
+        Ok(entry)
+    }
+
+    pub fn rename(&mut self, id: WalletId, name: &str) -> Result<WalletEntry, WalletError> {
+        self.get(id)?;
+        self.check_name(name, Some(id))?;
+        self.update(|file| {
+            if let Some(entry) = file.wallets.iter_mut().find(|entry| entry.id == id) {
+                entry.name = name.trim().to_string();
+            }
+        })?;
+        self.get(id).cloned()
+    }
+
+    /// Date a wallet back to `created_at` if it is older than the time it
+    /// was added, as with wallets restored from a backup.
+    pub fn backdate(&mut self, id: WalletId, created_at: u64) -> Result<(), WalletError> {
+        self.get(id)?;
+        self.update(|file| {
+            if let Some(entry) = file.wallets.iter_mut().find(|entry| entry.id == id) {
+                entry.created_at = entry.created_at.min(created_at);
+            }
+        })
+    }
+
+    pub fn set_recovering(&mut self, id: WalletId, recovering: bool) -> Result<(), WalletError> {
+        if self.get(id)?.recovering == recovering {
+            return Ok(());
+        }
+        self.update(|file| {
+            if let Some(entry) = file.wallets.iter_mut().find(|entry| entry.id == id) {
+                entry.recovering = recovering;
+            }
+        })
+    }
+
+    pub fn select(&mut self, id: WalletId) -> Result<(), WalletError> {
+        self.get(id)?;
+        self.update(|file| file.active = Some(id))
+    }
+
+    /// Forget a wallet. The oldest remaining one becomes active if it was
+    /// the active one.
+    pub fn remove(&mut self, id: WalletId) -> Result<WalletEntry, WalletError> {
+        let entry = self.get(id)?.clone();
+        self.update(|file| {
+            file.wallets.retain(|entry| entry.id != id);
+            if file.active == Some(id) {
+                file.active = file.wallets.first().map(|entry| entry.id);
+            }
+        })?;
+        Ok(entry)
+    }
+
+    /// The lowest account of the seed behind `parent` that has no wallet
+    /// of `script_type` yet.
+    pub fn next_account(&self, parent: &WalletEntry, script_type: ScriptType) -> u32 {
+        (0..)
+            .find(|account| {
+                !self.file.wallets.iter().any(|entry| {
+                    entry.network == parent.network
+                        && entry.fingerprint == parent.fingerprint
+                        && entry.script_type == script_type
+                        && entry.account == *account
+                        && entry.watch_only == parent.watch_only
+                })
+            })
+            .expect("some account is free")
+    }
+
+    /// A name for a wallet the user didn't name, e.g. `Signet wallet 2`.
+    pub fn default_name(&self, network: Network) -> String {
+        let base = match network {
+            Network::Bitcoin => "Bitcoin wallet".to_string(),
+            other => {
+                let name = other.to_string();
+                let mut chars = name.chars();
+                let first = chars.next().map(|c| c.to_ascii_uppercase());
+                format!("{}{} wallet", first.unwrap_or_default(), chars.as_str())
+            }
+        };
+        (1..)
+            .map(|n| match n {
+                1 => base.clone(),
+                n => format!("{} {}", base, n),
+            })
+            .find(|name| self.check_name(name, None).is_ok())
+            .expect("some name is free")
+    }
+
+    /// Names are what the user picks wallets by, so they must be unique.
+    /// `renaming` is left out of the comparison.
+    pub fn check_name(&self, name: &str, renaming: Option<WalletId>) -> Result<(), WalletError> {
+        let name = name.trim();
+        let invalid = |message: &str| WalletError::InvalidArgument {
+            field: "name".to_string(),
+            message: message.to_string(),
+        };
+        if name.is_empty() {
+            return Err(invalid("must not be empty"));
+        }
+        if name.chars().count() > MAX_NAME_LEN {
+            return Err(invalid(&format!(
+                "must be at most {} characters",
+                MAX_NAME_LEN
+            )));
+        }
+        let taken = self.file.wallets.iter().any(|entry| {
+            Some(entry.id) != renaming && entry.name.to_lowercase() == name.to_lowercase()
+        });
+        if taken {
+            return Err(invalid("is already used by another wallet"));
+        }
+        Ok(())
+    }
+
+    // Only one wallet per set of keys; `replacing` is left out of the
+    // comparison
+    fn check_keys(
+        &self,
+        keystore: &KeystoreFile,
+        replacing: Option<WalletId>,
+    ) -> Result<(), WalletError> {
+        let duplicate = self.file.wallets.iter().any(|entry| {
+            Some(entry.id) != replacing
+                && entry.network == keystore.network
+                && entry.fingerprint == keystore.fingerprint
+                && entry.script_type == keystore.script_type
+                && entry.account == keystore.account
+                && entry.watch_only == keystore.watch_only
+                && entry.checksum == keystore.checksum
+        });
+        if duplicate {
+            return Err(WalletError::WalletAlreadyExists);
+        }
+        Ok(())
+    }
+
+    // Apply `change` and save, leaving the registry as it was if saving fails
+    fn update(&mut self, change: impl FnOnce(&mut RegistryFile)) -> Result<(), WalletError> {
+        let mut file = self.file.clone();
+        change(&mut file);
+        save(&self.path, &file)?;
+        self.file = file;
+        Ok(())
+    }
+}
+
+fn save(path: &Path, file: &RegistryFile) -> Result<(), WalletError> {
+    let contents = serde_json::to_string_pretty(file).map_err(|e| WalletError::Database {
+        message: format!("Failed to serialize wallet list: {}", e),
+    })?;
+    storage::replace_file(path, contents.as_bytes())
+}
+
+/// Seconds since the epoch.
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::keystore::{self, Keystore};
+
+    const WORDS: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    // Different keys for each passphrase
+    fn keystore_file(passphrase: &str) -> KeystoreFile {
+        let mnemonic = keystore::parse_mnemonic(WORDS).unwrap();
+        let keystore = Keystore::from_mnemonic(
+            &mnemonic,
+            Some(passphrase),
+            Network::Signet,
+            ScriptType::Bip84,
+            0,
+        )
+        .unwrap();
+        KeystoreFile::unencrypted(&keystore)
+    }
+
+    fn temp_registry(name: &str) -> (PathBuf, Registry) {
+        let dir =
+            std::env::temp_dir().join(format!("slaps-registry-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let registry = Registry::load(dir.join("wallets.json")).unwrap();
+        (dir, registry)
+    }
+
+    #[test]
+    fn check_new() {
+        let (dir, mut registry) = temp_registry("check");
+        let file = keystore_file("");
+        registry.add("Savings", &file).unwrap();
+
+        let invalid_name = |result: Result<(), WalletError>| matches!(result, Err(WalletError::InvalidArgument { field, .. }) if field == "name");
+        let other = keystore_file("other");
+        assert!(invalid_name(registry.check_new(" ", &other)));
+        assert!(invalid_name(registry.check_new(&"x".repeat(65), &other)));
+        assert!(invalid_name(registry.check_new(" savings ", &other)));
+        assert!(matches!(
+            registry.check_new("Spending", &file),
+            Err(WalletError::WalletAlreadyExists)
+        ));
+        assert!(registry.check_new("Spending", &other).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn add_select_and_remove() {
+        let (dir, mut registry) = temp_registry("add");
+        assert_eq!(registry.default_name(Network::Signet), "Signet wallet");
+        let first = registry.add("Signet wallet", &keystore_file("")).unwrap();
+        assert_eq!(registry.default_name(Network::Signet), "Signet wallet 2");
+        let second = registry.add("Spending", &keystore_file("other")).unwrap();
+        assert_eq!((first.id, second.id), (1, 2));
+        assert_eq!(registry.active(), Some(second.id));
+
+        registry.set_recovering(first.id, true).unwrap();
+        registry.select(first.id).unwrap();
+        registry.rename(first.id, "Savings").unwrap();
+        let reloaded = Registry::load(dir.join("wallets.json")).unwrap();
+        assert_eq!(reloaded.active(), Some(first.id));
+        assert_eq!(reloaded.get(first.id).unwrap().name, "Savings");
+        assert!(reloaded.get(first.id).unwrap().recovering);
+
+        // Ids are never reused, and the oldest wallet left takes over
+        registry.remove(first.id).unwrap();
+        assert_eq!(registry.active(), Some(second.id));
+        assert!(matches!(
+            registry.get(first.id),
+            Err(WalletError::WalletNotFound)
+        ));
+        assert_eq!(registry.next_id(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replace() {
+        let (dir, mut registry) = temp_registry("replace");
+        let first = registry.add("Restored", &keystore_file("")).unwrap();
+        registry.set_recovering(first.id, true).unwrap();
+        let other = keystore_file("other");
+        registry.add("Spending", &other).unwrap();
+        registry.select(first.id).unwrap();
+
+        // Another wallet already has those keys
+        assert!(matches!(
+            registry.replace(first.id, &other),
+            Err(WalletError::WalletAlreadyExists)
+        ));
+        // Its own keys are no duplicate of themselves
+        let replacement = keystore_file("");
+        let entry = registry.replace(first.id, &replacement).unwrap();
+        assert_eq!(entry.id, 3);
+        assert_eq!(entry.name, "Restored");
+        assert!(!entry.recovering);
+        assert_eq!(registry.active(), Some(entry.id));
+        let ids: Vec<_> = registry.wallets().iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [3, 2]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn next_account() {
+        let (dir, mut registry) = temp_registry("accounts");
+        let parent = registry.add("Signet wallet", &keystore_file("")).unwrap();
+        assert_eq!(registry.next_account(&parent, ScriptType::Bip84), 1);
+        assert_eq!(registry.next_account(&parent, ScriptType::Bip44), 0);
+
+        // Another account of the same seed is a wallet of its own
+        let mnemonic = keystore::parse_mnemonic(WORDS).unwrap();
+        let account =
+            Keystore::from_mnemonic(&mnemonic, Some(""), Network::Signet, ScriptType::Bip84, 1)
+                .unwrap();
+        let account = KeystoreFile::unencrypted(&account);
+        registry
+            .check_new("Signet wallet account 1", &account)
+            .unwrap();
+        registry.add("Signet wallet account 1", &account).unwrap();
+        assert_eq!(registry.next_account(&parent, ScriptType::Bip84), 2);
+        assert!(matches!(
+            registry.check_new("Again", &account),
+            Err(WalletError::WalletAlreadyExists)
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
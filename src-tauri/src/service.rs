@@ -0,0 +1,3069 @@
+// Wallet background service, independent of Tauri so it can be driven from
+// tests, a CLI or any other frontend that implements `EventSink`.
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, AbortHandle, JoinError, JoinSet};
+use tokio::time::{sleep, Duration, Instant};
+
+// BDK wallet imports
+use bdk_esplora::esplora_client;
+use bdk_wallet::{
+    bitcoin::{
+        bech32, Address, Amount, FeeRate, Network, OutPoint, Psbt, Transaction, TxOut, Weight,
+    },
+    chain::spk_client::FullScanResponse,
+    rusqlite::Connection,
+    KeychainKind, PersistedWallet, SignOptions, Wallet,
+};
+
+use crate::backup::{self, Backup, BackupSettings, Birthday, WalletBackup};
+use crate::config::{Config, ConfigStore};
+use crate::descriptors::{self, WatchOnly};
+use crate::error::WalletError;
+use crate::events::{
+    AccountAdded, AddressInfo, BackupExported, BackupImported, BalanceInfo, ConfigUpdated,
+    DataUpdated, EventEmitter, EventSink, Heartbeat, LabelList, MessageVerification,
+    MultisigCreated, OutputInfo, Pong, ServiceStatus, SignedMessage, SyncCancelled, SyncCompleted,
+    SyncStarted, TransactionInfo, UnsignedPsbt, UtxoInfo, UtxoList, WalletCreated,
+    WalletErrorEvent, WalletImported, WalletList, WalletLocked, WalletRestored, WalletUnlocked,
+};
+use crate::fees::{FeeChoice, FeeEstimates};
+use crate::jobs::{self, JobOutcome, ScanPlan, SyncSettings};
+use crate::keystore::{self, Keystore, KeystoreFile, RecoveryPhrase, ScriptType};
+use crate::labels::{self, Label};
+use crate::message;
+use crate::registry::{unix_time, Registry, WalletEntry, WalletId};
+use crate::storage::{self, LEGACY_DB_PATH};
+
+// How often the background task emits a heartbeat when idle
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+// Capacity of the channel feeding the background task
+pub(crate) const CHANNEL_CAPACITY: usize = 100;
+
+// Define channel message type
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub enum AppMessage {
+    Ping,
+    UpdateData(String),
+    Shutdown,
+    // Lifecycle of the background task, handled by the supervisor
+    Start,
+    Restart,
+    Status,
+    // Wallet registry. New wallets are created on the configured network
+    // and become the active one.
+    ListWallets,
+    SelectWallet {
+        id: WalletId,
+    },
+    RenameWallet {
+        id: WalletId,
+        name: String,
+    },
+    // Removes the wallet's keys and database from disk
+    DeleteWallet {
+        id: WalletId,
+    },
+    // Wallet operations
+    CreateWallet {
+        word_count: usize, // 12 or 24
+        passphrase: Option<String>,
+        // Encrypts the keystore; unrelated to the BIP39 passphrase
+        password: String,
+        // Display name; a default like "Signet wallet 2" when missing
+        #[serde(default)]
+        name: Option<String>,
+        // BIP84 native segwit when missing
+        #[serde(default)]
+        script_type: ScriptType,
+    },
+    RestoreWallet {
+        mnemonic: String,
+        passphrase: Option<String>,
+        password: String,
+        // Candidates to look for funds on; BIP84 when empty
+        #[serde(default)]
+        script_types: Vec<ScriptType>,
+        #[serde(default)]
+        name: Option<String>,
+    },
+    // Another BIP44 account of the targeted wallet's seed, as a wallet of its
+    // own with separate balance, history and addresses. `password` opens
+    // the wallet's keystore and encrypts the new one.
+    AddAccount {
+        password: String,
+        // Needed again if the wallet has one, it is never stored
+        #[serde(default)]
+        passphrase: Option<String>,
+        // The wallet's own script type when missing
+        #[serde(default)]
+        script_type: Option<ScriptType>,
+        // The lowest free account when missing
+        #[serde(default)]
+        account: Option<u32>,
+        #[serde(default)]
+        name: Option<String>,
+    },
+    // k-of-n multisig between our key and the cosigners' account keys
+    CreateMultisig {
+        threshold: usize,
+        // Account keys with their origin, e.g. `[d34db33f/48'/1'/0'/2']tpub...`
+        cosigners: Vec<String>,
+        // Words for our key; new ones are generated and returned when missing
+        #[serde(default)]
+        mnemonic: Option<String>,
+        #[serde(default)]
+        passphrase: Option<String>,
+        password: String,
+        #[serde(default)]
+        name: Option<String>,
+    },
+    // Watch-only wallet from an xpub/tpub/zpub/vpub or public descriptors
+    ImportWatchOnly {
+        descriptor: String,
+        change_descriptor: Option<String>,
+        #[serde(default)]
+        name: Option<String>,
+    },
+    // Backup document of the wallet. With the keystore `password` it holds
+    // the private keys and words, otherwise only the public descriptors;
+    // `backup_password` encrypts the document itself.
+    ExportBackup {
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        backup_password: Option<String>,
+    },
+    // Add the wallet in a backup document, checked in full before anything
+    // is written. `password` encrypts the restored keystore.
+    ImportBackup {
+        backup: String,
+        #[serde(default)]
+        backup_password: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        name: Option<String>,
+        // Also take over the settings stored in the backup
+        #[serde(default)]
+        restore_settings: bool,
+    },
+    Unlock {
+        password: String,
+    },
+    Lock,
+    GetWalletAddress,
+    SyncWallet,
+    CancelSync,
+    GetWalletBalance,
+    // Unspent outputs, e.g. to pick from for `SendAll`
+    GetUtxos,
+    // The wallet's BIP329 labels, the same ones its backups carry
+    GetLabels,
+    // Label a transaction, address or output; empty text removes its label
+    SetLabel(Label),
+    // Fast/normal/slow rates from the esplora server, cached for a while
+    GetFeeEstimates,
+    // Pay `amount` sats to `address`, which must be on the wallet's network
+    SendTransaction {
+        address: String,
+        amount: u64,
+        // A preset or a custom sat/vB rate; normal when missing
+        #[serde(default)]
+        fee: FeeChoice,
+    },
+    // Empty the wallet into `address`, which gets everything but the fee.
+    // Spends every coin unless limited to confirmed ones or to the `utxos`
+    // picked, as `txid:vout`
+    SendAll {
+        address: String,
+        #[serde(default)]
+        fee: FeeChoice,
+        #[serde(default)]
+        confirmed_only: bool,
+        #[serde(default)]
+        utxos: Vec<String>,
+    },
+    // Pay every recipient in one transaction, which costs less in fees
+    // than a send per recipient
+    SendBatch {
+        recipients: Vec<Recipient>,
+        #[serde(default)]
+        fee: FeeChoice,
+    },
+    // PSBTs for one transaction signed by other cosigners or an offline
+    // signer; combined, signed with our key and broadcast once complete
+    CombinePsbt {
+        psbts: Vec<String>,
+    },
+    // Prove control of one of the wallet's addresses: BIP322 simple
+    // signatures for native segwit and taproot, signmessage for legacy
+    SignMessage {
+        address: String,
+        message: String,
+    },
+    // Check a signature from `SignMessage` or another wallet; needs no wallet
+    VerifyMessage {
+        address: String,
+        message: String,
+        signature: String,
+    },
+    // Configuration
+    GetConfig,
+    UpdateConfig(Config),
+}
+
+impl AppMessage {
+    // Whether this works with the targeted wallet, which keeps it from being
+    // locked as idle. Pings, settings and the wallet list don't, or a
+    // frontend polling them would keep the keys loaded forever.
+    fn uses_wallet(&self) -> bool {
+        match self {
+            Self::AddAccount { .. }
+            | Self::ExportBackup { .. }
+            | Self::Unlock { .. }
+            | Self::GetWalletAddress
+            | Self::SyncWallet
+            | Self::GetWalletBalance
+            | Self::GetUtxos
+            | Self::GetLabels
+            | Self::SetLabel(_)
+            | Self::SendTransaction { .. }
+            | Self::SendAll { .. }
+            | Self::SendBatch { .. }
+            | Self::CombinePsbt { .. }
+            | Self::SignMessage { .. } => true,
+            Self::Ping
+            | Self::UpdateData(_)
+            | Self::Shutdown
+            | Self::Start
+            | Self::Restart
+            | Self::Status
+            | Self::ListWallets
+            | Self::SelectWallet { .. }
+            | Self::RenameWallet { .. }
+            | Self::DeleteWallet { .. }
+            | Self::CreateWallet { .. }
+            | Self::RestoreWallet { .. }
+            | Self::CreateMultisig { .. }
+            | Self::ImportWatchOnly { .. }
+            | Self::ImportBackup { .. }
+            | Self::Lock
+            | Self::CancelSync
+            | Self::GetFeeEstimates
+            | Self::VerifyMessage { .. }
+            | Self::GetConfig
+            | Self::UpdateConfig(_) => false,
+        }
+    }
+}
+
+/// One output of `SendBatch`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Recipient {
+    pub address: String,
+    /// In sats
+    pub amount: u64,
+}
+
+/// Typed result of a single `AppMessage`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub enum AppResponse {
+    Pong,
+    DataUpdated(String),
+    ShuttingDown,
+    Status(ServiceStatus),
+    Wallets(WalletList),
+    // The only time the words are ever returned
+    WalletCreated {
+        wallet_id: WalletId,
+        phrase: RecoveryPhrase,
+    },
+    WalletRestored(WalletRestored),
+    AccountAdded(AccountAdded),
+    MultisigCreated {
+        wallet: MultisigCreated,
+        // Only when the words for our key were generated
+        phrase: Option<RecoveryPhrase>,
+    },
+    WalletImported(WalletImported),
+    // The backup document, to be saved by the frontend
+    BackupExported(String),
+    BackupImported(BackupImported),
+    Unlocked,
+    Locked,
+    WalletAddress(AddressInfo),
+    WalletSynced(BalanceInfo),
+    // Whether a running sync was stopped
+    SyncCancelled(bool),
+    WalletBalance(BalanceInfo),
+    Utxos(UtxoList),
+    Labels(LabelList),
+    FeeEstimates(FeeEstimates),
+    TransactionSent(TransactionInfo),
+    // What a send returns while signatures are missing
+    PsbtCreated(UnsignedPsbt),
+    MessageSigned(SignedMessage),
+    MessageVerified(MessageVerification),
+    Config(Config),
+}
+
+/// Reply returned to whoever sent the request.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Reply {
+    pub request_id: u64,
+    pub response: AppResponse,
+}
+
+/// Error returned to whoever sent the request.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RequestError {
+    pub request_id: u64,
+    /// Human readable fallback for `error`. Not called `message`, which
+    /// several error codes already use for their own details.
+    pub display: String,
+    #[serde(flatten)]
+    pub error: WalletError,
+}
+
+impl RequestError {
+    fn new(request_id: u64, error: WalletError) -> Self {
+        Self {
+            request_id,
+            display: error.to_string(),
+            error,
+        }
+    }
+}
+
+type ReplySender = oneshot::Sender<Result<AppResponse, WalletError>>;
+
+// A message tagged with its request id, the wallet it targets (the active
+// one when missing) and an optional reply channel
+pub(crate) struct Request {
+    pub(crate) id: u64,
+    pub(crate) wallet: Option<WalletId>,
+    pub(crate) message: AppMessage,
+    pub(crate) reply: Option<ReplySender>,
+}
+
+/// Cloneable handle for sending messages to a running `WalletService`.
+#[derive(Debug, Clone)]
+pub struct ServiceHandle {
+    tx: mpsc::Sender<Request>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl ServiceHandle {
+    pub(crate) fn new(tx: mpsc::Sender<Request>) -> Self {
+        Self {
+            tx,
+            next_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    /// Send a message to the active wallet and wait for the result of that
+    /// specific operation.
+    pub async fn request(&self, message: AppMessage) -> Result<Reply, RequestError> {
+        self.request_wallet(None, message).await
+    }
+
+    /// Like `request`, but for the wallet `wallet` instead of the active one.
+    pub async fn request_wallet(
+        &self,
+        wallet: Option<WalletId>,
+        message: AppMessage,
+    ) -> Result<Reply, RequestError> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let request_id = self.enqueue(wallet, message, Some(reply_tx)).await?;
+
+        let result = reply_rx.await.unwrap_or_else(|_| {
+            Err(WalletError::ServiceUnavailable {
+                message: "Background task dropped the request".to_string(),
+            })
+        });
+        match result {
+            Ok(response) => Ok(Reply {
+                request_id,
+                response,
+            }),
+            Err(error) => Err(RequestError::new(request_id, error)),
+        }
+    }
+
+    /// Queue a message for the active wallet without waiting for its
+    /// result. Returns the request id.
+    pub async fn send(&self, message: AppMessage) -> Result<u64, RequestError> {
+        self.enqueue(None, message, None).await
+    }
+
+    async fn enqueue(
+        &self,
+        wallet: Option<WalletId>,
+        message: AppMessage,
+        reply: Option<ReplySender>,
+    ) -> Result<u64, RequestError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = Request {
+            id,
+            wallet,
+            message,
+            reply,
+        };
+        self.tx.send(request).await.map_err(|e| {
+            let error = WalletError::ServiceUnavailable {
+                message: e.to_string(),
+            };
+            RequestError::new(id, error)
+        })?;
+        Ok(id)
+    }
+}
+
+// Wallet and its database connection, loaded once and owned by the
+// background task. The wallet only holds signing keys while unlocked, and
+// a watch-only wallet never does.
+struct LoadedWallet {
+    id: WalletId,
+    db_path: PathBuf,
+    conn: Connection,
+    wallet: PersistedWallet<Connection>,
+    script_type: ScriptType,
+    unlocked: bool,
+    watch_only: bool,
+    // Last request for this wallet, for the idle timer
+    last_used: Instant,
+}
+
+impl LoadedWallet {
+    // Write any staged changes to the database
+    fn persist(&mut self) -> Result<(), WalletError> {
+        self.wallet.persist(&mut self.conn)?;
+        Ok(())
+    }
+
+    // Reload the wallet with the signing keys from `keystore`, or with none
+    // at all to lock it
+    fn reload(&mut self, keystore: Option<&Keystore>) -> Result<(), WalletError> {
+        self.persist()?;
+        let mut params = Wallet::load().check_network(self.wallet.network());
+        if let Some(keystore) = keystore {
+            params = params
+                .descriptor(KeychainKind::External, Some(keystore.external.clone()))
+                .descriptor(KeychainKind::Internal, Some(keystore.internal.clone()))
+                .extract_keys();
+        }
+        self.wallet = params
+            .load_wallet(&mut self.conn)?
+            .ok_or(WalletError::WalletNotFound)?;
+        self.unlocked = keystore.is_some();
+        // Unlocking can take a while; the idle time starts after it
+        self.last_used = Instant::now();
+        Ok(())
+    }
+}
+
+// Completes a request: broadcasts errors for passive listeners and answers
+// the requester if it is waiting. Long-running requests keep their
+// responder until the job behind them finishes.
+pub(crate) struct Responder {
+    pub(crate) events: EventEmitter,
+    pub(crate) reply: Option<ReplySender>,
+}
+
+impl Responder {
+    pub(crate) fn respond(self, result: Result<AppResponse, WalletError>) {
+        // Errors are still broadcast for passive listeners
+        if let Err(error) = &result {
+            self.events.emit(WalletErrorEvent::from(error.clone()));
+        }
+        if let Some(reply) = self.reply {
+            // The requester may have given up waiting; that's fine
+            let _ = reply.send(result);
+        }
+    }
+}
+
+// A running full scan and everyone waiting for it
+struct SyncJob {
+    handle: AbortHandle,
+    waiters: Vec<Responder>,
+}
+
+// A running fee estimate request, and who is waiting for the estimates
+struct FeeJob {
+    handle: AbortHandle,
+    waiters: Vec<FeeWaiter>,
+}
+
+enum FeeWaiter {
+    Request(Responder),
+    // A send at a preset rate, started once the estimates are in
+    Send(Responder, WalletId, SendRequest),
+}
+
+impl FeeWaiter {
+    fn responder(self) -> Responder {
+        match self {
+            Self::Request(responder) | Self::Send(responder, _, _) => responder,
+        }
+    }
+}
+
+// A restored wallet waiting for its recovery scan, with the keystore for
+// every script type that was asked for
+struct RestoreJob {
+    handle: AbortHandle,
+    responder: Responder,
+    wallet_id: WalletId,
+    candidates: Vec<Keystore>,
+    // The candidate the wallet was saved with
+    saved: usize,
+    // Encrypts the keystore of another candidate if the scan picks it
+    password: String,
+}
+
+// Password hashing for a request, run on the blocking pool since Argon2 takes
+// long enough to stall the loop. It hands back how to go on with the request
+// on the service task, which answers the requester.
+type KeyWork = Box<dyn FnOnce() -> KeyFinish + Send>;
+type KeyFinish = Box<dyn FnOnce(&mut WalletService, Responder) + Send>;
+
+// Pair up the slow part of a request with what is done with its result
+fn key_work<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, WalletError> + Send + 'static,
+    finish: impl FnOnce(&mut WalletService, &EventEmitter, T) -> Result<AppResponse, WalletError>
+        + Send
+        + 'static,
+) -> KeyWork {
+    key_work_then(work, move |service, responder, value| {
+        let result = finish(service, &responder.events, value);
+        responder.respond(result);
+    })
+}
+
+// Like `key_work`, for requests that go on with another job and answer
+// when that one is done
+fn key_work_then<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, WalletError> + Send + 'static,
+    then: impl FnOnce(&mut WalletService, Responder, T) + Send + 'static,
+) -> KeyWork {
+    Box::new(move || {
+        let result = work();
+        Box::new(move |service, responder| match result {
+            Ok(value) => then(service, responder, value),
+            Err(e) => responder.respond(Err(e)),
+        })
+    })
+}
+
+/// Owns the `AppMessage` loop that used to live inside `run()`.
+///
+/// Quick requests are answered inline. Scans and broadcasts run as spawned
+/// jobs, and password hashing on the blocking pool, so the loop keeps
+/// serving pings, balance queries and heartbeats while they are in flight.
+pub struct WalletService {
+    rx: mpsc::Receiver<Request>,
+    events: EventEmitter,
+    config: ConfigStore,
+    // Read on first use, like the wallets it lists
+    registry: Option<Registry>,
+    wallets: HashMap<WalletId, LoadedWallet>,
+    jobs: JoinSet<JobOutcome>,
+    // Running full scan per wallet; a second `SyncWallet` for the same
+    // wallet joins the running scan instead of starting another one
+    syncs: HashMap<WalletId, SyncJob>,
+    // Recovery scan for a wallet being restored. The wallet is saved before
+    // it starts, and swapped for another script type if that is the one the
+    // scan finds used.
+    restore_job: Option<RestoreJob>,
+    // Running broadcast, and sends queued behind it so two transactions
+    // never pick the same coins
+    broadcast_job: Option<(task::Id, WalletId, Responder)>,
+    queued_sends: VecDeque<(Responder, WalletId, SendRequest)>,
+    // Last fee estimates and the esplora server they came from
+    fee_estimates: Option<(String, FeeEstimates)>,
+    fee_job: Option<FeeJob>,
+    // Keystores and backups being sealed or opened, with who asked
+    key_jobs: JoinSet<KeyFinish>,
+    key_waiters: HashMap<task::Id, Responder>,
+    heartbeat_count: u64,
+}
+
+impl WalletService {
+    /// Create the service along with the handle used to talk to it.
+    pub fn new(config: ConfigStore, sink: impl EventSink) -> (Self, ServiceHandle) {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let service = Self::with_receiver(config, EventEmitter::new(Arc::new(sink)), rx);
+        (service, ServiceHandle::new(tx))
+    }
+
+    // Service reading from a channel owned by someone else, e.g. the supervisor
+    pub(crate) fn with_receiver(
+        config: ConfigStore,
+        events: EventEmitter,
+        rx: mpsc::Receiver<Request>,
+    ) -> Self {
+        Self {
+            rx,
+            events,
+            config,
+            registry: None,
+            wallets: HashMap::new(),
+            jobs: JoinSet::new(),
+            syncs: HashMap::new(),
+            restore_job: None,
+            broadcast_job: None,
+            queued_sends: VecDeque::new(),
+            fee_estimates: None,
+            fee_job: None,
+            key_jobs: JoinSet::new(),
+            key_waiters: HashMap::new(),
+            heartbeat_count: 0,
+        }
+    }
+
+    /// Process messages until `AppMessage::Shutdown` is received or every
+    /// sender has been dropped.
+    pub async fn run(mut self) {
+        println!("Background task started");
+        self.migrate_legacy_db();
+
+        // Load the active wallet up front so the first request doesn't pay
+        // for it. No wallet is fine here; the user creates one with
+        // `CreateWallet`.
+        let loaded = self
+            .target(None)
+            .and_then(|id| load_wallet(&mut self.wallets, &mut self.registry, &self.config, id));
+        match loaded {
+            Ok(loaded) => println!("Wallet {} loaded", loaded.id),
+            Err(e) => println!("Wallet not loaded at startup: {}", e),
+        }
+
+        loop {
+            tokio::select! {
+                request = self.rx.recv() => {
+                    let Some(request) = request else {
+                        println!("All senders dropped");
+                        break;
+                    };
+                    if !self.handle_request(request) {
+                        break;
+                    }
+                }
+                Some(joined) = self.jobs.join_next_with_id(), if !self.jobs.is_empty() => {
+                    self.handle_job(joined);
+                }
+                Some(joined) = self.key_jobs.join_next_with_id(), if !self.key_jobs.is_empty() => {
+                    self.handle_key_job(joined);
+                }
+                _ = sleep(HEARTBEAT_INTERVAL) => {
+                    self.lock_idle_wallets();
+
+                    // Increment heartbeat counter
+                    self.heartbeat_count += 1;
+                    println!("Background task heartbeat: {}", self.heartbeat_count);
+
+                    // Send heartbeat event with counter value
+                    self.events.emit(Heartbeat {
+                        count: self.heartbeat_count,
+                    });
+                }
+            }
+        }
+
+        self.abort_jobs();
+        println!("Background task ended");
+    }
+
+    // Move a database left in the working directory by older builds, or at
+    // the path named by the deprecated `db_path` setting, into the data dir
+    fn migrate_legacy_db(&mut self) {
+        let legacy_paths = self
+            .config
+            .config()
+            .db_path
+            .iter()
+            .cloned()
+            .chain([LEGACY_DB_PATH.into()]);
+
+        for legacy in legacy_paths {
+            if let Err(e) = storage::migrate_legacy_db(&legacy, &self.config) {
+                println!("Failed to migrate {}: {}", legacy.display(), e);
+            }
+        }
+        if let Err(e) = storage::migrate_legacy_keys(&self.config) {
+            println!("Failed to migrate wallet keys: {}", e);
+        }
+
+        // Wallets from before the registry, one per network directory
+        let migrated = load_registry(&mut self.registry, &self.config)
+            .and_then(|registry| storage::migrate_network_dirs(&self.config, registry));
+        if let Err(e) = migrated {
+            println!("Failed to migrate wallets to the registry: {}", e);
+        }
+    }
+
+    // Returns false when the loop should stop
+    fn handle_request(&mut self, request: Request) -> bool {
+        let Request {
+            id,
+            wallet,
+            message,
+            reply,
+        } = request;
+        println!("Handling request {}", id);
+
+        let keep_running = !matches!(message, AppMessage::Shutdown);
+        // Resolved for every request so events name the wallet they are
+        // about; only wallet operations fail when there is none
+        let target = self.target(wallet);
+        let mut events = self.events.for_request(id);
+        // A busy loop never reaches the timer, so a wallet that has been
+        // idle too long is locked before the request can use its keys
+        self.lock_idle_wallets();
+        if let Ok(wallet_id) = target {
+            events = events.for_wallet(wallet_id);
+            if let Some(loaded) = self
+                .wallets
+                .get_mut(&wallet_id)
+                .filter(|_| message.uses_wallet())
+            {
+                loaded.last_used = Instant::now();
+            }
+        }
+        let responder = Responder { events, reply };
+
+        match message {
+            AppMessage::SyncWallet => {
+                println!("Syncing wallet");
+                self.start_sync(responder, target);
+            }
+            AppMessage::GetFeeEstimates => {
+                println!("Getting fee estimates");
+                self.start_fee_estimates(responder);
+            }
+            AppMessage::SendTransaction {
+                address,
+                amount,
+                fee,
+            } => {
+                println!("Sending {} sats to {} at {:?}", amount, address.trim(), fee);
+                let request = SendRequest::Payment {
+                    address,
+                    amount,
+                    fee,
+                };
+                self.start_send(responder, target, request);
+            }
+            AppMessage::SendBatch { recipients, fee } => {
+                println!("Sending to {} recipients at {:?}", recipients.len(), fee);
+                let request = SendRequest::Batch { recipients, fee };
+                self.start_send(responder, target, request);
+            }
+            AppMessage::SendAll {
+                address,
+                fee,
+                confirmed_only,
+                utxos,
+            } => {
+                println!("Sending all to {} at {:?}", address.trim(), fee);
+                let request = SendRequest::Drain {
+                    address,
+                    fee,
+                    confirmed_only,
+                    utxos,
+                };
+                self.start_send(responder, target, request);
+            }
+            AppMessage::CombinePsbt { psbts } => {
+                println!("Combining {} PSBTs", psbts.len());
+                self.start_send(responder, target, SendRequest::Psbts(psbts));
+            }
+            AppMessage::RestoreWallet {
+                mnemonic,
+                passphrase,
+                password,
+                script_types,
+                name,
+            } => {
+                println!("Restoring wallet");
+                self.start_restore(
+                    responder,
+                    &mnemonic,
+                    passphrase.as_deref(),
+                    password,
+                    script_types,
+                    name,
+                );
+            }
+            AppMessage::CreateWallet {
+                word_count,
+                passphrase,
+                password,
+                name,
+                script_type,
+            } => {
+                println!("Creating {:?} wallet", script_type);
+                let work = self.create_wallet(
+                    word_count,
+                    passphrase.as_deref(),
+                    password,
+                    name,
+                    script_type,
+                );
+                self.start_key_job(responder, work);
+            }
+            AppMessage::ExportBackup {
+                password,
+                backup_password,
+            } => {
+                println!("Exporting wallet backup");
+                let work = target.and_then(|id| self.export_backup(id, password, backup_password));
+                self.start_key_job(responder, work);
+            }
+            AppMessage::ImportBackup {
+                backup,
+                backup_password,
+                password,
+                name,
+                restore_settings,
+            } => {
+                println!("Importing wallet backup");
+                let work =
+                    self.import_backup(backup, backup_password, password, name, restore_settings);
+                self.start_key_job(responder, work);
+            }
+            AppMessage::Unlock { password } => {
+                println!("Unlocking wallet");
+                let work = target.and_then(|id| self.unlock(id, password));
+                self.start_key_job(responder, work);
+            }
+            AppMessage::AddAccount {
+                password,
+                passphrase,
+                script_type,
+                account,
+                name,
+            } => {
+                println!("Adding account");
+                let work = target.and_then(|parent_id| {
+                    self.add_account(parent_id, password, passphrase, script_type, account, name)
+                });
+                self.start_key_job(responder, work);
+            }
+            AppMessage::CreateMultisig {
+                threshold,
+                cosigners,
+                mnemonic,
+                passphrase,
+                password,
+                name,
+            } => {
+                println!("Creating {}-of-{} multisig", threshold, cosigners.len() + 1);
+                let work = self.create_multisig(
+                    threshold,
+                    &cosigners,
+                    mnemonic.as_deref(),
+                    passphrase.as_deref(),
+                    password,
+                    name,
+                );
+                self.start_key_job(responder, work);
+            }
+            message => {
+                let result = self.handle_message(&responder.events, target, message);
+                responder.respond(result);
+            }
+        }
+        keep_running
+    }
+
+    // Requests that are answered without spawning a job
+    fn handle_message(
+        &mut self,
+        events: &EventEmitter,
+        target: Result<WalletId, WalletError>,
+        message: AppMessage,
+    ) -> Result<AppResponse, WalletError> {
+        match message {
+            AppMessage::Ping => {
+                println!("Ping received!");
+                events.emit(Pong {
+                    message: "pong".to_string(),
+                });
+                Ok(AppResponse::Pong)
+            }
+            AppMessage::UpdateData(data) => {
+                println!("Data update: {}", data);
+                events.emit(DataUpdated { data: data.clone() });
+                Ok(AppResponse::DataUpdated(data))
+            }
+            AppMessage::Shutdown => {
+                println!("Shutting down background task");
+                Ok(AppResponse::ShuttingDown)
+            }
+            // Only reached when the service runs without a supervisor, in
+            // which case it is running by definition
+            AppMessage::Start | AppMessage::Status => {
+                Ok(AppResponse::Status(ServiceStatus::running()))
+            }
+            AppMessage::Restart => Err(WalletError::ServiceUnavailable {
+                message: "Restarting needs a supervised background task".to_string(),
+            }),
+            AppMessage::ListWallets => Ok(AppResponse::Wallets(self.wallet_list()?)),
+            AppMessage::SelectWallet { id } => {
+                println!("Selecting wallet {}", id);
+                load_registry(&mut self.registry, &self.config)?.select(id)?;
+                self.wallets_updated(events)
+            }
+            AppMessage::RenameWallet { id, name } => {
+                println!("Renaming wallet {}", id);
+                load_registry(&mut self.registry, &self.config)?.rename(id, &name)?;
+                self.wallets_updated(events)
+            }
+            AppMessage::DeleteWallet { id } => {
+                println!("Deleting wallet {}", id);
+                self.delete_wallet(events, id)
+            }
+            AppMessage::ImportWatchOnly {
+                descriptor,
+                change_descriptor,
+                name,
+            } => {
+                println!("Importing watch-only wallet");
+                self.import_watch_only(events, &descriptor, change_descriptor.as_deref(), name)
+            }
+            AppMessage::Lock => {
+                println!("Locking wallet");
+                self.lock(events, target?)
+            }
+            AppMessage::GetWalletAddress => {
+                println!("Getting wallet address");
+                self.get_wallet_address(events, target?)
+            }
+            AppMessage::GetWalletBalance => {
+                println!("Getting wallet balance");
+                self.get_wallet_balance(events, target?)
+            }
+            AppMessage::GetUtxos => {
+                println!("Listing unspent outputs");
+                self.get_utxos(events, target?)
+            }
+            AppMessage::GetLabels => {
+                println!("Getting labels");
+                let labels = LabelList {
+                    labels: labels::load(&self.config.labels_path_for(target?))?,
+                };
+                events.emit(labels.clone());
+                Ok(AppResponse::Labels(labels))
+            }
+            AppMessage::SetLabel(label) => {
+                println!("Setting label");
+                self.set_label(events, target?, label)
+            }
+            AppMessage::CancelSync => {
+                println!("Cancelling sync");
+                let synced = target.is_ok_and(|id| self.cancel_sync(id));
+                let restored = self.cancel_restore();
+                Ok(AppResponse::SyncCancelled(synced || restored))
+            }
+            AppMessage::SignMessage { address, message } => {
+                println!("Signing message");
+                self.sign_message(events, target?, &address, &message)
+            }
+            AppMessage::VerifyMessage {
+                address,
+                message,
+                signature,
+            } => {
+                println!("Verifying message");
+                let network = self.config.config().network;
+                let address = parse_address(&address, network)?;
+                let (valid, format) = message::verify(&address, &message, &signature)?;
+                let verification = MessageVerification {
+                    address: address.to_string(),
+                    valid,
+                    format,
+                };
+                events.emit(verification.clone());
+                Ok(AppResponse::MessageVerified(verification))
+            }
+            AppMessage::GetConfig => Ok(AppResponse::Config(self.config.config().clone())),
+            AppMessage::UpdateConfig(config) => {
+                println!("Updating configuration");
+                self.update_config(events, config)
+            }
+            AppMessage::SyncWallet
+            | AppMessage::GetFeeEstimates
+            | AppMessage::SendTransaction { .. }
+            | AppMessage::SendAll { .. }
+            | AppMessage::SendBatch { .. }
+            | AppMessage::CombinePsbt { .. }
+            | AppMessage::RestoreWallet { .. }
+            | AppMessage::CreateWallet { .. }
+            | AppMessage::AddAccount { .. }
+            | AppMessage::CreateMultisig { .. }
+            | AppMessage::ExportBackup { .. }
+            | AppMessage::ImportBackup { .. }
+            | AppMessage::Unlock { .. } => {
+                unreachable!("long-running requests are dispatched in handle_request")
+            }
+        }
+    }
+
+    fn handle_job(&mut self, joined: Result<(task::Id, JobOutcome), JoinError>) {
+        match joined {
+            Ok((id, JobOutcome::Synced { db_path, result })) => {
+                // A scan that finished just as it was cancelled is discarded
+                if let Some(job) = self.take_sync_job(id) {
+                    self.finish_sync(job.waiters, db_path, result);
+                }
+            }
+            Ok((id, JobOutcome::Recovered { result })) => {
+                if self.is_restore_job(id) {
+                    if let Some(job) = self.restore_job.take() {
+                        self.finish_restore(job, result);
+                    }
+                }
+            }
+            Ok((
+                _,
+                JobOutcome::Broadcast {
+                    db_path,
+                    tx,
+                    fee,
+                    fee_saved,
+                    result,
+                },
+            )) => {
+                if let Some((_, _, responder)) = self.broadcast_job.take() {
+                    self.finish_send(responder, db_path, tx, fee, fee_saved, result);
+                }
+                self.start_next_send();
+            }
+            Ok((
+                _,
+                JobOutcome::FeeEstimates {
+                    esplora_url,
+                    result,
+                },
+            )) => {
+                if let Some(job) = self.fee_job.take() {
+                    self.finish_fee_estimates(job.waiters, esplora_url, result);
+                }
+            }
+            // Cancelled syncs have already answered their waiters
+            Err(e)
+                if e.is_cancelled()
+                    && !self.is_sync_job(e.id())
+                    && !self.is_restore_job(e.id())
+                    && !self.is_fee_job(e.id()) =>
+            {
+                println!("Background job {} cancelled", e.id());
+            }
+            Err(e) => {
+                // The job panicked or was aborted; fail whoever was waiting on it
+                println!("Background job failed: {}", e);
+                let error = WalletError::JobFailed {
+                    message: e.to_string(),
+                };
+                if let Some(job) = self.take_sync_job(e.id()) {
+                    for responder in job.waiters {
+                        responder.respond(Err(error.clone()));
+                    }
+                }
+                if self.is_restore_job(e.id()) {
+                    if let Some(job) = self.restore_job.take() {
+                        job.responder.respond(Err(error.clone()));
+                    }
+                }
+                if self.is_fee_job(e.id()) {
+                    for waiter in self.fee_job.take().into_iter().flat_map(|job| job.waiters) {
+                        waiter.responder().respond(Err(error.clone()));
+                    }
+                }
+                if matches!(&self.broadcast_job, Some((id, _, _)) if *id == e.id()) {
+                    if let Some((_, _, responder)) = self.broadcast_job.take() {
+                        responder.respond(Err(error));
+                    }
+                    self.start_next_send();
+                }
+            }
+        }
+    }
+
+    // Run the slow part of a request on the blocking pool and answer the
+    // requester once it has been finished with
+    fn start_key_job(&mut self, responder: Responder, work: Result<KeyWork, WalletError>) {
+        match work {
+            Ok(work) => {
+                let handle = self.key_jobs.spawn_blocking(work);
+                self.key_waiters.insert(handle.id(), responder);
+            }
+            Err(e) => responder.respond(Err(e)),
+        }
+    }
+
+    fn handle_key_job(&mut self, joined: Result<(task::Id, KeyFinish), JoinError>) {
+        let (id, finish) = match joined {
+            Ok((id, finish)) => (id, Ok(finish)),
+            Err(e) => (e.id(), Err(e)),
+        };
+        let Some(responder) = self.key_waiters.remove(&id) else {
+            return;
+        };
+        match finish {
+            Ok(finish) => finish(self, responder),
+            Err(e) => {
+                println!("Key job failed: {}", e);
+                responder.respond(Err(WalletError::JobFailed {
+                    message: e.to_string(),
+                }));
+            }
+        }
+    }
+
+    // Stop running jobs and tell everyone waiting on them
+    fn abort_jobs(&mut self) {
+        self.jobs.abort_all();
+        // Hashing that already started runs to the end, but nobody is
+        // answered with its result
+        self.key_jobs.abort_all();
+        let error = WalletError::ServiceUnavailable {
+            message: "Background task stopped".to_string(),
+        };
+        let waiting = self
+            .syncs
+            .drain()
+            .flat_map(|(_, job)| job.waiters)
+            .chain(self.restore_job.take().map(|job| job.responder))
+            .chain(self.broadcast_job.take().map(|(_, _, responder)| responder))
+            .chain(
+                self.fee_job
+                    .take()
+                    .into_iter()
+                    .flat_map(|job| job.waiters)
+                    .map(FeeWaiter::responder),
+            )
+            .chain(
+                self.queued_sends
+                    .drain(..)
+                    .map(|(responder, _, _)| responder),
+            )
+            .chain(self.key_waiters.drain().map(|(_, responder)| responder));
+        for responder in waiting {
+            responder.respond(Err(error.clone()));
+        }
+    }
+
+    // The wallet a request is for: the one it names, or the active one
+    fn target(&mut self, wallet: Option<WalletId>) -> Result<WalletId, WalletError> {
+        let registry = load_registry(&mut self.registry, &self.config)?;
+        match wallet {
+            Some(id) => registry.get(id).map(|entry| entry.id),
+            None => registry.active().ok_or(WalletError::WalletNotFound),
+        }
+    }
+
+    fn wallet_list(&mut self) -> Result<WalletList, WalletError> {
+        let registry = load_registry(&mut self.registry, &self.config)?;
+        Ok(WalletList {
+            active: registry.active(),
+            wallets: registry.wallets().to_vec(),
+        })
+    }
+
+    // Tell listeners the registry changed and answer with the new list
+    fn wallets_updated(&mut self, events: &EventEmitter) -> Result<AppResponse, WalletError> {
+        let list = self.wallet_list()?;
+        events.emit(list.clone());
+        Ok(AppResponse::Wallets(list))
+    }
+
+    // The name a new wallet gets, checked against the registry before any
+    // slow work is done for it
+    fn new_wallet_name(
+        &mut self,
+        name: Option<String>,
+        file: &KeystoreFile,
+    ) -> Result<String, WalletError> {
+        let registry = load_registry(&mut self.registry, &self.config)?;
+        let name = name.unwrap_or_else(|| registry.default_name(file.network));
+        registry.check_new(&name, file)?;
+        Ok(name)
+    }
+
+    // Write a new wallet's files into the next free wallet directory, apply
+    // its first scan if there is one, and register it as the active wallet.
+    // Nothing is left on disk if any step fails.
+    fn add_wallet(
+        &mut self,
+        events: &EventEmitter,
+        name: &str,
+        file: &KeystoreFile,
+        external: String,
+        internal: Option<String>,
+        update: Option<FullScanResponse<KeychainKind>>,
+    ) -> Result<WalletEntry, WalletError> {
+        let registry = load_registry(&mut self.registry, &self.config)?;
+        registry.check_new(name, file)?;
+        let id = registry.next_id();
+
+        let mut loaded = write_wallet_files(&self.config, id, file, external, internal)?;
+        let added = match update {
+            Some(update) => apply_update(&mut loaded, update),
+            None => Ok(()),
+        }
+        .and_then(|()| registry.add(name, file));
+        let entry = match added {
+            Ok(entry) => entry,
+            Err(e) => {
+                drop(loaded);
+                remove_wallet_files(&self.config, id);
+                return Err(e);
+            }
+        };
+
+        self.wallets.insert(entry.id, loaded);
+        self.wallets_updated(events)?;
+        Ok(entry)
+    }
+
+    fn delete_wallet(
+        &mut self,
+        events: &EventEmitter,
+        id: WalletId,
+    ) -> Result<AppResponse, WalletError> {
+        load_registry(&mut self.registry, &self.config)?.get(id)?;
+        if self.is_sending(id) {
+            return Err(WalletError::WalletBusy);
+        }
+        load_registry(&mut self.registry, &self.config)?.remove(id)?;
+        self.forget_wallet(id);
+        self.wallets_updated(events)
+    }
+
+    // The broadcast result still has to be recorded in the wallet's database
+    fn is_sending(&self, id: WalletId) -> bool {
+        matches!(&self.broadcast_job, Some((_, wallet, _)) if *wallet == id)
+            || self.queued_sends.iter().any(|(_, wallet, _)| *wallet == id)
+    }
+
+    // Stop everything running for a wallet the registry no longer lists and
+    // remove its files
+    fn forget_wallet(&mut self, id: WalletId) {
+        self.cancel_sync(id);
+        if self.is_restoring(id) {
+            self.cancel_restore();
+        }
+        self.wallets.remove(&id);
+        // A leftover directory is harmless since ids are never reused
+        let dir = self.config.wallet_dir(id);
+        if let Err(e) = std::fs::remove_dir_all(&dir) {
+            println!("Failed to remove {}: {}", dir.display(), e);
+        }
+    }
+
+    fn create_wallet(
+        &mut self,
+        word_count: usize,
+        passphrase: Option<&str>,
+        password: String,
+        name: Option<String>,
+        script_type: ScriptType,
+    ) -> Result<KeyWork, WalletError> {
+        let network = self.config.config().network;
+        check_password(&password)?;
+
+        let keystore = Keystore::generate(word_count, passphrase, network, script_type)?;
+        let phrase = keystore
+            .recovery_phrase()
+            .expect("generated from a mnemonic");
+        let name = self.new_wallet_name(name, &KeystoreFile::unencrypted(&keystore))?;
+
+        Ok(key_work(
+            move || Ok((keystore.seal(&password)?, keystore)),
+            move |service, events, (file, keystore)| {
+                let entry = service.add_wallet(
+                    events,
+                    &name,
+                    &file,
+                    keystore.external.clone(),
+                    Some(keystore.internal.clone()),
+                    None,
+                )?;
+
+                events.for_wallet(entry.id).emit(WalletCreated {
+                    new_wallet_id: entry.id,
+                    network,
+                    fingerprint: keystore.fingerprint,
+                    script_type,
+                });
+                Ok(AppResponse::WalletCreated {
+                    wallet_id: entry.id,
+                    phrase,
+                })
+            },
+        ))
+    }
+
+    fn add_account(
+        &mut self,
+        parent_id: WalletId,
+        password: String,
+        passphrase: Option<String>,
+        script_type: Option<ScriptType>,
+        account: Option<u32>,
+        name: Option<String>,
+    ) -> Result<KeyWork, WalletError> {
+        let registry = load_registry(&mut self.registry, &self.config)?;
+        let parent = registry.get(parent_id)?.clone();
+        if parent.watch_only {
+            return Err(WalletError::WatchOnly);
+        }
+        let script_type = script_type.unwrap_or(parent.script_type);
+        // from_mnemonic refuses these too, but only once the password is hashed
+        if script_type == ScriptType::Bip48 {
+            return Err(WalletError::InvalidArgument {
+                field: "script_type".to_string(),
+                message: "multisig wallets are created with CreateMultisig".to_string(),
+            });
+        }
+        let account = account.unwrap_or_else(|| registry.next_account(&parent, script_type));
+
+        // Named after the first wallet of the seed, whichever account it
+        // was added from
+        let seed_name = registry
+            .wallets()
+            .iter()
+            .find(|entry| {
+                entry.network == parent.network
+                    && entry.fingerprint == parent.fingerprint
+                    && !entry.watch_only
+            })
+            .map_or(parent.name.clone(), |entry| entry.name.clone());
+        let name = name.unwrap_or_else(|| format!("{} account {}", seed_name, account));
+        // Checked again when the wallet is added, but an existing account
+        // shouldn't cost a round of password hashing first
+        registry.check_name(&name, None)?;
+        let exists = registry.wallets().iter().any(|entry| {
+            entry.network == parent.network
+                && entry.fingerprint == parent.fingerprint
+                && entry.script_type == script_type
+                && entry.account == account
+                && !entry.watch_only
+        });
+        if exists {
+            return Err(WalletError::WalletAlreadyExists);
+        }
+        let keystore_path = self.config.keystore_path_for(parent_id);
+
+        let open = move || {
+            let words = KeystoreFile::load(&keystore_path)?
+                .ok_or(WalletError::WalletNotFound)?
+                .open(&password)?
+                .mnemonic
+                .ok_or_else(|| WalletError::InvalidArgument {
+                    field: "wallet".to_string(),
+                    message: "has no recovery words to derive accounts from".to_string(),
+                })?;
+            let keystore = Keystore::from_mnemonic(
+                &keystore::parse_mnemonic(&words)?,
+                passphrase.as_deref(),
+                parent.network,
+                script_type,
+                account,
+            )?;
+            if keystore.fingerprint != parent.fingerprint {
+                return Err(WalletError::InvalidArgument {
+                    field: "passphrase".to_string(),
+                    message: "does not match the one the wallet was created with".to_string(),
+                });
+            }
+            Ok((keystore.seal(&password)?, keystore))
+        };
+
+        Ok(key_work(open, move |service, events, (file, keystore)| {
+            let name = service.new_wallet_name(Some(name), &file)?;
+            let entry = service.add_wallet(
+                events,
+                &name,
+                &file,
+                keystore.external.clone(),
+                Some(keystore.internal.clone()),
+                None,
+            )?;
+
+            let added = AccountAdded {
+                new_wallet_id: entry.id,
+                parent_id,
+                network: parent.network,
+                fingerprint: keystore.fingerprint,
+                script_type,
+                account,
+            };
+            events.for_wallet(entry.id).emit(added.clone());
+            Ok(AppResponse::AccountAdded(added))
+        }))
+    }
+
+    fn create_multisig(
+        &mut self,
+        threshold: usize,
+        cosigners: &[String],
+        mnemonic: Option<&str>,
+        passphrase: Option<&str>,
+        password: String,
+        name: Option<String>,
+    ) -> Result<KeyWork, WalletError> {
+        let network = self.config.config().network;
+        check_password(&password)?;
+        if cosigners.is_empty() {
+            return Err(WalletError::InvalidArgument {
+                field: "cosigners".to_string(),
+                message: "at least one cosigner is needed".to_string(),
+            });
+        }
+        let cosigners = cosigners
+            .iter()
+            .map(|cosigner| descriptors::parse_cosigner(cosigner, network))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let generated = mnemonic.is_none();
+        let mnemonic = match mnemonic {
+            Some(words) => keystore::parse_mnemonic(words)?,
+            None => keystore::generate_mnemonic(12)?,
+        };
+        let (keystore, key) =
+            Keystore::multisig(&mnemonic, passphrase, network, threshold, &cosigners)?;
+        let name = self.new_wallet_name(name, &KeystoreFile::unencrypted(&keystore))?;
+        let signers = cosigners.len() as u32 + 1;
+
+        Ok(key_work(
+            move || Ok((keystore.seal(&password)?, keystore)),
+            move |service, events, (file, keystore)| {
+                let entry = service.add_wallet(
+                    events,
+                    &name,
+                    &file,
+                    keystore.external.clone(),
+                    Some(keystore.internal.clone()),
+                    None,
+                )?;
+
+                let created = MultisigCreated {
+                    new_wallet_id: entry.id,
+                    network,
+                    fingerprint: keystore.fingerprint.clone(),
+                    threshold: threshold as u32,
+                    signers,
+                    key,
+                };
+                events.for_wallet(entry.id).emit(created.clone());
+                Ok(AppResponse::MultisigCreated {
+                    wallet: created,
+                    phrase: keystore.recovery_phrase().filter(|_| generated),
+                })
+            },
+        ))
+    }
+
+    fn import_watch_only(
+        &mut self,
+        events: &EventEmitter,
+        descriptor: &str,
+        change_descriptor: Option<&str>,
+        name: Option<String>,
+    ) -> Result<AppResponse, WalletError> {
+        let imported =
+            WatchOnly::parse(descriptor, change_descriptor, self.config.config().network)?;
+        let file = KeystoreFile::watch_only(&imported);
+        let name = self.new_wallet_name(name, &file)?;
+        let entry = self.add_wallet(
+            events,
+            &name,
+            &file,
+            imported.external.clone(),
+            imported.internal.clone(),
+            None,
+        )?;
+
+        let info = WalletImported {
+            new_wallet_id: entry.id,
+            network: imported.network,
+            fingerprint: imported.fingerprint,
+            script_type: imported.script_type,
+        };
+        events.for_wallet(entry.id).emit(info.clone());
+        Ok(AppResponse::WalletImported(info))
+    }
+
+    fn export_backup(
+        &mut self,
+        id: WalletId,
+        password: Option<String>,
+        backup_password: Option<String>,
+    ) -> Result<KeyWork, WalletError> {
+        if let Some(backup_password) = &backup_password {
+            check_password(backup_password)?;
+        }
+        let entry = load_registry(&mut self.registry, &self.config)?
+            .get(id)?
+            .clone();
+        let keystore_path = self.config.keystore_path_for(id);
+        let labels = labels::load(&self.config.labels_path_for(id))?;
+        let settings = BackupSettings::from(self.config.config());
+        let loaded = load_wallet(&mut self.wallets, &mut self.registry, &self.config, id)?;
+        let birthday = Birthday::of(&loaded.wallet, entry.created_at);
+        let (external, internal) = backup::public_descriptors(&loaded.wallet);
+        let password = password.filter(|_| !loaded.watch_only);
+
+        let export = move || {
+            let wallet = match password {
+                Some(password) => {
+                    let keystore = KeystoreFile::load(&keystore_path)?
+                        .ok_or(WalletError::WalletNotFound)?
+                        .open(&password)?;
+                    WalletBackup {
+                        name: entry.name,
+                        network: keystore.network,
+                        script_type: keystore.script_type,
+                        fingerprint: keystore.fingerprint,
+                        account: keystore.account,
+                        watch_only: false,
+                        external: keystore.external,
+                        internal: Some(keystore.internal),
+                        mnemonic: keystore.mnemonic,
+                        has_passphrase: keystore.has_passphrase,
+                        birthday,
+                        labels,
+                    }
+                }
+                None => {
+                    // As the import will see them
+                    let public = WatchOnly::parse(&external, internal.as_deref(), entry.network)?;
+                    WalletBackup {
+                        name: entry.name,
+                        network: public.network,
+                        script_type: public.script_type,
+                        fingerprint: public.fingerprint,
+                        account: public.account,
+                        watch_only: true,
+                        external,
+                        internal,
+                        mnemonic: None,
+                        has_passphrase: false,
+                        birthday,
+                        labels,
+                    }
+                }
+            };
+
+            let watch_only = wallet.watch_only;
+            let document = Backup { wallet, settings }.export(backup_password.as_deref())?;
+            // Only hand out what can be read back
+            Backup::open(&document, backup_password.as_deref())?;
+            Ok((document, watch_only, backup_password.is_some()))
+        };
+
+        Ok(key_work(
+            export,
+            move |_, events, (document, watch_only, encrypted)| {
+                events.for_wallet(id).emit(BackupExported {
+                    watch_only,
+                    encrypted,
+                });
+                Ok(AppResponse::BackupExported(document))
+            },
+        ))
+    }
+
+    fn import_backup(
+        &mut self,
+        document: String,
+        backup_password: Option<String>,
+        password: Option<String>,
+        name: Option<String>,
+        restore_settings: bool,
+    ) -> Result<KeyWork, WalletError> {
+        let open = move || {
+            let backup = Backup::open(&document, backup_password.as_deref())?;
+            let password = password.filter(|_| !backup.wallet.watch_only);
+            if let Some(password) = &password {
+                check_password(password)?;
+            }
+            let file = backup.wallet.keystore_file(password.as_deref())?;
+            Ok((backup, file))
+        };
+
+        Ok(key_work(open, move |service, events, (backup, file)| {
+            let Backup { wallet, settings } = backup;
+            let name = service.new_wallet_name(Some(name.unwrap_or(wallet.name.clone())), &file)?;
+            // Checked before the wallet is added, so bad settings don't leave
+            // half an import behind. What the environment sets stays as it is.
+            let settings = restore_settings
+                .then(|| {
+                    let config = settings.restore(service.config.config())?;
+                    service.config.keep_overrides(config)
+                })
+                .transpose()?;
+
+            let entry = service.add_wallet(
+                events,
+                &name,
+                &file,
+                wallet.external.clone(),
+                wallet.internal.clone(),
+                None,
+            )?;
+            load_registry(&mut service.registry, &service.config)?
+                .backdate(entry.id, wallet.birthday.time)?;
+            if !wallet.labels.is_empty() {
+                labels::save(&service.config.labels_path_for(entry.id), &wallet.labels)?;
+            }
+            if let Some(settings) = settings.clone() {
+                service.update_config(events, settings)?;
+            }
+
+            let imported = BackupImported {
+                new_wallet_id: entry.id,
+                network: wallet.network,
+                fingerprint: wallet.fingerprint,
+                script_type: wallet.script_type,
+                watch_only: wallet.watch_only,
+                birthday: wallet.birthday,
+                labels: wallet.labels.len(),
+                settings_restored: settings.is_some(),
+            };
+            events.for_wallet(entry.id).emit(imported.clone());
+            Ok(AppResponse::BackupImported(imported))
+        }))
+    }
+
+    fn unlock(&mut self, id: WalletId, password: String) -> Result<KeyWork, WalletError> {
+        let keystore_path = self.config.keystore_path_for(id);
+        load_wallet(&mut self.wallets, &mut self.registry, &self.config, id)?;
+
+        let open = move || {
+            let file = KeystoreFile::load(&keystore_path)?.ok_or(WalletError::WalletNotFound)?;
+            let keystore = file.open(&password)?;
+
+            // Keystores written before encryption take the first password
+            // they are unlocked with
+            if !file.is_encrypted() {
+                check_password(&password)?;
+                keystore.seal(&password)?.save(&keystore_path)?;
+                println!("Encrypted {}", keystore_path.display());
+            }
+            Ok(keystore)
+        };
+
+        Ok(key_work(open, move |service, events, keystore| {
+            load_wallet(
+                &mut service.wallets,
+                &mut service.registry,
+                &service.config,
+                id,
+            )?
+            .reload(Some(&keystore))?;
+            let auto_lock_secs = service.config.config().auto_lock_secs;
+            events.emit(WalletUnlocked {
+                auto_lock_secs: (auto_lock_secs > 0).then_some(auto_lock_secs),
+            });
+            Ok(AppResponse::Unlocked)
+        }))
+    }
+
+    fn lock(&mut self, events: &EventEmitter, id: WalletId) -> Result<AppResponse, WalletError> {
+        // Nothing to tell anyone if the keys weren't loaded
+        let Some(loaded) = self.wallets.get_mut(&id).filter(|loaded| loaded.unlocked) else {
+            return Ok(AppResponse::Locked);
+        };
+        loaded.reload(None)?;
+        events.emit(WalletLocked { idle: false });
+        Ok(AppResponse::Locked)
+    }
+
+    // Drop the keys of wallets nobody asked for in `auto_lock_secs`
+    fn lock_idle_wallets(&mut self) {
+        let timeout = self.config.config().auto_lock_secs;
+        if timeout == 0 {
+            return;
+        }
+        let timeout = Duration::from_secs(timeout);
+        for loaded in self.wallets.values_mut() {
+            if !loaded.unlocked || loaded.last_used.elapsed() < timeout {
+                continue;
+            }
+            println!("Wallet {} idle, locking it", loaded.id);
+            if let Err(e) = loaded.reload(None) {
+                println!("Failed to lock wallet {}: {}", loaded.id, e);
+                continue;
+            }
+            self.events
+                .for_wallet(loaded.id)
+                .emit(WalletLocked { idle: true });
+        }
+    }
+
+    fn get_wallet_address(
+        &mut self,
+        events: &EventEmitter,
+        id: WalletId,
+    ) -> Result<AppResponse, WalletError> {
+        let loaded = load_wallet(&mut self.wallets, &mut self.registry, &self.config, id)?;
+
+        // Get the next unused address
+        let address = loaded.wallet.next_unused_address(KeychainKind::External);
+        loaded.persist()?;
+
+        // Send the address to the frontend
+        let address_info = AddressInfo::new(&loaded.wallet, loaded.script_type, &address);
+        events.emit(address_info.clone());
+        Ok(AppResponse::WalletAddress(address_info))
+    }
+
+    fn sign_message(
+        &mut self,
+        events: &EventEmitter,
+        id: WalletId,
+        address: &str,
+        message: &str,
+    ) -> Result<AppResponse, WalletError> {
+        let loaded = load_wallet(&mut self.wallets, &mut self.registry, &self.config, id)?;
+        if loaded.watch_only {
+            return Err(WalletError::WatchOnly);
+        }
+        if !loaded.unlocked {
+            return Err(WalletError::WalletLocked);
+        }
+        let address = parse_address(address, loaded.wallet.network())?;
+        let (signature, format) = message::sign(&loaded.wallet, &address, message)?;
+
+        let signed = SignedMessage {
+            address: address.to_string(),
+            message: message.to_string(),
+            signature,
+            format,
+        };
+        events.emit(signed.clone());
+        Ok(AppResponse::MessageSigned(signed))
+    }
+
+    fn start_sync(&mut self, responder: Responder, target: Result<WalletId, WalletError>) {
+        let id = match target {
+            Ok(id) => id,
+            Err(e) => return responder.respond(Err(e)),
+        };
+        if let Some(job) = self.syncs.get_mut(&id) {
+            println!("Sync already running, waiting for it");
+            job.waiters.push(responder);
+            return;
+        }
+        if self.is_restoring(id) {
+            return responder.respond(Err(WalletError::RestoreInProgress));
+        }
+
+        match self.spawn_sync(&responder.events, id) {
+            Ok(handle) => {
+                let job = SyncJob {
+                    handle,
+                    waiters: vec![responder],
+                };
+                self.syncs.insert(id, job);
+            }
+            Err(e) => responder.respond(Err(e)),
+        }
+    }
+
+    fn spawn_sync(
+        &mut self,
+        events: &EventEmitter,
+        id: WalletId,
+    ) -> Result<AbortHandle, WalletError> {
+        let config = self.config.config();
+        let recovering = load_registry(&mut self.registry, &self.config)?
+            .get(id)?
+            .recovering;
+        let loaded = load_wallet(&mut self.wallets, &mut self.registry, &self.config, id)?;
+        check_backend(&loaded.wallet, config)?;
+
+        let client = scan_client(config)?;
+        let mut settings = SyncSettings::from(config);
+        // A restore whose recovery scan never went through still has to
+        // look as far ahead as it would have
+        if recovering {
+            settings.stop_gap = config.recovery_stop_gap;
+        }
+
+        // Start sync process
+        events.emit(SyncStarted {});
+
+        let job = jobs::full_scan(
+            client,
+            loaded.db_path.clone(),
+            ScanPlan::new(&loaded.wallet),
+            settings,
+            events.clone(),
+        );
+        Ok(self.jobs.spawn(job))
+    }
+
+    // Estimates for the configured server that are recent enough to use
+    fn fresh_fee_estimates(&self) -> Option<&FeeEstimates> {
+        self.fee_estimates
+            .as_ref()
+            .filter(|(esplora_url, estimates)| {
+                *esplora_url == self.config.config().esplora_url && estimates.is_fresh(unix_time())
+            })
+            .map(|(_, estimates)| estimates)
+    }
+
+    fn start_fee_estimates(&mut self, responder: Responder) {
+        match self.fresh_fee_estimates().cloned() {
+            Some(estimates) => {
+                responder.events.emit(estimates.clone());
+                responder.respond(Ok(AppResponse::FeeEstimates(estimates)));
+            }
+            None => self.wait_for_fee_estimates(FeeWaiter::Request(responder)),
+        }
+    }
+
+    // Wait for the running fee estimate request, starting one if there is
+    // none. Only one is ever in flight.
+    fn wait_for_fee_estimates(&mut self, waiter: FeeWaiter) {
+        if self.fee_job.is_none() {
+            let config = self.config.config();
+            let client = match esplora_client::Builder::new(&config.esplora_url)
+                .timeout(config.request_timeout_secs)
+                .build_async()
+            {
+                Ok(client) => client,
+                Err(e) => return waiter.responder().respond(Err(e.into())),
+            };
+            let handle = self
+                .jobs
+                .spawn(jobs::fee_estimates(client, config.esplora_url.clone()));
+            self.fee_job = Some(FeeJob {
+                handle,
+                waiters: Vec::new(),
+            });
+        }
+        if let Some(job) = &mut self.fee_job {
+            job.waiters.push(waiter);
+        }
+    }
+
+    // Cache the estimates and serve everyone who waited for them. Both kinds
+    // of waiters start over, so estimates for a server that was replaced in
+    // the meantime are fetched again.
+    fn finish_fee_estimates(
+        &mut self,
+        waiters: Vec<FeeWaiter>,
+        esplora_url: String,
+        result: Result<HashMap<u16, f64>, WalletError>,
+    ) {
+        match result {
+            Ok(estimates) => {
+                let estimates = FeeEstimates::new(&estimates, unix_time());
+                self.fee_estimates = Some((esplora_url, estimates));
+                for waiter in waiters {
+                    match waiter {
+                        FeeWaiter::Request(responder) => self.start_fee_estimates(responder),
+                        FeeWaiter::Send(responder, id, request) => {
+                            self.start_send(responder, Ok(id), request)
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                println!("Failed to get fee estimates: {}", e);
+                for waiter in waiters {
+                    match waiter {
+                        FeeWaiter::Request(responder) => responder.respond(Err(e.clone())),
+                        // A custom rate would still go through
+                        FeeWaiter::Send(responder, _, _) => {
+                            responder.respond(Err(WalletError::FeeEstimatesUnavailable {
+                                message: e.to_string(),
+                            }))
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_fee_job(&self, id: task::Id) -> bool {
+        matches!(&self.fee_job, Some(job) if job.handle.id() == id)
+    }
+
+    fn is_sync_job(&self, id: task::Id) -> bool {
+        self.syncs.values().any(|job| job.handle.id() == id)
+    }
+
+    // Remove the scan running as task `id` along with its waiters
+    fn take_sync_job(&mut self, id: task::Id) -> Option<SyncJob> {
+        let wallet = *self.syncs.iter().find(|(_, job)| job.handle.id() == id)?.0;
+        self.syncs.remove(&wallet)
+    }
+
+    fn is_restore_job(&self, id: task::Id) -> bool {
+        matches!(&self.restore_job, Some(job) if job.handle.id() == id)
+    }
+
+    // Whether the wallet's recovery scan is running
+    fn is_restoring(&self, wallet: WalletId) -> bool {
+        matches!(&self.restore_job, Some(job) if job.wallet_id == wallet)
+    }
+
+    // Abort the wallet's running scan and fail everyone waiting on it.
+    // Returns false if no scan was running.
+    fn cancel_sync(&mut self, wallet: WalletId) -> bool {
+        let Some(job) = self.syncs.remove(&wallet) else {
+            return false;
+        };
+        job.handle.abort();
+        respond_cancelled(job.waiters);
+        true
+    }
+
+    // Abort a restore's recovery scan. The wallet stays, and finishes its
+    // recovery with the next sync.
+    fn cancel_restore(&mut self) -> bool {
+        let Some(job) = self.restore_job.take() else {
+            return false;
+        };
+        job.handle.abort();
+        respond_cancelled([job.responder]);
+        true
+    }
+
+    fn start_restore(
+        &mut self,
+        responder: Responder,
+        mnemonic: &str,
+        passphrase: Option<&str>,
+        password: String,
+        script_types: Vec<ScriptType>,
+        name: Option<String>,
+    ) {
+        let work = check_password(&password)
+            .and_then(|()| self.restore_wallet(mnemonic, passphrase, password, script_types, name));
+        self.start_key_job(responder, work);
+    }
+
+    // Save the wallet with the first candidate that can be added, then look
+    // for funds on all of them
+    fn restore_wallet(
+        &mut self,
+        mnemonic: &str,
+        passphrase: Option<&str>,
+        password: String,
+        script_types: Vec<ScriptType>,
+        name: Option<String>,
+    ) -> Result<KeyWork, WalletError> {
+        if self.restore_job.is_some() {
+            return Err(WalletError::RestoreInProgress);
+        }
+        let mnemonic = keystore::parse_mnemonic(mnemonic)?;
+        let network = self.config.config().network;
+
+        // Keep the caller's order, which decides the pick when several
+        // script types turn out to have been used
+        let mut unique = Vec::new();
+        for script_type in script_types {
+            if !unique.contains(&script_type) {
+                unique.push(script_type);
+            }
+        }
+        if unique.is_empty() {
+            unique.push(ScriptType::default());
+        }
+
+        let candidates = unique
+            .into_iter()
+            .map(|script_type| {
+                Keystore::from_mnemonic(&mnemonic, passphrase, network, script_type, 0)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let registry = load_registry(&mut self.registry, &self.config)?;
+        if let Some(name) = &name {
+            registry.check_name(name, None)?;
+        }
+        let name = name.unwrap_or_else(|| registry.default_name(network));
+        let saved = candidates
+            .iter()
+            .position(|keystore| {
+                registry
+                    .check_new(&name, &KeystoreFile::unencrypted(keystore))
+                    .is_ok()
+            })
+            .ok_or(WalletError::WalletAlreadyExists)?;
+
+        Ok(key_work_then(
+            move || Ok((candidates[saved].seal(&password)?, candidates, password)),
+            move |service, responder, (file, candidates, password)| {
+                let started = service.spawn_restore(&responder.events, &name, &file, &candidates);
+                match started {
+                    Ok((handle, wallet_id)) => {
+                        service.restore_job = Some(RestoreJob {
+                            handle,
+                            responder,
+                            wallet_id,
+                            candidates,
+                            saved,
+                            password,
+                        })
+                    }
+                    Err(e) => responder.respond(Err(e)),
+                }
+            },
+        ))
+    }
+
+    fn spawn_restore(
+        &mut self,
+        events: &EventEmitter,
+        name: &str,
+        file: &KeystoreFile,
+        candidates: &[Keystore],
+    ) -> Result<(AbortHandle, WalletId), WalletError> {
+        // Checked again, another restore may have started while this one's
+        // keystore was being sealed
+        if self.restore_job.is_some() {
+            return Err(WalletError::RestoreInProgress);
+        }
+        let plans = candidates
+            .iter()
+            .map(ScanPlan::for_keystore)
+            .collect::<Result<Vec<_>, _>>()?;
+        let keystore = candidates
+            .iter()
+            .find(|keystore| keystore.script_type == file.script_type)
+            .expect("the file was sealed from a candidate");
+
+        // Saved first, so the words don't have to be entered again when the
+        // scan fails; syncing the wallet then finishes the recovery
+        let entry = self.add_wallet(
+            events,
+            name,
+            file,
+            keystore.external.clone(),
+            Some(keystore.internal.clone()),
+            None,
+        )?;
+        load_registry(&mut self.registry, &self.config)?.set_recovering(entry.id, true)?;
+
+        let config = self.config.config();
+        let client = scan_client(config)?;
+        let events = events.for_wallet(entry.id);
+        events.emit(SyncStarted {});
+
+        // Other wallets may have handed out many more addresses than we
+        // would, so look further ahead than a normal sync
+        let settings = SyncSettings {
+            stop_gap: config.recovery_stop_gap,
+            ..SyncSettings::from(config)
+        };
+        let job = jobs::recover(client, plans, settings, events);
+        Ok((self.jobs.spawn(job), entry.id))
+    }
+
+    fn finish_restore(
+        &mut self,
+        job: RestoreJob,
+        result: Result<Vec<FullScanResponse<KeychainKind>>, WalletError>,
+    ) {
+        let RestoreJob {
+            responder,
+            wallet_id,
+            candidates,
+            saved,
+            password,
+            ..
+        } = job;
+
+        let updates = match result {
+            Ok(updates) => updates,
+            Err(e) => {
+                println!("Recovery scan of wallet {} failed: {}", wallet_id, e);
+                return responder.respond(Err(e));
+            }
+        };
+        // The first candidate with any used address, else the saved one
+        let chosen = updates
+            .iter()
+            .position(|update| !update.last_active_indices.is_empty())
+            .unwrap_or(saved);
+        let update = updates
+            .into_iter()
+            .nth(chosen)
+            .expect("one update per candidate");
+
+        if chosen == saved {
+            let events = responder.events.clone();
+            let result = self.finish_recovery(&events, wallet_id, update);
+            return responder.respond(result);
+        }
+
+        // Funds are on another script type than the saved one
+        let keystore = candidates
+            .into_iter()
+            .nth(chosen)
+            .expect("one candidate per update");
+        let work = key_work(
+            move || Ok((keystore.seal(&password)?, keystore)),
+            move |service, events, (file, keystore)| {
+                let id = service.replace_wallet(events, wallet_id, &file, &keystore, update)?;
+                service.wallet_restored(events, id)
+            },
+        );
+        self.start_key_job(responder, Ok(work));
+    }
+
+    // Apply a recovery scan to the wallet it was for
+    fn finish_recovery(
+        &mut self,
+        events: &EventEmitter,
+        id: WalletId,
+        update: FullScanResponse<KeychainKind>,
+    ) -> Result<AppResponse, WalletError> {
+        let loaded = load_wallet(&mut self.wallets, &mut self.registry, &self.config, id)?;
+        apply_update(loaded, update)?;
+        load_registry(&mut self.registry, &self.config)?.set_recovering(id, false)?;
+        self.wallet_restored(events, id)
+    }
+
+    // Replace a restored wallet by one with another candidate's keys, under
+    // the same name. The new wallet is written and registered in full
+    // before the saved one is removed, so a failure leaves that one as it was.
+    fn replace_wallet(
+        &mut self,
+        events: &EventEmitter,
+        id: WalletId,
+        file: &KeystoreFile,
+        keystore: &Keystore,
+        update: FullScanResponse<KeychainKind>,
+    ) -> Result<WalletId, WalletError> {
+        if self.is_sending(id) {
+            return Err(WalletError::WalletBusy);
+        }
+        let registry = load_registry(&mut self.registry, &self.config)?;
+        registry.check_replacement(id, file)?;
+        let new_id = registry.next_id();
+
+        let mut loaded = write_wallet_files(
+            &self.config,
+            new_id,
+            file,
+            keystore.external.clone(),
+            Some(keystore.internal.clone()),
+        )?;
+        let replaced = apply_update(&mut loaded, update).and_then(|()| registry.replace(id, file));
+        if let Err(e) = replaced {
+            drop(loaded);
+            remove_wallet_files(&self.config, new_id);
+            return Err(e);
+        }
+
+        self.wallets.insert(new_id, loaded);
+        self.forget_wallet(id);
+        self.wallets_updated(events)?;
+        Ok(new_id)
+    }
+
+    fn wallet_restored(
+        &mut self,
+        events: &EventEmitter,
+        id: WalletId,
+    ) -> Result<AppResponse, WalletError> {
+        let entry = load_registry(&mut self.registry, &self.config)?
+            .get(id)?
+            .clone();
+        let loaded = load_wallet(&mut self.wallets, &mut self.registry, &self.config, id)?;
+        let restored = WalletRestored {
+            new_wallet_id: id,
+            network: entry.network,
+            fingerprint: entry.fingerprint,
+            script_type: entry.script_type,
+            balance: BalanceInfo::from(loaded.wallet.balance()),
+        };
+        events.for_wallet(id).emit(restored.clone());
+        Ok(AppResponse::WalletRestored(restored))
+    }
+
+    fn finish_sync(
+        &mut self,
+        waiters: Vec<Responder>,
+        db_path: PathBuf,
+        result: Result<FullScanResponse<KeychainKind>, WalletError>,
+    ) {
+        let result = result.and_then(|update| {
+            let loaded = self.loaded_at(&db_path)?;
+            apply_update(loaded, update)?;
+            let id = loaded.id;
+            let balance = BalanceInfo::from(loaded.wallet.balance());
+            load_registry(&mut self.registry, &self.config)?.set_recovering(id, false)?;
+            Ok(balance)
+        });
+
+        for responder in waiters {
+            let result = result.clone().map(|balance| {
+                responder.events.emit(SyncCompleted {
+                    balance: balance.clone(),
+                });
+                AppResponse::WalletSynced(balance)
+            });
+            responder.respond(result);
+        }
+    }
+
+    fn get_wallet_balance(
+        &mut self,
+        events: &EventEmitter,
+        id: WalletId,
+    ) -> Result<AppResponse, WalletError> {
+        let loaded = load_wallet(&mut self.wallets, &mut self.registry, &self.config, id)?;
+
+        let balance = BalanceInfo::from(loaded.wallet.balance());
+        events.emit(balance.clone());
+        Ok(AppResponse::WalletBalance(balance))
+    }
+
+    fn get_utxos(
+        &mut self,
+        events: &EventEmitter,
+        id: WalletId,
+    ) -> Result<AppResponse, WalletError> {
+        let loaded = load_wallet(&mut self.wallets, &mut self.registry, &self.config, id)?;
+
+        let network = loaded.wallet.network();
+        let mut utxos: Vec<_> = loaded.wallet.list_unspent().collect();
+        utxos.sort_by_key(|utxo| std::cmp::Reverse(utxo.txout.value));
+        let utxos = UtxoList {
+            utxos: utxos
+                .iter()
+                .map(|utxo| UtxoInfo::new(utxo, network))
+                .collect(),
+        };
+        events.emit(utxos.clone());
+        Ok(AppResponse::Utxos(utxos))
+    }
+
+    fn set_label(
+        &mut self,
+        events: &EventEmitter,
+        id: WalletId,
+        label: Label,
+    ) -> Result<AppResponse, WalletError> {
+        if label.reference.trim().is_empty() {
+            return Err(WalletError::InvalidArgument {
+                field: "ref".to_string(),
+                message: "Say what the label is for".to_string(),
+            });
+        }
+        let path = self.config.labels_path_for(id);
+        let mut labels = labels::load(&path)?;
+        labels::set(&mut labels, label);
+        labels::save(&path, &labels)?;
+
+        let labels = LabelList { labels };
+        events.emit(labels.clone());
+        Ok(AppResponse::Labels(labels))
+    }
+
+    fn start_send(
+        &mut self,
+        responder: Responder,
+        target: Result<WalletId, WalletError>,
+        request: SendRequest,
+    ) {
+        let id = match target {
+            Ok(id) => id,
+            Err(e) => return responder.respond(Err(e)),
+        };
+        if self.broadcast_job.is_some() {
+            println!("Broadcast in progress, queueing send");
+            self.queued_sends.push_back((responder, id, request));
+            return;
+        }
+        if request.fee().is_some_and(|fee| fee.is_preset()) && self.fresh_fee_estimates().is_none()
+        {
+            self.wait_for_fee_estimates(FeeWaiter::Send(responder, id, request));
+            return;
+        }
+
+        match self.spawn_send(id, request) {
+            Ok(SendStarted::Broadcast(task)) => self.broadcast_job = Some((task, id, responder)),
+            Ok(SendStarted::Unsigned(psbt)) => {
+                responder.events.emit(psbt.clone());
+                responder.respond(Ok(AppResponse::PsbtCreated(psbt)));
+            }
+            Err(e) => responder.respond(Err(e)),
+        }
+    }
+
+    // Sends that finish without a broadcast don't hold up the queue
+    fn start_next_send(&mut self) {
+        while self.broadcast_job.is_none() {
+            let Some((responder, id, request)) = self.queued_sends.pop_front() else {
+                break;
+            };
+            self.start_send(responder, Ok(id), request);
+        }
+    }
+
+    // Build or combine the PSBT and sign it with whatever key we hold on the
+    // service task, then broadcast as a job. A PSBT that still misses
+    // signatures, like any from a watch-only wallet, is handed back instead.
+    fn spawn_send(
+        &mut self,
+        id: WalletId,
+        request: SendRequest,
+    ) -> Result<SendStarted, WalletError> {
+        let estimates = self.fresh_fee_estimates().cloned();
+        let config = self.config.config();
+        let loaded = load_wallet(&mut self.wallets, &mut self.registry, &self.config, id)?;
+        if !loaded.unlocked && !loaded.watch_only {
+            return Err(WalletError::WalletLocked);
+        }
+        check_backend(&loaded.wallet, config)?;
+
+        let (mut psbt, amount, fee_saved) = match request {
+            SendRequest::Payment {
+                address,
+                amount,
+                fee,
+            } => {
+                let address = parse_address(&address, loaded.wallet.network())?;
+                if amount == 0 {
+                    return Err(WalletError::InvalidArgument {
+                        field: "amount".to_string(),
+                        message: "must be more than 0 sats".to_string(),
+                    });
+                }
+                let fee_rate = fee.rate(estimates.as_ref())?;
+                let recipient = (address, Amount::from_sat(amount));
+                let psbt = build_payment(&mut loaded.wallet, &[recipient], fee_rate)?;
+                (psbt, amount, None)
+            }
+            SendRequest::Batch { recipients, fee } => {
+                let recipients = parse_recipients(&recipients, loaded.wallet.network())?;
+                let total = check_funds(&loaded.wallet, &recipients)?;
+                let fee_rate = fee.rate(estimates.as_ref())?;
+                let psbt = build_payment(&mut loaded.wallet, &recipients, fee_rate)?;
+                let fee_saved = separate_fees(&loaded.wallet, &psbt, &recipients, fee_rate)
+                    .checked_sub(psbt_fee(&psbt)?)
+                    .unwrap_or(Amount::ZERO);
+                (psbt, total.to_sat(), Some(fee_saved))
+            }
+            SendRequest::Drain {
+                address,
+                fee,
+                confirmed_only,
+                utxos,
+            } => {
+                let address = parse_address(&address, loaded.wallet.network())?;
+                let fee_rate = fee.rate(estimates.as_ref())?;
+                let psbt = build_drain(
+                    &mut loaded.wallet,
+                    &address,
+                    &utxos,
+                    confirmed_only,
+                    fee_rate,
+                )?;
+                let amount = payment_amount(&loaded.wallet, &psbt.unsigned_tx);
+                (psbt, amount, None)
+            }
+            SendRequest::Psbts(psbts) => {
+                let psbt = combine_psbts(&loaded.wallet, &psbts)?;
+                let amount = payment_amount(&loaded.wallet, &psbt.unsigned_tx);
+                (psbt, amount, None)
+            }
+        };
+
+        let unsigned_tx = psbt.unsigned_tx.clone();
+        let started = (|| {
+            // A watch-only wallet has no keys, but can still finalize what
+            // others signed
+            let finalized = loaded.wallet.sign(&mut psbt, SignOptions::default())?;
+            // Building the transaction revealed a change address; keep it
+            // reserved, also for PSBTs that come back signed later
+            loaded.persist()?;
+
+            if !finalized {
+                let unsigned = psbt_info(&loaded.wallet, &psbt, amount, fee_saved)?;
+                // Single key wallets that hold their key sign on their own
+                if unsigned.required == 1 && !loaded.watch_only {
+                    return Err(WalletError::Signing {
+                        message: "Failed to finalize transaction".to_string(),
+                    });
+                }
+                return Ok(SendStarted::Unsigned(unsigned));
+            }
+
+            let fee = psbt_fee(&psbt)?;
+            let tx = psbt
+                .extract_tx()
+                .map_err(|e| WalletError::TransactionBuild {
+                    message: e.to_string(),
+                })?;
+
+            // Create esplora client
+            let client = esplora_client::Builder::new(&config.esplora_url)
+                .timeout(config.request_timeout_secs)
+                .build_async()?;
+
+            let job = jobs::broadcast(client, loaded.db_path.clone(), tx, fee, fee_saved);
+            Ok(SendStarted::Broadcast(self.jobs.spawn(job).id()))
+        })();
+        // Nothing will come of the transaction, so its change address is
+        // free for the next one
+        if started.is_err() {
+            loaded.wallet.cancel_tx(&unsigned_tx);
+        }
+        started
+    }
+
+    fn finish_send(
+        &mut self,
+        responder: Responder,
+        db_path: PathBuf,
+        tx: Transaction,
+        fee: Amount,
+        fee_saved: Option<Amount>,
+        result: Result<(), WalletError>,
+    ) {
+        let result = result.and_then(|()| {
+            // Record the transaction right away so the balance and coin
+            // selection account for it before the next sync
+            let loaded = self.loaded_at(&db_path)?;
+            loaded
+                .wallet
+                .apply_unconfirmed_txs([(tx.clone(), unix_time())]);
+            loaded.persist()?;
+
+            let transaction_info = TransactionInfo {
+                txid: tx.compute_txid().to_string(),
+                amount: payment_amount(&loaded.wallet, &tx),
+                outputs: payment_outputs(&loaded.wallet, &tx),
+                fee: fee.to_sat(),
+                vsize: tx.vsize() as u64,
+                fee_saved: fee_saved.map(Amount::to_sat),
+            };
+            responder.events.emit(transaction_info.clone());
+            Ok(AppResponse::TransactionSent(transaction_info))
+        });
+
+        // Release the change address reserved for a transaction that never
+        // made it out
+        if result.is_err() {
+            if let Ok(loaded) = self.loaded_at(&db_path) {
+                loaded.wallet.cancel_tx(&tx);
+            }
+        }
+        responder.respond(result);
+    }
+
+    // The loaded wallet at `db_path`, provided it is still loaded
+    fn loaded_at(&mut self, db_path: &Path) -> Result<&mut LoadedWallet, WalletError> {
+        self.wallets
+            .values_mut()
+            .find(|loaded| loaded.db_path == db_path)
+            .ok_or_else(|| WalletError::JobFailed {
+                message: "Wallet was unloaded while the operation was running".to_string(),
+            })
+    }
+
+    fn update_config(
+        &mut self,
+        events: &EventEmitter,
+        config: Config,
+    ) -> Result<AppResponse, WalletError> {
+        let previous_data_dir = self.config.data_dir().to_path_buf();
+        let current = self.config.update(config)?.clone();
+
+        // Another data directory has its own registry and wallets; load
+        // them on next use. Wallets on another network than the new one
+        // stay loaded but can't sync or send until it is switched back.
+        if self.config.data_dir() != previous_data_dir {
+            println!("Data directory changed, unloading wallets");
+            self.wallets.clear();
+            self.registry = None;
+        }
+
+        events.emit(ConfigUpdated {
+            config: current.clone(),
+        });
+        Ok(AppResponse::Config(current))
+    }
+}
+
+// Tell whoever waited on a cancelled scan
+fn respond_cancelled(waiters: impl IntoIterator<Item = Responder>) {
+    for responder in waiters {
+        responder.events.emit(SyncCancelled {});
+        responder.respond(Err(WalletError::SyncCancelled));
+    }
+}
+
+// Return the registry held in `slot`, reading it on first use
+fn load_registry<'a>(
+    slot: &'a mut Option<Registry>,
+    config: &ConfigStore,
+) -> Result<&'a mut Registry, WalletError> {
+    if slot.is_none() {
+        *slot = Some(Registry::load(config.registry_path())?);
+    }
+    Ok(slot.as_mut().expect("registry was just loaded"))
+}
+
+// Return wallet `id`, opening its database and loading it on first use. A
+// freshly loaded wallet is locked: it only has the public descriptors stored
+// in the database.
+fn load_wallet<'a>(
+    wallets: &'a mut HashMap<WalletId, LoadedWallet>,
+    registry: &mut Option<Registry>,
+    config: &ConfigStore,
+    id: WalletId,
+) -> Result<&'a mut LoadedWallet, WalletError> {
+    let slot = match wallets.entry(id) {
+        Entry::Occupied(entry) => return Ok(entry.into_mut()),
+        Entry::Vacant(slot) => slot,
+    };
+
+    let network = load_registry(registry, config)?.get(id)?.network;
+    let db_path = config.db_path_for(id);
+    let keystore = KeystoreFile::load(&config.keystore_path_for(id))?;
+    let Some(keystore) = keystore.filter(|_| db_path.is_file()) else {
+        return Err(WalletError::WalletNotFound);
+    };
+    if keystore.network != network {
+        return Err(WalletError::NetworkMismatch {
+            message: format!(
+                "keystore is for {}, wallet is registered for {}",
+                keystore.network, network
+            ),
+        });
+    }
+
+    let mut conn = Connection::open(&db_path)?;
+    let wallet = Wallet::load()
+        .check_network(network)
+        .load_wallet(&mut conn)?
+        .ok_or(WalletError::WalletNotFound)?;
+
+    Ok(slot.insert(LoadedWallet {
+        id,
+        db_path,
+        conn,
+        wallet,
+        script_type: keystore.script_type,
+        unlocked: false,
+        watch_only: keystore.watch_only,
+        last_used: Instant::now(),
+    }))
+}
+
+// Save `file` into wallet `id`'s directory and create a database for the
+// descriptors, removing both again if either step fails. Private
+// descriptors leave the wallet able to sign.
+fn write_wallet_files(
+    config: &ConfigStore,
+    id: WalletId,
+    file: &KeystoreFile,
+    external: String,
+    internal: Option<String>,
+) -> Result<LoadedWallet, WalletError> {
+    let db_path = config.db_path_for(id);
+    let keystore_path = config.keystore_path_for(id);
+    // Ids only get registered once their files are complete, so anything
+    // here is left over from an attempt that never was
+    if config.wallet_dir(id).exists() {
+        println!("Removing leftover {}", config.wallet_dir(id).display());
+        remove_wallet_files(config, id);
+    }
+
+    // Keys first, so a database never exists without them
+    file.save(&keystore_path)?;
+    let created = (|| {
+        storage::create_parent_dir(&db_path)?;
+        let mut conn = Connection::open(&db_path)?;
+        let params = match internal {
+            Some(internal) => Wallet::create(external, internal),
+            None => Wallet::create_single(external),
+        };
+        let wallet = params.network(file.network).create_wallet(&mut conn)?;
+        Ok(LoadedWallet {
+            id,
+            db_path: db_path.clone(),
+            conn,
+            wallet,
+            script_type: file.script_type,
+            unlocked: !file.watch_only,
+            watch_only: file.watch_only,
+            last_used: Instant::now(),
+        })
+    })();
+    if created.is_err() {
+        remove_wallet_files(config, id);
+    }
+    created
+}
+
+// Apply a scan to the wallet and write it to the database
+fn apply_update(
+    loaded: &mut LoadedWallet,
+    update: FullScanResponse<KeychainKind>,
+) -> Result<(), WalletError> {
+    loaded
+        .wallet
+        .apply_update(update)
+        .map_err(|e| WalletError::Database {
+            message: format!("Failed to apply update: {}", e),
+        })?;
+    loaded.persist()
+}
+
+// What a send asks for
+enum SendRequest {
+    // Pay this many sats to an address as the user typed it
+    Payment {
+        address: String,
+        amount: u64,
+        fee: FeeChoice,
+    },
+    // Several recipients in one transaction
+    Batch {
+        recipients: Vec<Recipient>,
+        fee: FeeChoice,
+    },
+    // Everything the picked coins hold, less the fee
+    Drain {
+        address: String,
+        fee: FeeChoice,
+        confirmed_only: bool,
+        utxos: Vec<String>,
+    },
+    // PSBTs for the same transaction, each carrying other signatures
+    Psbts(Vec<String>),
+}
+
+impl SendRequest {
+    // The rate a new transaction is built with
+    fn fee(&self) -> Option<FeeChoice> {
+        match self {
+            Self::Payment { fee, .. } | Self::Batch { fee, .. } | Self::Drain { fee, .. } => {
+                Some(*fee)
+            }
+            Self::Psbts(_) => None,
+        }
+    }
+}
+
+// What `spawn_send` started
+enum SendStarted {
+    Broadcast(task::Id),
+    Unsigned(UnsignedPsbt),
+}
+
+// Unsigned transaction paying every recipient at `fee_rate`
+fn build_payment(
+    wallet: &mut Wallet,
+    recipients: &[(Address, Amount)],
+    fee_rate: FeeRate,
+) -> Result<Psbt, WalletError> {
+    check_funds(wallet, recipients)?;
+
+    let mut tx_builder = wallet.build_tx();
+    for (address, amount) in recipients {
+        tx_builder.add_recipient(address.script_pubkey(), *amount);
+    }
+    tx_builder.fee_rate(fee_rate);
+    Ok(tx_builder.finish()?)
+}
+
+// The total paid to `recipients`, provided the wallet holds that much in
+// coins it can spend: confirmed ones and its own unconfirmed change, not
+// immature coinbase outputs or unconfirmed payments from others
+fn check_funds(wallet: &Wallet, recipients: &[(Address, Amount)]) -> Result<Amount, WalletError> {
+    let available = wallet.balance().trusted_spendable();
+    let total = recipients
+        .iter()
+        .try_fold(Amount::ZERO, |total, (_, amount)| {
+            total.checked_add(*amount)
+        });
+    match total {
+        Some(total) if total <= available => Ok(total),
+        total => Err(WalletError::InsufficientFunds {
+            required: total.unwrap_or(Amount::MAX).to_sat(),
+            available: available.to_sat(),
+        }),
+    }
+}
+
+// Batch recipients as the user gave them, numbered from 1 in errors
+fn parse_recipients(
+    recipients: &[Recipient],
+    network: Network,
+) -> Result<Vec<(Address, Amount)>, WalletError> {
+    let invalid = |message: String| WalletError::InvalidArgument {
+        field: "recipients".to_string(),
+        message,
+    };
+    if recipients.is_empty() {
+        return Err(invalid("no recipients given".to_string()));
+    }
+    recipients
+        .iter()
+        .enumerate()
+        .map(|(i, recipient)| {
+            let address = parse_address(&recipient.address, network).map_err(|e| match e {
+                WalletError::InvalidAddress { message } => WalletError::InvalidAddress {
+                    message: format!("recipient {}: {}", i + 1, message),
+                },
+                e => e,
+            })?;
+            if recipient.amount == 0 {
+                return Err(invalid(format!(
+                    "recipient {} must get more than 0 sats",
+                    i + 1
+                )));
+            }
+            Ok((address, Amount::from_sat(recipient.amount)))
+        })
+        .collect()
+}
+
+// Fees of paying each recipient in a transaction of its own, estimated as
+// the batch without the other recipients' outputs: the same inputs and
+// change, at the same rate
+fn separate_fees(
+    wallet: &Wallet,
+    batch: &Psbt,
+    recipients: &[(Address, Amount)],
+    fee_rate: FeeRate,
+) -> Amount {
+    let vsize = estimated_vsize(wallet, batch);
+    let outputs: Vec<u64> = recipients
+        .iter()
+        .map(|(address, amount)| {
+            TxOut {
+                value: *amount,
+                script_pubkey: address.script_pubkey(),
+            }
+            .weight()
+            .to_vbytes_ceil()
+        })
+        .collect();
+    let all_outputs: u64 = outputs.iter().sum();
+    outputs
+        .iter()
+        .filter_map(|output| fee_rate.fee_vb(vsize.saturating_sub(all_outputs) + output))
+        .sum()
+}
+
+// Unsigned transaction paying everything `utxos` hold to `address`, less the
+// fee. Without `utxos` it spends every coin, or every confirmed one.
+fn build_drain(
+    wallet: &mut Wallet,
+    address: &Address,
+    utxos: &[String],
+    confirmed_only: bool,
+    fee_rate: FeeRate,
+) -> Result<Psbt, WalletError> {
+    let invalid = |message: String| WalletError::InvalidArgument {
+        field: "utxos".to_string(),
+        message,
+    };
+    let mut selected = Vec::with_capacity(utxos.len());
+    for utxo in utxos {
+        let outpoint =
+            OutPoint::from_str(utxo.trim()).map_err(|e| invalid(format!("{}: {}", utxo, e)))?;
+        let output = wallet.get_utxo(outpoint).ok_or_else(|| {
+            invalid(format!(
+                "{} is not an unspent output of this wallet",
+                outpoint
+            ))
+        })?;
+        if confirmed_only && !output.chain_position.is_confirmed() {
+            return Err(invalid(format!("{} is not confirmed yet", outpoint)));
+        }
+        selected.push(outpoint);
+    }
+    let unconfirmed: Vec<_> = wallet
+        .list_unspent()
+        .filter(|utxo| confirmed_only && !utxo.chain_position.is_confirmed())
+        .map(|utxo| utxo.outpoint)
+        .collect();
+
+    let mut tx_builder = wallet.build_tx();
+    if selected.is_empty() {
+        // Immature coinbase outputs are left out by BDK
+        tx_builder.drain_wallet().unspendable(unconfirmed);
+    } else {
+        tx_builder
+            .add_utxos(&selected)
+            .map_err(|e| invalid(e.to_string()))?
+            .manually_selected_only();
+    }
+    tx_builder
+        .drain_to(address.script_pubkey())
+        .fee_rate(fee_rate);
+    Ok(tx_builder.finish()?)
+}
+
+// Merge the signatures of several copies of one PSBT, which must only spend
+// coins of `wallet`
+fn combine_psbts(wallet: &Wallet, psbts: &[String]) -> Result<Psbt, WalletError> {
+    let invalid = |message: String| WalletError::InvalidArgument {
+        field: "psbts".to_string(),
+        message,
+    };
+    let mut parsed = psbts
+        .iter()
+        .map(|psbt| Psbt::from_str(psbt.trim()).map_err(|e| invalid(e.to_string())));
+    let mut psbt = parsed
+        .next()
+        .ok_or_else(|| invalid("at least one PSBT is needed".to_string()))??;
+    for other in parsed {
+        psbt.combine(other?).map_err(|e| invalid(e.to_string()))?;
+    }
+
+    let foreign = psbt
+        .unsigned_tx
+        .input
+        .iter()
+        .any(|input| wallet.get_utxo(input.previous_output).is_none());
+    if foreign {
+        return Err(invalid(
+            "spends coins this wallet doesn't have, or that were spent already".to_string(),
+        ));
+    }
+    Ok(psbt)
+}
+
+// What `tx` pays out, counting everything but our change
+fn payment_amount(wallet: &Wallet, tx: &Transaction) -> u64 {
+    payment_outputs(wallet, tx)
+        .iter()
+        .map(|output| output.amount)
+        .sum()
+}
+
+fn payment_outputs(wallet: &Wallet, tx: &Transaction) -> Vec<OutputInfo> {
+    tx.output
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| {
+            let keychain = wallet
+                .derivation_of_spk(output.script_pubkey.clone())
+                .map(|(keychain, _)| keychain);
+            keychain != Some(KeychainKind::Internal)
+        })
+        .map(|(vout, output)| OutputInfo {
+            vout: vout as u32,
+            address: Address::from_script(&output.script_pubkey, wallet.network())
+                .ok()
+                .map(|address| address.to_string()),
+            amount: output.value.to_sat(),
+        })
+        .collect()
+}
+
+fn psbt_fee(psbt: &Psbt) -> Result<Amount, WalletError> {
+    psbt.fee().map_err(|e| WalletError::TransactionBuild {
+        message: e.to_string(),
+    })
+}
+
+// Report a PSBT waiting for signatures. An input that is already finalized
+// counts as fully signed; the least signed input decides.
+fn psbt_info(
+    wallet: &Wallet,
+    psbt: &Psbt,
+    amount: u64,
+    fee_saved: Option<Amount>,
+) -> Result<UnsignedPsbt, WalletError> {
+    let required =
+        descriptors::required_signatures(wallet.public_descriptor(KeychainKind::External));
+    let signatures = psbt
+        .inputs
+        .iter()
+        .map(|input| {
+            if input.final_script_witness.is_some() || input.final_script_sig.is_some() {
+                required
+            } else {
+                input.partial_sigs.len() + usize::from(input.tap_key_sig.is_some())
+            }
+        })
+        .min()
+        .unwrap_or_default();
+
+    Ok(UnsignedPsbt {
+        psbt: psbt.to_string(),
+        txid: psbt.unsigned_tx.compute_txid().to_string(),
+        amount,
+        outputs: payment_outputs(wallet, &psbt.unsigned_tx),
+        fee: psbt_fee(psbt)?.to_sat(),
+        vsize: estimated_vsize(wallet, psbt),
+        fee_saved: fee_saved.map(Amount::to_sat),
+        signatures: signatures.min(required) as u32,
+        required: required as u32,
+    })
+}
+
+// Virtual size of the transaction once every input is signed. Inputs that
+// aren't finalized yet count with the largest satisfaction their descriptor
+// allows, the same bound BDK sets the fee by.
+fn estimated_vsize(wallet: &Wallet, psbt: &Psbt) -> u64 {
+    let mut tx = psbt.unsigned_tx.clone();
+    let mut missing = Weight::ZERO;
+    for (txin, input) in tx.input.iter_mut().zip(&psbt.inputs) {
+        if input.final_script_sig.is_none() && input.final_script_witness.is_none() {
+            missing += wallet
+                .get_utxo(txin.previous_output)
+                .and_then(|utxo| {
+                    wallet
+                        .public_descriptor(utxo.keychain)
+                        .max_weight_to_satisfy()
+                        .ok()
+                })
+                .unwrap_or(Weight::ZERO);
+        } else {
+            txin.script_sig = input.final_script_sig.clone().unwrap_or_default();
+            txin.witness = input.final_script_witness.clone().unwrap_or_default();
+        }
+    }
+    (tx.weight() + missing).to_vbytes_ceil()
+}
+
+// An address typed in by the user, which must be for `network`
+fn parse_address(input: &str, network: Network) -> Result<Address, WalletError> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(WalletError::InvalidAddress {
+            message: "no address given".to_string(),
+        });
+    }
+    input
+        .parse::<Address<_>>()
+        .map_err(|e| {
+            // A typo in a segwit address would otherwise be reported as a
+            // base58 error
+            let segwit = ["bc1", "tb1", "bcrt1"]
+                .iter()
+                .any(|hrp| input.to_lowercase().starts_with(hrp));
+            let message = match bech32::segwit::decode(input) {
+                Err(bech32_error) if segwit => error_chain(&bech32_error),
+                _ => error_chain(&e),
+            };
+            WalletError::InvalidAddress { message }
+        })?
+        .require_network(network)
+        .map_err(|_| WalletError::AddressNetworkMismatch { network })
+}
+
+// An error with its causes, which parse errors keep the details in
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message = format!("{}: {}", message, cause);
+        source = cause.source();
+    }
+    message
+}
+
+// Checked up front so a bad password fails before any slow work is done
+fn check_password(password: &str) -> Result<(), WalletError> {
+    if password.is_empty() {
+        return Err(WalletError::InvalidArgument {
+            field: "password".to_string(),
+            message: "must not be empty".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn remove_wallet_files(config: &ConfigStore, id: WalletId) {
+    let _ = std::fs::remove_dir_all(config.wallet_dir(id));
+}
+
+// The esplora server only serves the configured network
+fn check_backend(wallet: &Wallet, config: &Config) -> Result<(), WalletError> {
+    if wallet.network() != config.network {
+        return Err(WalletError::NetworkMismatch {
+            message: format!(
+                "wallet is on {}, the esplora server is for {}",
+                wallet.network(),
+                config.network
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Esplora client for scans. The jobs do their own retrying with backoff and
+// report each attempt, so the client must not retry on its own.
+fn scan_client(config: &Config) -> Result<esplora_client::AsyncClient, WalletError> {
+    Ok(esplora_client::Builder::new(&config.esplora_url)
+        .timeout(config.request_timeout_secs)
+        .max_retries(0)
+        .build_async()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_address_checks_network() {
+        let signet = "tb1q6rz28mcfaxtmd6v789l9rrlrusdprr9pqcpvkl";
+        let mainnet = "bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l";
+
+        let address = parse_address(&format!(" {} ", signet), Network::Signet).unwrap();
+        assert_eq!(address.to_string(), signet);
+        // Testnet and signet share their addresses
+        assert!(parse_address(signet, Network::Testnet).is_ok());
+        assert!(parse_address(mainnet, Network::Bitcoin).is_ok());
+
+        assert!(matches!(
+            parse_address(mainnet, Network::Signet),
+            Err(WalletError::AddressNetworkMismatch {
+                network: Network::Signet
+            })
+        ));
+        assert!(matches!(
+            parse_address(signet, Network::Bitcoin),
+            Err(WalletError::AddressNetworkMismatch {
+                network: Network::Bitcoin
+            })
+        ));
+    }
+
+    #[test]
+    fn parse_address_reports_typos() {
+        assert!(matches!(
+            parse_address("  ", Network::Signet),
+            Err(WalletError::InvalidAddress { .. })
+        ));
+        // Last character changed, so the checksum fails
+        match parse_address(
+            "tb1q6rz28mcfaxtmd6v789l9rrlrusdprr9pqcpvkm",
+            Network::Signet,
+        ) {
+            Err(WalletError::InvalidAddress { message }) => {
+                assert!(!message.to_lowercase().contains("base58"), "{}", message)
+            }
+            other => panic!("expected InvalidAddress, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,271 @@
+// On-disk layout of wallet data.
+//
+// Each wallet lives in a folder of its own under the app-data directory (see
+// `ConfigStore::wallet_dir`), listed in the wallet registry. Older builds
+// kept one wallet per network in a folder named after the network, and
+// before that wrote a single `bdk-wallet.sqlite` into whatever directory
+// the app was started from. The `migrate_*` functions move both into place
+// on first run, oldest layout first.
+use std::path::{Path, PathBuf};
+
+use bdk_wallet::{bitcoin::Network, rusqlite::Connection, KeychainKind, Wallet};
+
+use crate::config::ConfigStore;
+use crate::error::WalletError;
+use crate::keystore::{Keystore, KeystoreFile};
+use crate::registry::{Registry, WalletEntry};
+
+// Where builds before the app-data directory kept the wallet, relative to
+// the working directory
+pub const LEGACY_DB_PATH: &str = "bdk-wallet.sqlite";
+
+// Test key every install used before wallets were generated from a mnemonic
+const LEGACY_EXTERNAL_DESC: &str = "wpkh(tprv8ZgxMBicQKsPdy6LMhUtFHAgpocR8GC6QmwMSFpZs7h6Eziw3SpThFfczTDh5rW2krkqffa11UpX3XkeTTB2FvzZKWXqPY54Y6Rq4AQ5R8L/84'/1'/0'/0/*)";
+const LEGACY_INTERNAL_DESC: &str = "wpkh(tprv8ZgxMBicQKsPdy6LMhUtFHAgpocR8GC6QmwMSFpZs7h6Eziw3SpThFfczTDh5rW2krkqffa11UpX3XkeTTB2FvzZKWXqPY54Y6Rq4AQ5R8L/84'/1'/0'/1/*)";
+
+// Networks that may have a folder from before the wallet registry
+const LEGACY_NETWORKS: [Network; 5] = [
+    Network::Bitcoin,
+    Network::Testnet,
+    Network::Testnet4,
+    Network::Signet,
+    Network::Regtest,
+];
+
+/// Move a legacy wallet database into the folder for the network it was
+/// created on, from where `migrate_network_dirs` picks it up. Returns the new location if a file was moved.
+///
+/// Nothing is touched when the legacy file is missing, holds no wallet, or
+/// a database already exists at the destination.
+pub fn migrate_legacy_db(
+    legacy: &Path,
+    config: &ConfigStore,
+) -> Result<Option<PathBuf>, WalletError> {
+    if !legacy.is_file() {
+        return Ok(None);
+    }
+
+    // Read the network from the wallet itself rather than trusting the
+    // current config, so signet data can never end up in a mainnet folder
+    let network = {
+        let mut conn = Connection::open(legacy)?;
+        match Wallet::load().load_wallet(&mut conn)? {
+            Some(wallet) => wallet.network(),
+            None => return Ok(None),
+        }
+    };
+
+    let target = config.legacy_db_path(network);
+    if target.exists() {
+        println!(
+            "Not migrating {}: {} already exists",
+            legacy.display(),
+            target.display()
+        );
+        return Ok(None);
+    }
+
+    create_parent_dir(&target)?;
+    move_file(legacy, &target)?;
+    println!("Migrated {} to {}", legacy.display(), target.display());
+    Ok(Some(target))
+}
+
+/// Write a keystore for a database created with the old built-in test key,
+/// so it keeps working now that keys are no longer compiled in. Returns
+/// true if a keystore was written.
+///
+/// Databases whose descriptors don't match the test key are left alone.
+pub fn migrate_legacy_keys(config: &ConfigStore) -> Result<bool, WalletError> {
+    let network = config.config().network;
+    let db_path = config.legacy_db_path(network);
+    let keystore_path = config.legacy_keystore_path(network);
+    if !db_path.is_file() || keystore_path.exists() {
+        return Ok(false);
+    }
+
+    let mut conn = Connection::open(&db_path)?;
+    let loaded = Wallet::load()
+        .descriptor(KeychainKind::External, Some(LEGACY_EXTERNAL_DESC))
+        .descriptor(KeychainKind::Internal, Some(LEGACY_INTERNAL_DESC))
+        .check_network(network)
+        .load_wallet(&mut conn);
+    if !matches!(loaded, Ok(Some(_))) {
+        return Ok(false);
+    }
+
+    // The test key is public anyway, so it stays unencrypted until the first
+    // unlock sets a password
+    let keystore = Keystore::from_descriptors(LEGACY_EXTERNAL_DESC, LEGACY_INTERNAL_DESC, network)?;
+    KeystoreFile::unencrypted(&keystore).save(&keystore_path)?;
+    println!(
+        "Wrote {} for a wallet using the shared test key; create a new wallet to get keys of your own",
+        keystore_path.display()
+    );
+    Ok(true)
+}
+
+/// Register the wallets kept one per network before the registry existed,
+/// moving each database and keystore into a wallet folder of its own.
+/// Returns the wallets that were added; the one for the configured network
+/// becomes active, as it was before.
+pub fn migrate_network_dirs(
+    config: &ConfigStore,
+    registry: &mut Registry,
+) -> Result<Vec<WalletEntry>, WalletError> {
+    let mut added = Vec::new();
+    for network in LEGACY_NETWORKS {
+        let db_path = config.legacy_db_path(network);
+        let keystore_path = config.legacy_keystore_path(network);
+        if !db_path.is_file() {
+            continue;
+        }
+        let keystore = match KeystoreFile::load(&keystore_path)? {
+            Some(keystore) if keystore.network == network => keystore,
+            _ => {
+                println!("Not migrating {}: no keystore for it", db_path.display());
+                continue;
+            }
+        };
+
+        let id = registry.next_id();
+        let name = registry.default_name(network);
+        registry.check_new(&name, &keystore)?;
+        let target_db = config.db_path_for(id);
+        let target_keystore = config.keystore_path_for(id);
+        create_parent_dir(&target_db)?;
+        move_file(&keystore_path, &target_keystore)?;
+        move_file(&db_path, &target_db)?;
+
+        match registry.add(&name, &keystore) {
+            Ok(entry) => {
+                println!("Migrated {} to {}", db_path.display(), target_db.display());
+                // A restore whose recovery scan never went through
+                let recovering = config.legacy_recovering_path(network);
+                if recovering.exists() {
+                    registry.set_recovering(entry.id, true)?;
+                    let _ = std::fs::remove_file(&recovering);
+                }
+                added.push(entry);
+            }
+            Err(e) => {
+                // Put the files back so the next start can try again
+                let _ = move_file(&target_keystore, &keystore_path);
+                let _ = move_file(&target_db, &db_path);
+                return Err(e);
+            }
+        }
+    }
+
+    let network = config.config().network;
+    if let Some(entry) = added.iter().find(|entry| entry.network == network) {
+        registry.select(entry.id)?;
+    }
+    Ok(added)
+}
+
+/// Write `contents` to a temporary file and move it over `path`, so a crash
+/// never leaves a half-written file behind.
+pub fn replace_file(path: &Path, contents: &[u8]) -> Result<(), WalletError> {
+    create_parent_dir(path)?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents).map_err(|e| io_error(&tmp, e))?;
+    std::fs::rename(&tmp, path).map_err(|e| io_error(path, e))
+}
+
+/// Make sure the directory that will hold `path` exists.
+pub fn create_parent_dir(path: &Path) -> Result<(), WalletError> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| io_error(parent, e))?;
+    }
+    Ok(())
+}
+
+// Rename, falling back to copy and delete when the data directory is on a
+// different filesystem than the legacy file
+fn move_file(from: &Path, to: &Path) -> Result<(), WalletError> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to).map_err(|e| io_error(to, e))?;
+    std::fs::remove_file(from).map_err(|e| io_error(from, e))
+}
+
+fn io_error(path: &Path, error: std::io::Error) -> WalletError {
+    WalletError::Database {
+        message: format!("{}: {}", path.display(), error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bdk_wallet::bitcoin::Network;
+
+    use crate::config::Config;
+
+    const EXTERNAL: &str = "wpkh(tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/0/*)";
+    const INTERNAL: &str = "wpkh(tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/1/*)";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("slaps-storage-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn create_db(path: &Path, network: Network) {
+        let mut conn = Connection::open(path).unwrap();
+        Wallet::create(EXTERNAL, INTERNAL)
+            .network(network)
+            .create_wallet(&mut conn)
+            .unwrap();
+    }
+
+    #[test]
+    fn migrates_into_the_wallet_network() {
+        let dir = temp_dir("migrate");
+        let legacy = dir.join(LEGACY_DB_PATH);
+        // The configured network doesn't matter, the wallet's own does
+        let config = ConfigStore::in_memory(
+            Config {
+                network: Network::Testnet,
+                ..Config::default()
+            },
+            dir.join("data"),
+        )
+        .unwrap();
+
+        assert_eq!(migrate_legacy_db(&legacy, &config).unwrap(), None);
+
+        create_db(&legacy, Network::Signet);
+        let target = config.legacy_db_path(Network::Signet);
+        assert_eq!(
+            migrate_legacy_db(&legacy, &config).unwrap(),
+            Some(target.clone())
+        );
+        assert!(!legacy.exists());
+        let mut conn = Connection::open(&target).unwrap();
+        let wallet = Wallet::load().load_wallet(&mut conn).unwrap().unwrap();
+        assert_eq!(wallet.network(), Network::Signet);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn never_overwrites() {
+        let dir = temp_dir("existing");
+        let legacy = dir.join(LEGACY_DB_PATH);
+        let config = ConfigStore::in_memory(Config::default(), dir.join("data")).unwrap();
+        let target = config.legacy_db_path(Network::Signet);
+        create_parent_dir(&target).unwrap();
+        std::fs::write(&target, b"existing").unwrap();
+
+        create_db(&legacy, Network::Signet);
+        assert_eq!(migrate_legacy_db(&legacy, &config).unwrap(), None);
+        assert!(legacy.exists());
+        assert_eq!(std::fs::read(&target).unwrap(), b"existing");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
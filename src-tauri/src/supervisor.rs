@@ -0,0 +1,433 @@
+// Keeps the wallet service alive.
+//
+// The supervisor owns the channel behind every `ServiceHandle` and runs the
+// `WalletService` as a child task with a channel of its own. When the child
+// panics, a fresh one is started after a short backoff, so handles held by
+// the frontend never go stale. Lifecycle messages (`Start`, `Restart`,
+// `Status`) are answered here; everything else is forwarded to the child.
+use std::any::Any;
+use std::future;
+use std::sync::Arc;
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::config::ConfigStore;
+use crate::error::WalletError;
+use crate::events::{EventEmitter, EventSink, ServiceStatus, WorkerState};
+use crate::jobs;
+use crate::service::{
+    AppMessage, AppResponse, Request, Responder, ServiceHandle, WalletService, CHANNEL_CAPACITY,
+};
+
+// Wait before the first restart after a crash, doubled for every crash in a row
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+// Longest wait between restarts, however often the task keeps crashing
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+// A task that stayed up this long is considered healthy again
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+// The running wallet service and how to reach it
+struct Worker {
+    tx: mpsc::Sender<Request>,
+    task: JoinHandle<()>,
+    started_at: Instant,
+}
+
+/// Runs a `WalletService` and restarts it whenever it crashes.
+pub struct Supervisor {
+    rx: mpsc::Receiver<Request>,
+    events: EventEmitter,
+    config: ConfigStore,
+    worker: Option<Worker>,
+    state: WorkerState,
+    restarts: u32,
+    // Crashes since the task last ran for `STABLE_AFTER`, drives the backoff
+    crashes_in_a_row: u32,
+    last_error: Option<String>,
+    // When a crashed task is started again
+    restart_at: Option<Instant>,
+    // `Restart` requests answered once the new task is up
+    restart_waiters: Vec<Responder>,
+    // Set by `Shutdown`, so the task ending is not mistaken for a restart
+    stop_requested: bool,
+}
+
+impl Supervisor {
+    /// Create the supervisor along with the handle used to talk to it.
+    pub fn new(config: ConfigStore, sink: impl EventSink) -> (Self, ServiceHandle) {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let supervisor = Self {
+            rx,
+            events: EventEmitter::new(Arc::new(sink)),
+            config,
+            worker: None,
+            state: WorkerState::Stopped,
+            restarts: 0,
+            crashes_in_a_row: 0,
+            last_error: None,
+            restart_at: None,
+            restart_waiters: Vec::new(),
+            stop_requested: false,
+        };
+        (supervisor, ServiceHandle::new(tx))
+    }
+
+    /// Start the wallet service and keep it running until every handle has
+    /// been dropped.
+    pub async fn run(mut self) {
+        println!("Supervisor started");
+        self.start_worker();
+
+        loop {
+            tokio::select! {
+                request = self.rx.recv() => {
+                    let Some(request) = request else {
+                        println!("All senders dropped");
+                        break;
+                    };
+                    self.handle_request(request);
+                }
+                result = worker_exit(&mut self.worker) => {
+                    self.handle_exit(result);
+                }
+                _ = restart_due(self.restart_at) => {
+                    self.restart_at = None;
+                    self.start_worker();
+                }
+            }
+        }
+
+        // Dropping the child's sender makes it finish what it is doing and
+        // stop on its own
+        if let Some(worker) = self.worker.take() {
+            drop(worker.tx);
+            let _ = worker.task.await;
+        }
+        println!("Supervisor ended");
+    }
+
+    // Never waits on the task's channel: while the supervisor is stuck there
+    // it can't answer `Status` or notice the task crashing
+    fn handle_request(&mut self, request: Request) {
+        let responder = Responder {
+            events: self.events.for_request(request.id),
+            reply: request.reply,
+        };
+
+        match request.message {
+            AppMessage::Status => responder.respond(Ok(AppResponse::Status(self.status()))),
+            AppMessage::Start => {
+                if self.worker.is_none() {
+                    println!("Starting background task");
+                    self.restart_at = None;
+                    self.start_worker();
+                }
+                responder.respond(Ok(AppResponse::Status(self.status())));
+            }
+            AppMessage::Restart => {
+                println!("Restarting background task");
+                match &self.worker {
+                    // Let the task shut down cleanly; the new one is started
+                    // when it has ended
+                    Some(worker) => {
+                        let shutdown = Request {
+                            id: request.id,
+                            wallet: None,
+                            message: AppMessage::Shutdown,
+                            reply: None,
+                        };
+                        self.restart_waiters.push(responder);
+                        match worker.tx.try_send(shutdown) {
+                            Ok(()) => {}
+                            // Queued behind the rest, without holding up the
+                            // supervisor meanwhile
+                            Err(TrySendError::Full(shutdown)) => {
+                                let tx = worker.tx.clone();
+                                tokio::spawn(async move {
+                                    let _ = tx.send(shutdown).await;
+                                });
+                            }
+                            // Already gone; its exit is handled next
+                            Err(TrySendError::Closed(_)) => {
+                                println!("Background task ended before the restart");
+                            }
+                        }
+                    }
+                    None => {
+                        self.restarts += 1;
+                        self.restart_at = None;
+                        self.start_worker();
+                        responder.respond(Ok(AppResponse::Status(self.status())));
+                    }
+                }
+            }
+            AppMessage::Shutdown if self.worker.is_none() => {
+                self.restart_at = None;
+                self.set_state(WorkerState::Stopped);
+                responder.respond(Ok(AppResponse::ShuttingDown));
+            }
+            message => {
+                let Some(worker) = &self.worker else {
+                    responder.respond(Err(self.unavailable()));
+                    return;
+                };
+                let shutdown = matches!(message, AppMessage::Shutdown);
+                let forwarded = Request {
+                    id: request.id,
+                    wallet: request.wallet,
+                    message,
+                    reply: responder.reply,
+                };
+                let (request, error) = match worker.tx.try_send(forwarded) {
+                    Ok(()) => {
+                        self.stop_requested |= shutdown;
+                        return;
+                    }
+                    // A refused `Shutdown` leaves the task running
+                    Err(TrySendError::Full(request)) => {
+                        let error = WalletError::ServiceUnavailable {
+                            message: "busy with earlier requests, try again shortly".to_string(),
+                        };
+                        (request, error)
+                    }
+                    // The task died and its exit hasn't been handled yet
+                    Err(TrySendError::Closed(request)) => {
+                        self.stop_requested |= shutdown;
+                        (request, WalletError::ServiceRestarting)
+                    }
+                };
+                let responder = Responder {
+                    events: responder.events,
+                    reply: request.reply,
+                };
+                responder.respond(Err(error));
+            }
+        }
+    }
+
+    fn handle_exit(&mut self, result: Result<(), JoinError>) {
+        let Some(worker) = self.worker.take() else {
+            return;
+        };
+        if worker.started_at.elapsed() >= STABLE_AFTER {
+            self.crashes_in_a_row = 0;
+        }
+
+        match result {
+            Ok(()) if !self.restart_waiters.is_empty() => {
+                self.restarts += 1;
+                self.start_worker();
+            }
+            Ok(()) => {
+                println!("Background task stopped");
+                self.set_state(WorkerState::Stopped);
+            }
+            Err(e) => {
+                let message = panic_message(e);
+                println!("Background task crashed: {}", message);
+                self.last_error = Some(message);
+                self.crashes_in_a_row += 1;
+
+                if self.stop_requested {
+                    self.set_state(WorkerState::Stopped);
+                } else if !self.restart_waiters.is_empty() {
+                    self.restarts += 1;
+                    self.start_worker();
+                } else {
+                    let delay = restart_delay(self.crashes_in_a_row);
+                    println!("Restarting background task in {:?}", delay);
+                    self.restarts += 1;
+                    self.restart_at = Some(Instant::now() + delay);
+                    self.set_state(WorkerState::Restarting);
+                }
+            }
+        }
+        self.stop_requested = false;
+    }
+
+    // Start a fresh service with the config as currently saved on disk
+    fn start_worker(&mut self) {
+        let config = self.config.reload().unwrap_or_else(|e| {
+            println!("Failed to reload config, keeping the previous one: {}", e);
+            self.config.clone()
+        });
+        self.config = config.clone();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let service = WalletService::with_receiver(config, self.events.clone(), rx);
+        self.worker = Some(Worker {
+            tx,
+            task: tokio::spawn(service.run()),
+            started_at: Instant::now(),
+        });
+        self.set_state(WorkerState::Running);
+
+        let status = self.status();
+        for responder in self.restart_waiters.drain(..) {
+            responder.respond(Ok(AppResponse::Status(status.clone())));
+        }
+    }
+
+    fn set_state(&mut self, state: WorkerState) {
+        self.state = state;
+        self.events.emit(self.status());
+    }
+
+    fn status(&self) -> ServiceStatus {
+        ServiceStatus {
+            state: self.state,
+            restarts: self.restarts,
+            last_error: self.last_error.clone(),
+        }
+    }
+
+    fn unavailable(&self) -> WalletError {
+        match self.state {
+            WorkerState::Restarting => WalletError::ServiceRestarting,
+            _ => WalletError::ServiceStopped,
+        }
+    }
+}
+
+// Resolves when the running task ends; never resolves without one
+async fn worker_exit(worker: &mut Option<Worker>) -> Result<(), JoinError> {
+    match worker {
+        Some(worker) => (&mut worker.task).await,
+        None => future::pending().await,
+    }
+}
+
+// Resolves when a pending restart is due; never resolves without one
+async fn restart_due(at: Option<Instant>) {
+    match at {
+        Some(at) => sleep_until(at).await,
+        None => future::pending().await,
+    }
+}
+
+fn restart_delay(crashes: u32) -> Duration {
+    jobs::backoff(RESTART_DELAY, crashes, MAX_RESTART_DELAY)
+}
+
+fn panic_message(error: JoinError) -> String {
+    if !error.is_panic() {
+        return error.to_string();
+    }
+    let payload: Box<dyn Any + Send> = error.into_panic();
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "background task panicked".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::Config;
+
+    fn supervisor(name: &str) -> ServiceHandle {
+        let dir =
+            std::env::temp_dir().join(format!("slaps-supervisor-{}-{}", name, std::process::id()));
+        let config = ConfigStore::in_memory(Config::default(), dir).unwrap();
+        let (supervisor, handle) = Supervisor::new(config, |_: &str, _: serde_json::Value| {});
+        tokio::spawn(supervisor.run());
+        handle
+    }
+
+    async fn state(handle: &ServiceHandle) -> WorkerState {
+        match handle.request(AppMessage::Status).await.unwrap().response {
+            AppResponse::Status(status) => status.state,
+            other => panic!("expected Status, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_and_start() {
+        let handle = supervisor("lifecycle");
+        assert_eq!(state(&handle).await, WorkerState::Running);
+        assert!(handle.request(AppMessage::Ping).await.is_ok());
+
+        handle.request(AppMessage::Shutdown).await.unwrap();
+        while state(&handle).await != WorkerState::Stopped {
+            tokio::task::yield_now().await;
+        }
+        let error = handle.request(AppMessage::Ping).await.unwrap_err();
+        assert_eq!(error.error, WalletError::ServiceStopped);
+
+        handle.request(AppMessage::Start).await.unwrap();
+        assert_eq!(state(&handle).await, WorkerState::Running);
+        assert!(handle.request(AppMessage::Ping).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn busy_task_is_not_waited_on() {
+        let dir =
+            std::env::temp_dir().join(format!("slaps-supervisor-busy-{}", std::process::id()));
+        let config = ConfigStore::in_memory(Config::default(), dir).unwrap();
+        let (mut supervisor, _handle) = Supervisor::new(config, |_: &str, _: serde_json::Value| {});
+        // A task stuck on something, with its queue full
+        let (tx, _rx) = mpsc::channel(1);
+        supervisor.worker = Some(Worker {
+            tx,
+            task: tokio::spawn(future::pending()),
+            started_at: Instant::now(),
+        });
+        supervisor.state = WorkerState::Running;
+
+        let mut replies = Vec::new();
+        for id in 0..2 {
+            let (reply, rx) = tokio::sync::oneshot::channel();
+            supervisor.handle_request(Request {
+                id,
+                wallet: None,
+                message: AppMessage::Shutdown,
+                reply: Some(reply),
+            });
+            replies.push(rx);
+        }
+        assert!(replies[0].try_recv().is_err(), "the first one is queued");
+        assert!(matches!(
+            replies[1].try_recv().unwrap(),
+            Err(WalletError::ServiceUnavailable { .. })
+        ));
+        assert!(
+            supervisor.stop_requested,
+            "the queued shutdown still counts"
+        );
+
+        let (reply, mut rx) = tokio::sync::oneshot::channel();
+        supervisor.handle_request(Request {
+            id: 2,
+            wallet: None,
+            message: AppMessage::Status,
+            reply: Some(reply),
+        });
+        assert!(matches!(rx.try_recv().unwrap(), Ok(AppResponse::Status(_))));
+    }
+
+    #[test]
+    fn restart_backoff() {
+        assert_eq!(restart_delay(1), RESTART_DELAY);
+        assert_eq!(restart_delay(2), 2 * RESTART_DELAY);
+        assert_eq!(restart_delay(6), MAX_RESTART_DELAY);
+        assert_eq!(restart_delay(u32::MAX), MAX_RESTART_DELAY);
+    }
+
+    #[tokio::test]
+    async fn panic_messages() {
+        let error = tokio::spawn(async { panic!("wallet {} exploded", 1) })
+            .await
+            .unwrap_err();
+        assert_eq!(panic_message(error), "wallet 1 exploded");
+        let error = tokio::spawn(async { std::panic::panic_any(7) })
+            .await
+            .unwrap_err();
+        assert_eq!(panic_message(error), "background task panicked");
+    }
+}
@@ -1,93 +1,570 @@
-import { useState, useEffect } from "react";
+import { useState, useEffect, useRef } from "react";
 import reactLogo from "./assets/react.svg";
 import { invoke } from "@tauri-apps/api/core";
 import { listen } from "@tauri-apps/api/event";
 import "./App.css";
 
+// Result of a send_to_background call, correlated by request id
+interface Reply {
+  request_id: number;
+  response: unknown;
+}
+
+// Stable error codes from WalletError; extra fields depend on the code
+type WalletErrorCode =
+  | "database"
+  | "descriptor_mismatch"
+  | "network_mismatch"
+  | "wallet_not_found"
+  | "wallet_already_exists"
+  | "wallet_busy"
+  | "restore_in_progress"
+  | "invalid_mnemonic"
+  | "wrong_password"
+  | "wallet_locked"
+  | "watch_only"
+  | "keystore"
+  | "kdf_limit_exceeded"
+  | "invalid_argument"
+  | "invalid_address"
+  | "address_network_mismatch"
+  | "insufficient_funds"
+  | "transaction_build"
+  | "signing"
+  | "esplora"
+  | "sync_timeout"
+  | "sync_cancelled"
+  | "broadcast_rejected"
+  | "service_unavailable"
+  | "service_stopped"
+  | "service_restarting"
+  | "job_failed"
+  | "config"
+  | "invalid_config";
+
+interface RequestError {
+  request_id: number;
+  display: string;
+  // Details of some error codes
+  message?: string;
+  code: WalletErrorCode;
+  required?: number;
+  available?: number;
+  network?: string;
+}
+
+// Envelope fields present on every background event
+interface EventEnvelope {
+  version: number;
+  request_id: number | null;
+  wallet_id: number | null;
+}
+
+interface WalletEntry {
+  id: number;
+  name: string;
+  network: string;
+  script_type: ScriptType;
+  fingerprint: string;
+  // BIP44 account of the seed; wallets of one seed share the fingerprint
+  account: number;
+  watch_only: boolean;
+  created_at: number;
+  // Restored but not scanned yet; the next sync finishes the recovery
+  recovering: boolean;
+}
+
+// Wallets of the same seed, listed together
+const groupBySeed = (wallets: WalletEntry[]) => {
+  const seeds = new Map<string, WalletEntry[]>();
+  for (const wallet of wallets) {
+    const key = `${wallet.network}:${wallet.fingerprint}`;
+    seeds.set(key, [...(seeds.get(key) ?? []), wallet]);
+  }
+  return [...seeds.values()];
+};
+
+interface WalletList {
+  active: number | null;
+  wallets: WalletEntry[];
+}
+
+interface WalletListEvent extends EventEnvelope, WalletList {}
+
+interface LockedEvent extends EventEnvelope {
+  // Locked by the idle timer rather than on request
+  idle: boolean;
+}
+
+type Keychain = "External" | "Internal";
+
+// BIP44 legacy, BIP49 nested segwit, BIP84 native segwit, BIP86 taproot
+type ScriptType = "bip44" | "bip49" | "bip84" | "bip86";
+
+type FeePreset = "fast" | "normal" | "slow";
+
+// A preset, or a rate in sat/vB
+type FeeChoice = FeePreset | { custom: number };
+
+// Rates in sat/vB for the presets
+interface FeeEstimatesEvent extends EventEnvelope {
+  fast: number;
+  normal: number;
+  slow: number;
+  fetched_at: number;
+}
+
+const SCRIPT_TYPES: { value: ScriptType; label: string }[] = [
+  { value: "bip84", label: "Native segwit (BIP84)" },
+  { value: "bip86", label: "Taproot (BIP86)" },
+  { value: "bip49", label: "Nested segwit (BIP49)" },
+  { value: "bip44", label: "Legacy (BIP44)" },
+];
+
+interface AddressEvent extends EventEnvelope {
+  address: string;
+  index: number;
+  keychain: Keychain;
+  script_type: ScriptType;
+  derivation_path: string | null;
+}
+
+interface BalanceEvent extends EventEnvelope {
+  confirmed: number;
+  trusted_pending: number;
+  untrusted_pending: number;
+  immature: number;
+  spendable: number;
+  total: number;
+}
+
+interface SyncProgressEvent extends EventEnvelope {
+  keychain: Keychain;
+  index: number;
+}
+
+interface SyncRetryEvent extends EventEnvelope {
+  attempt: number;
+  max_attempts: number;
+  delay_ms: number;
+  message: string;
+}
+
+interface SyncCompletedEvent extends EventEnvelope {
+  balance: BalanceEvent;
+}
+
+// Returned once by CreateWallet; never stored by the frontend
+interface RecoveryPhrase {
+  words: string[];
+  fingerprint: string;
+}
+
+interface WalletRestoredEvent extends EventEnvelope {
+  network: string;
+  fingerprint: string;
+  script_type: ScriptType;
+  balance: BalanceEvent;
+}
+
+interface WalletImportedEvent extends EventEnvelope {
+  network: string;
+  fingerprint: string;
+  script_type: ScriptType;
+}
+
+// An output paying someone, i.e. not our change
+interface OutputInfo {
+  vout: number;
+  address: string | null;
+  amount: number;
+}
+
+// Built instead of sending while signatures are missing
+interface PsbtEvent extends EventEnvelope {
+  psbt: string;
+  txid: string;
+  amount: number;
+  outputs: OutputInfo[];
+  fee: number;
+  // Estimated until every signature is in
+  vsize: number;
+  // Batches only
+  fee_saved: number | null;
+  signatures: number;
+  required: number;
+}
+
+interface MultisigEvent {
+  new_wallet_id: number;
+  threshold: number;
+  signers: number;
+  // Our key, for the cosigners to add
+  key: string;
+}
+
+type MessageFormat = "bip322_simple" | "legacy";
+
+interface SignedMessageEvent extends EventEnvelope {
+  address: string;
+  message: string;
+  signature: string;
+  format: MessageFormat;
+}
+
+interface MessageVerifiedEvent extends EventEnvelope {
+  address: string;
+  valid: boolean;
+  format: MessageFormat;
+}
+
+interface UtxoInfo {
+  // txid:vout
+  outpoint: string;
+  value: number;
+  address: string | null;
+  keychain: "External" | "Internal";
+  confirmation_height: number | null;
+}
+
+interface UtxosEvent extends EventEnvelope {
+  utxos: UtxoInfo[];
+}
+
+// A BIP329 record
+type LabelType = "tx" | "addr" | "pubkey" | "input" | "output" | "xpub";
+
+interface Label {
+  type: LabelType;
+  ref: string;
+  label: string;
+  spendable?: boolean;
+}
+
+interface LabelsEvent extends EventEnvelope {
+  labels: Label[];
+}
+
+interface TransactionEvent extends EventEnvelope {
+  txid: string;
+  amount: number;
+  outputs: OutputInfo[];
+  fee: number;
+  vsize: number;
+  // Batches only
+  fee_saved: number | null;
+}
+
+interface ServiceStatus {
+  state: "running" | "restarting" | "stopped";
+  restarts: number;
+  last_error: string | null;
+}
+
+interface ServiceStatusEvent extends EventEnvelope, ServiceStatus {}
+
+// Recipients of a sent transaction, listed when there are several
+function OutputList({ outputs, feeSaved }: { outputs: OutputInfo[]; feeSaved: number | null }) {
+  if (outputs.length < 2) {
+    return null;
+  }
+  return (
+    <>
+      {outputs.map((output) => (
+        <p key={output.vout}><small>#{output.vout}: {output.amount} sats to {output.address ?? "unknown script"}</small></p>
+      ))}
+      {feeSaved !== null && <p><small>Saved {feeSaved} sats in fees over separate sends</small></p>}
+    </>
+  );
+}
+
 function App() {
   const [greetMsg, setGreetMsg] = useState("");
   const [name, setName] = useState("");
   const [heartbeatCount, setHeartbeatCount] = useState(0);
+  const [serviceStatus, setServiceStatus] = useState<ServiceStatus | null>(null);
   const [lastPingResponse, setLastPingResponse] = useState<string | null>(null);
   const [lastDataUpdate, setLastDataUpdate] = useState<string | null>(null);
   
   // Wallet state
-  const [walletAddress, setWalletAddress] = useState<string | null>(null);
+  const [walletAddress, setWalletAddress] = useState<AddressEvent | null>(null);
   const [walletBalance, setWalletBalance] = useState<number | null>(null);
   const [syncStatus, setSyncStatus] = useState<string | null>(null);
-  const [txid, setTxid] = useState<string | null>(null);
+  const [transaction, setTransaction] = useState<TransactionEvent | null>(null);
   const [walletError, setWalletError] = useState<string | null>(null);
+  const [sendAddress, setSendAddress] = useState("");
   const [sendAmount, setSendAmount] = useState<number>(5000);
+  const [feePreset, setFeePreset] = useState<FeePreset | "custom">("normal");
+  const [customFeeRate, setCustomFeeRate] = useState<number>(2);
+  const [feeEstimates, setFeeEstimates] = useState<FeeEstimatesEvent | null>(null);
+  const [utxos, setUtxos] = useState<UtxoInfo[] | null>(null);
+  const [selectedUtxos, setSelectedUtxos] = useState<string[]>([]);
+  const [labels, setLabels] = useState<Label[] | null>(null);
+  const [labelType, setLabelType] = useState<LabelType>("tx");
+  const [labelRef, setLabelRef] = useState("");
+  const [labelText, setLabelText] = useState("");
+  const [confirmedOnly, setConfirmedOnly] = useState(false);
+  const [batchRecipients, setBatchRecipients] = useState("");
+  const [wordCount, setWordCount] = useState<12 | 24>(12);
+  const [scriptType, setScriptType] = useState<ScriptType>("bip84");
+  // Script types a restore looks for funds on; "any" tries them all
+  const [restoreScriptType, setRestoreScriptType] = useState<ScriptType | "any">("any");
+  const [passphrase, setPassphrase] = useState("");
+  // Encrypts the keystore; separate from the BIP39 passphrase above
+  const [password, setPassword] = useState("");
+  const [walletLocked, setWalletLocked] = useState<boolean | null>(null);
+  const [recoveryPhrase, setRecoveryPhrase] = useState<RecoveryPhrase | null>(null);
+  const [restoreWords, setRestoreWords] = useState("");
+  const [watchDescriptor, setWatchDescriptor] = useState("");
+  const [unsignedPsbt, setUnsignedPsbt] = useState<PsbtEvent | null>(null);
+  const [signedPsbts, setSignedPsbts] = useState("");
+  const [cosigners, setCosigners] = useState("");
+  const [threshold, setThreshold] = useState<number>(2);
+  const [multisig, setMultisig] = useState<MultisigEvent | null>(null);
+  const [backupPassword, setBackupPassword] = useState("");
+  const [backupDocument, setBackupDocument] = useState("");
+  const [restoreSettings, setRestoreSettings] = useState(false);
+  const [messageAddress, setMessageAddress] = useState("");
+  const [messageText, setMessageText] = useState("");
+  const [messageSignature, setMessageSignature] = useState("");
+  const [signedMessage, setSignedMessage] = useState<SignedMessageEvent | null>(null);
+  const [messageVerified, setMessageVerified] = useState<MessageVerifiedEvent | null>(null);
+  const [walletList, setWalletList] = useState<WalletList | null>(null);
+  // Read by the event listeners, which are only set up once. Set along with
+  // the list rather than after it renders, since a new wallet's sync events
+  // follow right after it.
+  const activeWalletId = useRef<number | null>(null);
+  // Name for the next new wallet, or the new name when renaming
+  const [walletName, setWalletName] = useState("");
 
   useEffect(() => {
-    const unlistenBackgroundEvent = listen("background-event", (event) => {
+    // Another wallet's sync or idle lock must not show up as the active
+    // wallet's. Events that aren't about a wallet always apply.
+    const forActiveWallet = (event: { payload: EventEnvelope }) =>
+      event.payload.wallet_id === null || event.payload.wallet_id === activeWalletId.current;
+
+    const unlistenBackgroundEvent = listen<{ message: string }>("background-event", (event) => {
       console.log("Received background event:", event);
-      setLastPingResponse(event.payload as string);
+      setLastPingResponse(event.payload.message);
     });
 
-    const unlistenDataUpdated = listen("data-updated", (event) => {
+    const unlistenDataUpdated = listen<{ data: string }>("data-updated", (event) => {
       console.log("Data updated:", event);
-      setLastDataUpdate(event.payload as string);
+      setLastDataUpdate(event.payload.data);
+    });
+
+    const unlistenServiceStatus = listen<ServiceStatusEvent>("service-status", (event) => {
+      console.log("Background task status:", event);
+      setServiceStatus(event.payload);
     });
 
-    const unlistenHeartbeat = listen("heartbeat", (event) => {
+    // The supervisor may have started the task before we were listening
+    invoke<Reply>("service_status")
+      .then((reply) => {
+        const { Status } = reply.response as { Status: ServiceStatus };
+        setServiceStatus(Status);
+      })
+      .catch((error) => console.error("Error reading background task status:", error));
+
+    const unlistenHeartbeat = listen<{ count: number }>("heartbeat", (event) => {
       console.log("Heartbeat received from background task:", event);
-      setHeartbeatCount(event.payload as number);
+      setHeartbeatCount(event.payload.count);
     });
     
     // Wallet event listeners
-    const unlistenWalletAddress = listen("wallet-address", (event) => {
+    const unlistenWalletsUpdated = listen<WalletListEvent>("wallets-updated", (event) => {
+      console.log("Wallet list updated:", event);
+      activeWalletId.current = event.payload.active;
+      setWalletList(event.payload);
+    });
+
+    invoke<Reply>("send_to_background", { message: { ListWallets: null } })
+      .then((reply) => {
+        const { Wallets } = reply.response as { Wallets: WalletList };
+        activeWalletId.current = Wallets.active;
+        setWalletList(Wallets);
+      })
+      .catch((error) => console.error("Error listing wallets:", error));
+    
+    const unlistenWalletAddress = listen<AddressEvent>("wallet-address", (event) => {
       console.log("Wallet address received:", event);
-      setWalletAddress(event.payload as string);
+      if (!forActiveWallet(event)) return;
+      setWalletAddress(event.payload);
     });
     
-    const unlistenWalletBalance = listen("wallet-balance", (event) => {
+    const unlistenWalletBalance = listen<BalanceEvent>("wallet-balance", (event) => {
       console.log("Wallet balance received:", event);
-      setWalletBalance(parseInt(event.payload as string));
+      if (!forActiveWallet(event)) return;
+      setWalletBalance(event.payload.total);
     });
     
-    const unlistenSyncStarted = listen("sync-started", (event) => {
+    const unlistenSyncStarted = listen<EventEnvelope>("sync-started", (event) => {
       console.log("Wallet sync started:", event);
+      if (!forActiveWallet(event)) return;
       setSyncStatus("Syncing...");
     });
     
-    const unlistenSyncProgress = listen("sync-progress", (event) => {
+    const unlistenSyncProgress = listen<SyncProgressEvent>("sync-progress", (event) => {
       console.log("Wallet sync progress:", event);
-      setSyncStatus(event.payload as string);
+      if (!forActiveWallet(event)) return;
+      const { keychain, index } = event.payload;
+      setSyncStatus(`Scanning ${keychain.toLowerCase()} keychain at index ${index}`);
+    });
+    
+    const unlistenSyncRetry = listen<SyncRetryEvent>("sync-retry", (event) => {
+      console.log("Wallet sync retrying:", event);
+      if (!forActiveWallet(event)) return;
+      const { attempt, max_attempts, delay_ms } = event.payload;
+      setSyncStatus(`Attempt ${attempt} of ${max_attempts} failed, retrying in ${delay_ms / 1000}s`);
+    });
+    
+    const unlistenSyncCancelled = listen<EventEnvelope>("sync-cancelled", (event) => {
+      console.log("Wallet sync cancelled:", event);
+      if (!forActiveWallet(event)) return;
+      setSyncStatus("Sync cancelled");
     });
     
-    const unlistenSyncCompleted = listen("sync-completed", (event) => {
+    const unlistenSyncCompleted = listen<SyncCompletedEvent>("sync-completed", (event) => {
       console.log("Wallet sync completed:", event);
+      if (!forActiveWallet(event)) return;
       setSyncStatus("Sync completed");
-      setWalletBalance(parseInt(event.payload as string));
+      setWalletBalance(event.payload.balance.total);
+    });
+    
+    const unlistenWalletRestored = listen<WalletRestoredEvent>("wallet-restored", (event) => {
+      console.log("Wallet restored:", event);
+      setSyncStatus(`Restored ${event.payload.script_type} wallet ${event.payload.fingerprint}`);
+      setWalletBalance(event.payload.balance.total);
+    });
+    
+    const unlistenWalletImported = listen<WalletImportedEvent>("wallet-imported", (event) => {
+      console.log("Watch-only wallet imported:", event);
+      setSyncStatus(`Imported watch-only ${event.payload.script_type} wallet ${event.payload.fingerprint}`);
     });
     
-    const unlistenTransactionSent = listen("transaction-sent", (event) => {
+    const unlistenWalletUnlocked = listen<EventEnvelope>("wallet-unlocked", (event) => {
+      console.log("Wallet unlocked:", event);
+      if (!forActiveWallet(event)) return;
+      setWalletLocked(false);
+    });
+    
+    const unlistenWalletLocked = listen<LockedEvent>("wallet-locked", (event) => {
+      console.log("Wallet locked:", event);
+      if (!forActiveWallet(event)) return;
+      setWalletLocked(true);
+      if (event.payload.idle) {
+        setWalletError("Wallet locked after a period of inactivity. Unlock it to send.");
+      }
+    });
+    
+    const unlistenTransactionSent = listen<TransactionEvent>("transaction-sent", (event) => {
       console.log("Transaction sent:", event);
-      setTxid(event.payload as string);
+      if (!forActiveWallet(event)) return;
+      setTransaction(event.payload);
+    });
+    
+    const unlistenPsbtCreated = listen<PsbtEvent>("psbt-created", (event) => {
+      console.log("Unsigned PSBT created:", event);
+      if (!forActiveWallet(event)) return;
+      setUnsignedPsbt(event.payload);
     });
     
+    const unlistenFeeEstimates = listen<FeeEstimatesEvent>("fee-estimates", (event) => {
+      console.log("Fee estimates:", event);
+      setFeeEstimates(event.payload);
+    });
+    
+    const unlistenUtxos = listen<UtxosEvent>("wallet-utxos", (event) => {
+      console.log("Unspent outputs:", event);
+      if (!forActiveWallet(event)) return;
+      setUtxos(event.payload.utxos);
+      // Drop picks that have been spent in the meantime
+      const outpoints = event.payload.utxos.map((utxo) => utxo.outpoint);
+      setSelectedUtxos((selected) => selected.filter((outpoint) => outpoints.includes(outpoint)));
+    });
+    
+    const unlistenLabels = listen<LabelsEvent>("wallet-labels", (event) => {
+      console.log("Labels:", event);
+      if (!forActiveWallet(event)) return;
+      setLabels(event.payload.labels);
+    });
+    
+    const unlistenMessageSigned = listen<SignedMessageEvent>("message-signed", (event) => {
+      console.log("Message signed:", event);
+      if (!forActiveWallet(event)) return;
+      setSignedMessage(event.payload);
+      setMessageSignature(event.payload.signature);
+      setMessageVerified(null);
+    });
+    
+    const unlistenMessageVerified = listen<MessageVerifiedEvent>("message-verified", (event) => {
+      console.log("Message verified:", event);
+      setMessageVerified(event.payload);
+    });
+    
+    // Errors for our own requests are shown from the invoke reply
     const unlistenWalletError = listen("wallet-error", (event) => {
       console.log("Wallet error:", event);
-      setWalletError(event.payload as string);
-      // Clear error after 5 seconds
-      setTimeout(() => setWalletError(null), 5000);
     });
 
     return () => {
       unlistenBackgroundEvent.then(unsub => unsub());
       unlistenDataUpdated.then(unsub => unsub());
       unlistenHeartbeat.then(unsub => unsub());
+      unlistenServiceStatus.then(unsub => unsub());
+      unlistenWalletsUpdated.then(unsub => unsub());
       unlistenWalletAddress.then(unsub => unsub());
       unlistenWalletBalance.then(unsub => unsub());
       unlistenSyncStarted.then(unsub => unsub());
       unlistenSyncProgress.then(unsub => unsub());
+      unlistenSyncRetry.then(unsub => unsub());
+      unlistenSyncCancelled.then(unsub => unsub());
       unlistenSyncCompleted.then(unsub => unsub());
+      unlistenWalletRestored.then(unsub => unsub());
+      unlistenWalletImported.then(unsub => unsub());
+      unlistenWalletUnlocked.then(unsub => unsub());
+      unlistenWalletLocked.then(unsub => unsub());
       unlistenTransactionSent.then(unsub => unsub());
+      unlistenPsbtCreated.then(unsub => unsub());
+      unlistenFeeEstimates.then(unsub => unsub());
+      unlistenUtxos.then(unsub => unsub());
+      unlistenLabels.then(unsub => unsub());
+      unlistenMessageSigned.then(unsub => unsub());
+      unlistenMessageVerified.then(unsub => unsub());
       unlistenWalletError.then(unsub => unsub());
     };
   }, []);
 
+  const showWalletError = (error: unknown) => {
+    const requestError = error as RequestError;
+    switch (requestError.code) {
+      case "wallet_not_found":
+        setWalletError("No wallet yet. Use Create Wallet to create one.");
+        break;
+      case "insufficient_funds":
+        setWalletError(
+          `Not enough funds: need ${requestError.required} sats, have ${requestError.available} sats`
+        );
+        break;
+      case "wallet_locked":
+        setWalletError("Wallet is locked. Enter your password and unlock it first.");
+        break;
+      case "wrong_password":
+        setWalletError("Wrong password");
+        break;
+      case "address_network_mismatch":
+        setWalletError(`That is not a ${requestError.network} address`);
+        break;
+      case "sync_cancelled":
+        // Requested by the user, the sync status already says so
+        return;
+      default:
+        setWalletError(requestError.display ?? String(error));
+    }
+    // Clear error after 5 seconds
+    setTimeout(() => setWalletError(null), 5000);
+  };
+
   async function greet() {
     // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
     setGreetMsg(await invoke("greet", { name: name + name }));
@@ -115,48 +592,486 @@ function App() {
     }
   };
   
+  const controlBackground = async (message: "Start" | "Restart" | "Shutdown") => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: { [message]: null }
+      });
+      console.log(`${message} request completed:`, reply);
+    } catch (error) {
+      console.error(`Error sending ${message}:`, error);
+    }
+  };
+  
+  // Wallet registry functions; the wallet operations below act on the
+  // active wallet
+  const selectWallet = async (id: number) => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: { SelectWallet: { id } }
+      });
+      console.log("Select wallet request completed:", reply);
+      // The newly selected wallet's details are fetched on demand
+      setWalletAddress(null);
+      setWalletBalance(null);
+      setWalletLocked(null);
+    } catch (error) {
+      console.error("Error selecting wallet:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const renameWallet = async () => {
+    if (walletList?.active == null) return;
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: { RenameWallet: { id: walletList.active, name: walletName } }
+      });
+      console.log("Rename wallet request completed:", reply);
+      setWalletName("");
+    } catch (error) {
+      console.error("Error renaming wallet:", error);
+      showWalletError(error);
+    }
+  };
+  
+  // Next account of the selected wallet's seed, with the script type picked
+  // for new wallets
+  const addAccount = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: {
+          AddAccount: {
+            password,
+            passphrase: passphrase || null,
+            script_type: scriptType,
+            account: null,
+            name: walletName || null
+          }
+        }
+      });
+      console.log("Add account request completed:", reply);
+      setPassphrase("");
+      setWalletName("");
+      setWalletLocked(false);
+    } catch (error) {
+      console.error("Error adding account:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const deleteWallet = async () => {
+    const active = walletList?.wallets.find((wallet) => wallet.id === walletList.active);
+    if (!active || !window.confirm(`Delete "${active.name}" and its keys from this device?`)) return;
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: { DeleteWallet: { id: active.id } }
+      });
+      console.log("Delete wallet request completed:", reply);
+      setWalletAddress(null);
+      setWalletBalance(null);
+      setWalletLocked(null);
+    } catch (error) {
+      console.error("Error deleting wallet:", error);
+      showWalletError(error);
+    }
+  };
+  
   // Wallet functions
+  const createWallet = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: {
+          CreateWallet: {
+            word_count: wordCount,
+            passphrase: passphrase || null,
+            password,
+            name: walletName || null,
+            script_type: scriptType
+          }
+        }
+      });
+      console.log("Create wallet request completed");
+      const { WalletCreated } = reply.response as {
+        WalletCreated: { wallet_id: number; phrase: RecoveryPhrase };
+      };
+      setRecoveryPhrase(WalletCreated.phrase);
+      setPassphrase("");
+      setPassword("");
+      setWalletName("");
+      setWalletLocked(false);
+    } catch (error) {
+      console.error("Error creating wallet:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const restoreWallet = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: {
+          RestoreWallet: {
+            mnemonic: restoreWords,
+            passphrase: passphrase || null,
+            password,
+            script_types: restoreScriptType === "any"
+              ? SCRIPT_TYPES.map((option) => option.value)
+              : [restoreScriptType],
+            name: walletName || null
+          }
+        }
+      });
+      console.log("Restore wallet request completed:", reply);
+      setRestoreWords("");
+      setPassphrase("");
+      setPassword("");
+      setWalletName("");
+      setWalletLocked(false);
+    } catch (error) {
+      console.error("Error restoring wallet:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const createMultisig = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: {
+          CreateMultisig: {
+            threshold,
+            // One key per line
+            cosigners: cosigners.split("\n").map((line) => line.trim()).filter(Boolean),
+            mnemonic: null,
+            passphrase: passphrase || null,
+            password,
+            name: walletName || null
+          }
+        }
+      });
+      console.log("Create multisig request completed");
+      const { MultisigCreated } = reply.response as {
+        MultisigCreated: { wallet: MultisigEvent; phrase: RecoveryPhrase | null };
+      };
+      setMultisig(MultisigCreated.wallet);
+      setRecoveryPhrase(MultisigCreated.phrase);
+      setCosigners("");
+      setPassphrase("");
+      setPassword("");
+      setWalletName("");
+      setWalletLocked(false);
+    } catch (error) {
+      console.error("Error creating multisig wallet:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const combinePsbt = async () => {
+    if (!unsignedPsbt) return;
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: {
+          CombinePsbt: {
+            psbts: [unsignedPsbt.psbt, ...signedPsbts.split(/\s+/).filter(Boolean)]
+          }
+        }
+      });
+      console.log("Combine PSBT request completed:", reply);
+      setSignedPsbts("");
+    } catch (error) {
+      console.error("Error combining PSBTs:", error);
+      showWalletError(error);
+    }
+  };
+  
+  // Signs with the key of an address of the unlocked wallet
+  const signMessage = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: {
+          SignMessage: {
+            address: messageAddress || walletAddress?.address || "",
+            message: messageText
+          }
+        }
+      });
+      console.log("Sign message request completed:", reply);
+    } catch (error) {
+      console.error("Error signing message:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const verifyMessage = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: {
+          VerifyMessage: {
+            address: messageAddress || walletAddress?.address || "",
+            message: messageText,
+            signature: messageSignature
+          }
+        }
+      });
+      console.log("Verify message request completed:", reply);
+    } catch (error) {
+      console.error("Error verifying message:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const importWatchOnly = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: {
+          ImportWatchOnly: {
+            descriptor: watchDescriptor,
+            change_descriptor: null,
+            name: walletName || null
+          }
+        }
+      });
+      console.log("Import watch-only wallet request completed:", reply);
+      setWatchDescriptor("");
+      setWalletName("");
+    } catch (error) {
+      console.error("Error importing watch-only wallet:", error);
+      showWalletError(error);
+    }
+  };
+  
+  // Without the wallet password only the public descriptors are exported
+  const exportBackup = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: {
+          ExportBackup: {
+            password: password || null,
+            backup_password: backupPassword || null
+          }
+        }
+      });
+      console.log("Export backup request completed");
+      const { BackupExported } = reply.response as { BackupExported: string };
+      const link = document.createElement("a");
+      link.href = URL.createObjectURL(new Blob([BackupExported], { type: "application/json" }));
+      link.download = "wallet-backup.json";
+      link.click();
+      URL.revokeObjectURL(link.href);
+      setPassword("");
+      setBackupPassword("");
+    } catch (error) {
+      console.error("Error exporting backup:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const importBackup = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: {
+          ImportBackup: {
+            backup: backupDocument,
+            backup_password: backupPassword || null,
+            password: password || null,
+            name: walletName || null,
+            restore_settings: restoreSettings
+          }
+        }
+      });
+      console.log("Import backup request completed:", reply);
+      setBackupDocument("");
+      setBackupPassword("");
+      setPassword("");
+      setWalletName("");
+    } catch (error) {
+      console.error("Error importing backup:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const unlockWallet = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: { Unlock: { password } }
+      });
+      console.log("Unlock wallet request completed:", reply);
+      setPassword("");
+    } catch (error) {
+      console.error("Error unlocking wallet:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const lockWallet = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: { Lock: null }
+      });
+      console.log("Lock wallet request completed:", reply);
+    } catch (error) {
+      console.error("Error locking wallet:", error);
+      showWalletError(error);
+    }
+  };
+  
   const getWalletAddress = async () => {
     try {
-      await invoke("send_to_background", {
+      const reply = await invoke<Reply>("send_to_background", {
         message: { GetWalletAddress: null }
       });
-      console.log("Get wallet address request sent");
+      console.log("Get wallet address request completed:", reply);
     } catch (error) {
       console.error("Error requesting wallet address:", error);
+      showWalletError(error);
     }
   };
   
   const syncWallet = async () => {
     try {
-      await invoke("send_to_background", {
+      const reply = await invoke<Reply>("send_to_background", {
         message: { SyncWallet: null }
       });
-      console.log("Sync wallet request sent");
+      console.log("Sync wallet request completed:", reply);
     } catch (error) {
       console.error("Error requesting wallet sync:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const cancelSync = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: { CancelSync: null }
+      });
+      console.log("Cancel sync request completed:", reply);
+    } catch (error) {
+      console.error("Error cancelling wallet sync:", error);
+      showWalletError(error);
     }
   };
   
   const getWalletBalance = async () => {
     try {
-      await invoke("send_to_background", {
+      const reply = await invoke<Reply>("send_to_background", {
         message: { GetWalletBalance: null }
       });
-      console.log("Get wallet balance request sent");
+      console.log("Get wallet balance request completed:", reply);
     } catch (error) {
       console.error("Error requesting wallet balance:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const getFeeEstimates = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: { GetFeeEstimates: null }
+      });
+      console.log("Get fee estimates request completed:", reply);
+    } catch (error) {
+      console.error("Error requesting fee estimates:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const selectedFee = (): FeeChoice =>
+    feePreset === "custom" ? { custom: customFeeRate } : feePreset;
+  
+  const getUtxos = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: { GetUtxos: null }
+      });
+      console.log("Get unspent outputs request completed:", reply);
+    } catch (error) {
+      console.error("Error requesting unspent outputs:", error);
+      showWalletError(error);
+    }
+  };
+  
+  const toggleUtxo = (outpoint: string, selected: boolean) => {
+    setSelectedUtxos((current) =>
+      selected ? [...current, outpoint] : current.filter((picked) => picked !== outpoint)
+    );
+  };
+  
+  const getLabels = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: { GetLabels: null }
+      });
+      console.log("Get labels request completed:", reply);
+    } catch (error) {
+      console.error("Error requesting labels:", error);
+      showWalletError(error);
+    }
+  };
+  
+  // Empty text removes the label
+  const setLabel = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: { SetLabel: { type: labelType, ref: labelRef, label: labelText } }
+      });
+      console.log("Set label request completed:", reply);
+      setLabelText("");
+    } catch (error) {
+      console.error("Error setting label:", error);
+      showWalletError(error);
+    }
+  };
+  
+  // Everything the wallet (or the picked coins) holds, less the fee
+  const sendAll = async () => {
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: {
+          SendAll: {
+            address: sendAddress,
+            fee: selectedFee(),
+            confirmed_only: confirmedOnly,
+            utxos: selectedUtxos
+          }
+        }
+      });
+      console.log("Send all request completed:", reply);
+    } catch (error) {
+      console.error("Error requesting send all:", error);
+      showWalletError(error);
+    }
+  };
+  
+  // One "address, amount" per line
+  const sendBatch = async () => {
+    const recipients = batchRecipients
+      .split("\n")
+      .map((line) => line.trim())
+      .filter((line) => line.length > 0)
+      .map((line) => {
+        const [address, amount] = line.split(/[\s,]+/);
+        return { address, amount: parseInt(amount) || 0 };
+      });
+    try {
+      const reply = await invoke<Reply>("send_to_background", {
+        message: { SendBatch: { recipients, fee: selectedFee() } }
+      });
+      console.log("Send batch request completed:", reply);
+    } catch (error) {
+      console.error("Error requesting batch send:", error);
+      showWalletError(error);
     }
   };
   
   const sendTransaction = async () => {
+    const fee = selectedFee();
     try {
-      await invoke("send_to_background", {
-        message: { SendTransaction: sendAmount }
+      const reply = await invoke<Reply>("send_to_background", {
+        message: { SendTransaction: { address: sendAddress, amount: sendAmount, fee } }
       });
-      console.log("Send transaction request sent");
+      console.log("Send transaction request completed:", reply);
     } catch (error) {
       console.error("Error requesting transaction send:", error);
+      showWalletError(error);
     }
   };
 
@@ -185,17 +1100,172 @@ function App() {
           </div>
         )}
         
+        <div className="input-row">
+          <select
+            value={walletList?.active ?? ""}
+            onChange={(e) => selectWallet(parseInt(e.target.value))}
+          >
+            {walletList?.active == null && <option value="">No wallet</option>}
+            {groupBySeed(walletList?.wallets ?? []).map((seed) => (
+              <optgroup key={seed[0].id} label={`${seed[0].fingerprint} (${seed[0].network})`}>
+                {seed.map((wallet) => (
+                  <option key={wallet.id} value={wallet.id}>
+                    {wallet.name} (account {wallet.account}{wallet.watch_only ? ", watch-only" : ""})
+                  </option>
+                ))}
+              </optgroup>
+            ))}
+          </select>
+          <input
+            value={walletName}
+            onChange={(e) => setWalletName(e.target.value)}
+            placeholder="Wallet name"
+          />
+          <button onClick={renameWallet}>Rename</button>
+          <button onClick={deleteWallet}>Delete</button>
+        </div>
+        
+        <div className="input-row">
+          <input
+            type="password"
+            value={password}
+            onChange={(e) => setPassword(e.target.value)}
+            placeholder="Wallet password"
+          />
+          <button onClick={unlockWallet}>Unlock</button>
+          <button onClick={lockWallet}>Lock</button>
+          {walletLocked !== null && <span>{walletLocked ? "Locked" : "Unlocked"}</span>}
+        </div>
+        
+        <div className="input-row">
+          <select
+            value={wordCount}
+            onChange={(e) => setWordCount(parseInt(e.target.value) as 12 | 24)}
+          >
+            <option value={12}>12 words</option>
+            <option value={24}>24 words</option>
+          </select>
+          <select
+            value={scriptType}
+            onChange={(e) => setScriptType(e.target.value as ScriptType)}
+          >
+            {SCRIPT_TYPES.map((option) => (
+              <option key={option.value} value={option.value}>{option.label}</option>
+            ))}
+          </select>
+          <input
+            type="password"
+            value={passphrase}
+            onChange={(e) => setPassphrase(e.target.value)}
+            placeholder="Optional passphrase"
+          />
+          <button onClick={createWallet}>Create Wallet</button>
+          <button onClick={addAccount}>Add Account</button>
+        </div>
+        
+        <div className="input-row">
+          <input
+            type="password"
+            value={restoreWords}
+            onChange={(e) => setRestoreWords(e.target.value)}
+            placeholder="Recovery words to restore"
+          />
+          <select
+            value={restoreScriptType}
+            onChange={(e) => setRestoreScriptType(e.target.value as ScriptType | "any")}
+          >
+            <option value="any">Any address type</option>
+            {SCRIPT_TYPES.map((option) => (
+              <option key={option.value} value={option.value}>{option.label}</option>
+            ))}
+          </select>
+          <button onClick={restoreWallet}>Restore Wallet</button>
+        </div>
+        
+        <div className="input-row">
+          <input
+            value={watchDescriptor}
+            onChange={(e) => setWatchDescriptor(e.target.value)}
+            placeholder="xpub, ypub, zpub, tpub or public descriptor"
+          />
+          <button onClick={importWatchOnly}>Import Watch-Only</button>
+        </div>
+        
+        <div className="input-row">
+          <textarea
+            value={cosigners}
+            onChange={(e) => setCosigners(e.target.value)}
+            placeholder="Cosigner keys, one per line, e.g. [fingerprint/48'/1'/0'/2']tpub..."
+          />
+          <input
+            type="number"
+            value={threshold}
+            onChange={(e) => setThreshold(parseInt(e.target.value))}
+            min="1"
+            title="Signatures needed"
+          />
+          <button onClick={createMultisig}>Create Multisig</button>
+        </div>
+        
+        <div className="input-row">
+          <input
+            type="password"
+            value={backupPassword}
+            onChange={(e) => setBackupPassword(e.target.value)}
+            placeholder="Backup password (optional)"
+          />
+          <button onClick={exportBackup}>Export Backup</button>
+        </div>
+        
+        <div className="input-row">
+          <textarea
+            value={backupDocument}
+            onChange={(e) => setBackupDocument(e.target.value)}
+            placeholder="Paste a wallet backup"
+          />
+          <label>
+            <input
+              type="checkbox"
+              checked={restoreSettings}
+              onChange={(e) => setRestoreSettings(e.target.checked)}
+            />
+            Restore settings
+          </label>
+          <button onClick={importBackup}>Import Backup</button>
+        </div>
+        
+        {multisig && (
+          <div className="info-box">
+            <strong>{multisig.threshold}-of-{multisig.signers} multisig created. Share your key with the cosigners:</strong>
+            <p className="address">{multisig.key}</p>
+            <button onClick={() => setMultisig(null)}>Done</button>
+          </div>
+        )}
+        
+        {recoveryPhrase && (
+          <div className="info-box">
+            <strong>Write down your recovery words:</strong>
+            <p className="address">{recoveryPhrase.words.join(" ")}</p>
+            <p><small>They will not be shown again. Fingerprint: {recoveryPhrase.fingerprint}</small></p>
+            <button onClick={() => setRecoveryPhrase(null)}>I have written them down</button>
+          </div>
+        )}
+        
         <div className="button-row">
-          <button onClick={getWalletAddress}>Create/Get Address</button>
+          <button onClick={getWalletAddress}>Get Address</button>
           <button onClick={syncWallet}>Sync Wallet</button>
+          <button onClick={cancelSync}>Cancel Sync</button>
           <button onClick={getWalletBalance}>Get Balance</button>
         </div>
         
         {walletAddress && (
           <div className="info-box">
             <strong>Wallet Address:</strong>
-            <p className="address">{walletAddress.split('|')[1]}</p>
-            <p><small>Index: {walletAddress.split('|')[0]}</small></p>
+            <p className="address">{walletAddress.address}</p>
+            <p><small>Index: {walletAddress.index}, Type: {walletAddress.script_type}</small></p>
+            {walletAddress.derivation_path && (
+              <p><small>Path: {walletAddress.derivation_path}</small></p>
+            )}
           </div>
         )}
         
@@ -216,6 +1286,11 @@ function App() {
         <div className="transaction-box">
           <h3>Send Transaction</h3>
           <div className="input-row">
+            <input
+              value={sendAddress}
+              onChange={(e) => setSendAddress(e.target.value)}
+              placeholder="Recipient address"
+            />
             <input
               type="number"
               value={sendAmount}
@@ -224,12 +1299,176 @@ function App() {
               min="1000"
             />
             <button onClick={sendTransaction}>Send</button>
+            <button onClick={sendAll}>Send All</button>
+          </div>
+          <div className="input-row">
+            <textarea
+              value={batchRecipients}
+              onChange={(e) => setBatchRecipients(e.target.value)}
+              placeholder="Batch: one address, amount in sats per line"
+            />
+            <button onClick={sendBatch}>Send Batch</button>
+          </div>
+          <div className="input-row">
+            <label>
+              <input
+                type="checkbox"
+                checked={confirmedOnly}
+                onChange={(e) => setConfirmedOnly(e.target.checked)}
+              />
+              Confirmed coins only
+            </label>
+            <button onClick={getUtxos}>Show Coins</button>
           </div>
+          {utxos && (
+            <div className="info-box">
+              <strong>Coins{selectedUtxos.length > 0 && ` (Send All spends the ${selectedUtxos.length} picked)`}:</strong>
+              {utxos.length === 0 && <p><small>No unspent coins</small></p>}
+              {utxos.map((utxo) => (
+                <label key={utxo.outpoint}>
+                  <input
+                    type="checkbox"
+                    checked={selectedUtxos.includes(utxo.outpoint)}
+                    onChange={(e) => toggleUtxo(utxo.outpoint, e.target.checked)}
+                  />
+                  <small>
+                    {utxo.value} sats, {utxo.address ?? utxo.outpoint}
+                    {utxo.confirmation_height === null ? " (unconfirmed)" : ` (block ${utxo.confirmation_height})`}
+                  </small>
+                </label>
+              ))}
+            </div>
+          )}
+          <div className="input-row">
+            <select
+              value={feePreset}
+              onChange={(e) => setFeePreset(e.target.value as FeePreset | "custom")}
+            >
+              <option value="fast">Fast{feeEstimates && ` (${feeEstimates.fast} sat/vB)`}</option>
+              <option value="normal">Normal{feeEstimates && ` (${feeEstimates.normal} sat/vB)`}</option>
+              <option value="slow">Slow{feeEstimates && ` (${feeEstimates.slow} sat/vB)`}</option>
+              <option value="custom">Custom</option>
+            </select>
+            {feePreset === "custom" && (
+              <input
+                type="number"
+                value={customFeeRate}
+                onChange={(e) => setCustomFeeRate(parseFloat(e.target.value))}
+                placeholder="sat/vB"
+                min="1"
+                step="0.1"
+              />
+            )}
+            <button onClick={getFeeEstimates}>Refresh Fees</button>
+          </div>
+          {feeEstimates && (
+            <p><small>Fee estimates from {new Date(feeEstimates.fetched_at * 1000).toLocaleTimeString()}</small></p>
+          )}
           
-          {txid && (
+          {transaction && (
             <div className="info-box">
               <strong>Transaction Sent:</strong>
-              <p className="txid">{txid}</p>
+              <p className="txid">{transaction.txid}</p>
+              <p><small>Amount: {transaction.amount} sats, Fee: {transaction.fee} sats, Size: {transaction.vsize} vB</small></p>
+              <OutputList outputs={transaction.outputs} feeSaved={transaction.fee_saved} />
+            </div>
+          )}
+          
+          {unsignedPsbt && (
+            <div className="info-box">
+              <strong>PSBT waiting for signatures ({unsignedPsbt.signatures} of {unsignedPsbt.required}):</strong>
+              <p className="txid">{unsignedPsbt.psbt}</p>
+              <p><small>Amount: {unsignedPsbt.amount} sats, Fee: {unsignedPsbt.fee} sats, Size: ~{unsignedPsbt.vsize} vB</small></p>
+              <OutputList outputs={unsignedPsbt.outputs} feeSaved={unsignedPsbt.fee_saved} />
+              <div className="input-row">
+                <textarea
+                  value={signedPsbts}
+                  onChange={(e) => setSignedPsbts(e.target.value)}
+                  placeholder="Signed PSBTs from the other signers"
+                />
+                <button onClick={combinePsbt}>Combine &amp; Broadcast</button>
+              </div>
+            </div>
+          )}
+        </div>
+        
+        <div className="transaction-box">
+          <h3>Labels</h3>
+          <div className="input-row">
+            <select value={labelType} onChange={(e) => setLabelType(e.target.value as LabelType)}>
+              <option value="tx">Transaction</option>
+              <option value="addr">Address</option>
+              <option value="output">Output</option>
+              <option value="input">Input</option>
+              <option value="pubkey">Public key</option>
+              <option value="xpub">Xpub</option>
+            </select>
+            <input
+              value={labelRef}
+              onChange={(e) => setLabelRef(e.target.value)}
+              placeholder="Txid, address or txid:vout"
+            />
+          </div>
+          <div className="input-row">
+            <input
+              value={labelText}
+              onChange={(e) => setLabelText(e.target.value)}
+              placeholder="Label (empty removes it)"
+            />
+            <button onClick={setLabel}>Save Label</button>
+            <button onClick={getLabels}>Show Labels</button>
+          </div>
+          
+          {labels && (
+            <div className="info-box">
+              <strong>Labels:</strong>
+              {labels.length === 0 && <p><small>No labels</small></p>}
+              {labels.map((label) => (
+                <p key={`${label.type}:${label.ref}`}>
+                  <small>{label.type} {label.ref}: {label.label}</small>
+                </p>
+              ))}
+            </div>
+          )}
+        </div>
+        
+        <div className="transaction-box">
+          <h3>Sign &amp; Verify Message</h3>
+          <div className="input-row">
+            <input
+              value={messageAddress}
+              onChange={(e) => setMessageAddress(e.target.value)}
+              placeholder={walletAddress?.address ?? "Address"}
+            />
+          </div>
+          <div className="input-row">
+            <textarea
+              value={messageText}
+              onChange={(e) => setMessageText(e.target.value)}
+              placeholder="Message"
+            />
+          </div>
+          <div className="input-row">
+            <input
+              value={messageSignature}
+              onChange={(e) => setMessageSignature(e.target.value)}
+              placeholder="Signature"
+            />
+            <button onClick={signMessage}>Sign</button>
+            <button onClick={verifyMessage}>Verify</button>
+          </div>
+          
+          {signedMessage && (
+            <div className="info-box">
+              <strong>Signed by {signedMessage.address} ({signedMessage.format === "legacy" ? "legacy" : "BIP322"}):</strong>
+              <p className="txid">{signedMessage.signature}</p>
+            </div>
+          )}
+          
+          {messageVerified && (
+            <div className="info-box">
+              <strong>{messageVerified.valid ? "Valid signature" : "Invalid signature"}</strong>
+              <p><small>{messageVerified.address}</small></p>
             </div>
           )}
         </div>
@@ -237,6 +1476,20 @@ function App() {
 
       <div className="card">
         <h2>Background Task Communication</h2>
+        {serviceStatus && (
+          <div className="stat-box">
+            <strong>Status:</strong> {serviceStatus.state}
+            <p><small>Restarts: {serviceStatus.restarts}</small></p>
+            {serviceStatus.last_error && (
+              <p><small>Last crash: {serviceStatus.last_error}</small></p>
+            )}
+          </div>
+        )}
+        <div className="button-row">
+          <button onClick={() => controlBackground("Start")}>Start</button>
+          <button onClick={() => controlBackground("Restart")}>Restart</button>
+          <button onClick={() => controlBackground("Shutdown")}>Stop</button>
+        </div>
         <div className="stat-box">
           <strong>Heartbeat Count:</strong> {heartbeatCount}
           <p><small>(Updates every 10 seconds from the background task)</small></p>